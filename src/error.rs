@@ -0,0 +1,97 @@
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{}: {}", .0.display(), .1)]
+    IoAt(PathBuf, #[source] io::Error),
+    #[error("Repository directory {} does not exist and cannot be created: {}", .0.display(), .1)]
+    NotCreatable(PathBuf, #[source] io::Error),
+    #[error("Failed to parse the config file: {0}")]
+    Config(#[from] toml::de::Error),
+    #[error("Failed to parse {}: {}", .0.display(), .1)]
+    TomlParse(PathBuf, #[source] toml::de::Error),
+    #[error("Failed to serialize to TOML: {0}")]
+    TomlWrite(#[from] toml::ser::Error),
+    #[error("Failed to parse {}: {}", .0.display(), .1)]
+    JsonParse(PathBuf, #[source] serde_json::Error),
+    #[error("Failed to serialize to JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid regular expression: {0}")]
+    Regex(#[from] regex::Error),
+    #[error("Failed to obtain the user's config directory")]
+    ConfigDir,
+    #[error("Failed to obtain the user's home directory")]
+    HomeDir,
+    #[error("{0}")]
+    Path(String),
+    #[error("Repository directory {} does not exist", .0.display())]
+    NoRepoDir(PathBuf),
+    #[error("{} is not in the repository", .0.display())]
+    NotInRepo(PathBuf),
+    #[error("{} is not a symlink into the repository", .0.display())]
+    NotManaged(PathBuf),
+    #[error("{} is a broken symlink", .0.display())]
+    BrokenLink(PathBuf),
+    #[error("{} already exists in the repository", .0.display())]
+    AlreadyInRepo(PathBuf),
+    #[error("Invalid file name: {0}")]
+    InvalidName(String),
+    #[error("Unknown config key {0:?}; valid keys are {1}")]
+    UnknownKey(String, String),
+    #[error("Environment variable {0} is not set")]
+    UnsetVar(String),
+    #[error("No repository configured; set repo_dir or default_repo in the config")]
+    NoRepo,
+    #[error("{0} cannot be used with {1}")]
+    Conflicting(&'static str, &'static str),
+    #[error("Unknown repository {0:?}; configured repositories: {1}")]
+    UnknownRepo(String, String),
+    #[error("Invalid value {1:?} for {0}")]
+    InvalidValue(String, String),
+    #[error("Invalid tag: {0:?}")]
+    InvalidTag(String),
+    #[error("{} is a directory", .0.display())]
+    IsDir(PathBuf),
+    #[error("{} is not a directory", .0.display())]
+    NotDir(PathBuf),
+    #[error("{} already exists; pass --force to overwrite it", .0.display())]
+    Exists(PathBuf),
+    #[error("Directory {} does not exist; pass --parents to create it", .0.display())]
+    NoParentDir(PathBuf),
+    #[error("{error}; the file has been left at {}", .path.display())]
+    LeftBehind { error: Box<Error>, path: PathBuf },
+    #[error("{error}; failed to move the file back from {}: {rollback}", .path.display())]
+    RollbackFailed { error: Box<Error>, path: PathBuf, rollback: io::Error },
+    #[error("{0} file(s) could not be added")]
+    NotAdded(usize),
+    #[error("{0} file(s) could not be exported")]
+    NotExported(usize),
+    #[error("{0} file(s) could not be removed")]
+    NotRemoved(usize),
+    #[error("{0} symlink(s) could not be created")]
+    NotLinked(usize),
+    #[error("{0} symlink(s) could not be removed")]
+    NotUnmanaged(usize),
+    #[error("{0} symlink(s) could not be updated; the file has been left where it was")]
+    LinksNotUpdated(usize),
+    #[error("{0} problem(s) found")]
+    Problems(usize),
+    #[error("{0} difference(s) from the journal; pass --force to undo anyway")]
+    Diverged(usize),
+    #[error("{0} addition(s) could not be undone")]
+    NotUndone(usize),
+    #[error("Nothing to undo")]
+    NothingToUndo,
+    #[error("Cannot undo {0:?}; only additions can be undone")]
+    NotUndoable(String),
+    #[error("{} matches {1} files", .0.display())]
+    Ambiguous(PathBuf, usize),
+    #[error("Text extractor {0} exited with status {1}")]
+    Exited(String, i32),
+}
@@ -1,210 +1,7265 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{self, File};
 use std::io;
 use std::io::prelude::*;
 use std::os::unix;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::vec::Vec;
 
-use serde_derive::Deserialize;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use structopt::clap::Shell;
 use structopt::StructOpt;
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
+mod error;
+use error::Error;
 
-#[derive(Deserialize, Debug)]
+
+// Directory inside the repository holding paperman's own bookkeeping
+const META_DIR: &str = ".paperman";
+const TEXT_CACHE_DIR: &str = "text-cache";
+const SIDECAR_DIR: &str = "meta";
+const DEFAULT_REPO_DIR: &str = "~/papers";
+// Name under which `repo_dir` can be picked with `--repo`
+const DEFAULT_REPO: &str = "default";
+const JOURNAL: &str = "journal";
+// Digests of the files in the repository, kept next to them so that `add`
+// does not have to read every sidecar
+const DIGEST_INDEX: &str = ".paperman-index.toml";
+
+#[derive(Serialize, Deserialize, Default, Debug)]
 struct Config {
+    // May be left out when `default_repo` picks one of `repos`
+    #[serde(default)]
     repo_dir: PathBuf,
+    // `symlink_type` is accepted as another spelling of the same setting
+    #[serde(default, alias = "symlink_type")]
+    symlink_style: SymlinkStyle,
+    #[serde(default)]
+    link_type: LinkType,
+    // Additional repositories that can be picked by name with `--repo`;
+    // `repo_dir` stays the default one
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    repos: BTreeMap<String, PathBuf>,
+    // Name in `repos` of the repository used unless `--repo` is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_repo: Option<String>,
+    // Program used by `open` [default: xdg-open]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    viewer: Option<String>,
+    // Program used by `grep` to extract text, run as `PROGRAM FILE -`
+    // [default: pdftotext]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text_extractor: Option<String>,
 }
 
-fn read_config() -> Result<Config, String> {
-    let mut path = dirs::config_dir().ok_or("Failed to obtain the user's config directory")?;
-    path.push(concat!(env!("CARGO_PKG_NAME"), ".toml"));
-    let mut file = File::open(path).map_err(|e| e.to_string())?;
-    let mut buf = String::new();
-    file.read_to_string(&mut buf).map_err(|e| e.to_string())?;
-    let mut config: Config = toml::from_str(&buf).map_err(|e| e.to_string())?;
-    config.repo_dir = expand_tilde(config.repo_dir).unwrap();
-    Ok(config)
+// How commands print their results
+#[derive(Default, Eq, PartialEq, Clone, Copy, Debug)]
+enum OutputMode {
+    #[default]
+    Human,
+    Json,
 }
 
-fn expand_tilde<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
-    let path = path.as_ref();
-    if !path.starts_with("~") {
-        Some(path.to_path_buf())
+#[derive(Default, Eq, PartialEq, Clone, Copy, Debug)]
+enum Verbosity {
+    // Leave failures to the exit status
+    Quiet,
+    #[default]
+    Normal,
+    // Also report each operation performed
+    Verbose,
+}
+
+// How `add` makes a file available at its original location
+#[derive(Serialize, Deserialize, Default, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum LinkType {
+    // Replace the file with a symlink into the repository
+    #[default]
+    Symlink,
+    // Give the file a second name in the repository, for filesystems on
+    // which symlinks are unreliable
+    Hard,
+    // Leave the original alone and keep a copy in the repository
+    None,
+}
+
+impl LinkType {
+    fn add_mode(self) -> AddMode {
+        match self {
+            LinkType::Symlink => AddMode::Move,
+            LinkType::Hard => AddMode::HardLink,
+            LinkType::None => AddMode::Copy,
+        }
     }
-    else {
-        if path == Path::new("~") {
-            dirs::home_dir()
+}
+
+#[derive(Serialize, Deserialize, Default, Eq, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum SymlinkStyle {
+    #[default]
+    Relative,
+    Absolute,
+}
+
+impl Opt {
+    fn output_mode(&self) -> OutputMode {
+        if self.json {
+            OutputMode::Json
         }
         else {
-            let stripped = path.strip_prefix("~").unwrap();
-            dirs::home_dir().map(|mut home_dir| {
-                home_dir.push(stripped);
-                home_dir
-            })
+            OutputMode::Human
+        }
+    }
+
+    fn color(&self) -> bool {
+        let tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+        wants_color(self.no_color, std::env::var_os("NO_COLOR"), std::env::var_os("TERM"), tty)
+    }
+
+    fn verbosity(&self) -> Verbosity {
+        match (self.quiet, self.verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, _) => Verbosity::Verbose,
         }
     }
 }
 
-#[derive(StructOpt, Debug)]
-struct Opt {
-    #[structopt(subcommand)]
-    cmd: Command,
+// Use the path given on the command line, then $PAPERMAN_CONFIG, then the
+// user's config directory
+fn config_path(path: Option<PathBuf>) -> Result<PathBuf, Error> {
+    if let Some(path) = path {
+        return Ok(path);
+    }
+    match std::env::var_os("PAPERMAN_CONFIG") {
+        Some(ref path) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => {
+            let mut path = dirs::config_dir().ok_or(Error::ConfigDir)?;
+            path.push(concat!(env!("CARGO_PKG_NAME"), ".toml"));
+            Ok(path)
+        },
+    }
 }
 
-#[derive(StructOpt, Debug)]
-enum Command {
-    #[structopt(name = "add")]
-    Add {
-        #[structopt(name = "FILE", parse(from_os_str))]
-        files: Vec<PathBuf>,
-    },
+fn read_config<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+    let path = path.as_ref();
+    let mut file = File::open(path).map_err(|e| Error::IoAt(path.to_path_buf(), e))?;
+    let mut buf = String::new();
+    file.read_to_string(&mut buf)?;
+    let mut config: Config = toml::from_str(&buf)?;
+    config.repo_dir = expand_env_vars(expand_tilde(config.repo_dir).ok_or(Error::HomeDir)?)?;
+    for dir in config.repos.values_mut() {
+        *dir = expand_env_vars(expand_tilde(dir.clone()).ok_or(Error::HomeDir)?)?;
+    }
+    if !config.repo_dir.as_os_str().is_empty() {
+        let repo_dir = config.repo_dir.clone();
+        config.repos.entry(DEFAULT_REPO.to_string()).or_insert(repo_dir);
+    }
+    if let Some(name) = config.default_repo.clone() {
+        select_repo(&mut config, &name)?;
+    }
+    Ok(config)
 }
 
-fn add(files: Vec<PathBuf>, config: Config) -> Result<(), String> {
-    let mut failed = Vec::new();
-    for fp in files {
-        // Process only a regular file
-        match file_type(&fp).map_err(|e| e.to_string())? {
-            FileType::Dir => {
-                failed.push((fp.clone(), "file is a directory, which cannot be added"));
-                continue;
-            },
-            FileType::Symlink => {
-                failed.push((fp.clone(), "file is a symlink, which cannot be added"));
-                continue;
-            },
-            FileType::File => (),
-        }
+// Make sure that the repository is a directory or can be created, so that
+// commands fail up front rather than halfway through
+fn validate_repo_dir(repo_dir: &Path) -> Result<(), Error> {
+    let repo_dir = to_absolute(repo_dir)?;
+    let existing = repo_dir.ancestors().find(|dir| dir.exists()).unwrap();
+    if !existing.is_dir() {
+        return Err(Error::NotDir(existing.to_path_buf()));
+    }
+    if existing != repo_dir {
+        probe_writable(existing).map_err(|e| Error::NotCreatable(repo_dir.clone(), e))?;
+    }
+    Ok(())
+}
 
-        // Move
-        let to = config.repo_dir.join(fp.file_name().unwrap());
-        if to.exists() {
-            failed.push((fp.clone(), "destination file exists"));
-            continue;
+// Ask for the repository directory until an acceptable one is given
+fn prompt_repo_dir(input: &mut dyn BufRead, output: &mut dyn Write, home_dir: Option<&Path>) -> Result<PathBuf, Error> {
+    loop {
+        write!(output, "Repository directory [{}]: ", DEFAULT_REPO_DIR)?;
+        output.flush()?;
+        let mut answer = String::new();
+        if input.read_line(&mut answer)? == 0 {
+            return Err(Error::Path("No repository directory given".to_string()));
         }
-        fs::create_dir_all(&config.repo_dir).unwrap();
-        fs::rename(&fp, &to).unwrap();
+        let answer = answer.trim();
+        let repo_dir = PathBuf::from(if answer.is_empty() { DEFAULT_REPO_DIR } else { answer });
+        match check_new_repo_dir(&repo_dir, home_dir) {
+            Ok(()) => return Ok(repo_dir),
+            Err(e) => writeln!(output, "{}", e)?,
+        }
+    }
+}
+
+// Make sure a repository can be kept at `repo_dir`, with `~` standing for
+// `home_dir`
+fn check_new_repo_dir(repo_dir: &Path, home_dir: Option<&Path>) -> Result<(), Error> {
+    if repo_dir.as_os_str().is_empty() {
+        return Err(Error::Path("No repository directory given".to_string()));
+    }
+    let expanded = expand_tilde_with(repo_dir, home_dir).ok_or(Error::HomeDir)?;
+    if expanded.as_os_str().to_string_lossy().starts_with('~') {
+        return Err(Error::Path(format!("{} cannot be expanded; there is no such user", repo_dir.display())));
+    }
+    validate_repo_dir(&expanded)?;
+    if expanded.is_dir() {
+        probe_writable(&expanded).map_err(|e| Error::IoAt(expanded.clone(), e))?;
+    }
+    Ok(())
+}
 
-        // Link
-        let link_ref = relative_path_from(&fp.parent().unwrap(), &to)?;
-        unix::fs::symlink(link_ref, fp).unwrap();
+// Point `config.repo_dir` at the repository named `name`
+fn select_repo(config: &mut Config, name: &str) -> Result<(), Error> {
+    match config.repos.get(name) {
+        Some(dir) => {
+            config.repo_dir = dir.clone();
+            Ok(())
+        },
+        None => {
+            let names: Vec<_> = config.repos.keys().map(String::as_str).collect();
+            let known = if names.is_empty() { "none".to_string() } else { names.join(", ") };
+            Err(Error::UnknownRepo(name.to_string(), known))
+        },
     }
+}
 
-    if failed.len() > 0 {
-        eprintln!("The following paths are ignored:");
-        for (fp, reason) in failed {
-            eprintln!("{}\t({})", fp.display(), reason);
-        }
+#[derive(Serialize, Debug)]
+struct InitReport<'a> {
+    config_path: &'a Path,
+    repo_dir: &'a Path,
+}
+
+// Write a config file pointing at `repo_dir` and create the repository
+fn init<W: WriteColor>(config_path: PathBuf, repo_dir: Option<PathBuf>, home_dir: Option<&Path>, force: bool, dry_run: bool, printer: &mut Printer<W>) -> Result<(), Error> {
+    if config_path.symlink_metadata().is_ok() && !force {
+        return Err(Error::Exists(config_path));
+    }
+    let repo_dir = match repo_dir {
+        Some(repo_dir) => {
+            check_new_repo_dir(&repo_dir, home_dir)?;
+            repo_dir
+        },
+        None => prompt_repo_dir(&mut io::stdin().lock(), &mut printer.err, home_dir)?,
+    };
+    let has_tilde = repo_dir.as_os_str().to_string_lossy().starts_with('~');
+    // A path starting with `~` is written as given so that it keeps referring
+    // to the home directory, but other relative paths would be resolved
+    // against whatever directory paperman happens to be run from
+    let repo_dir = if has_tilde { repo_dir } else { to_absolute(&repo_dir)? };
+    let content = toml::to_string(&Config { repo_dir, ..Config::default() })?;
+    // Read back what is about to be written, as read_config would
+    let written: Config = toml::from_str(&content)?;
+    let repo_dir = expand_tilde_with(&written.repo_dir, home_dir).ok_or(Error::HomeDir)?;
+
+    if dry_run {
+        printer.say(format_args!("[dry-run] write {}", config_path.display()))?;
+        printer.say(format_args!("[dry-run] mkdir {}", repo_dir.display()))?;
+        return Ok(());
+    }
+    if let Some(dir) = config_path.parent() {
+        fs::create_dir_all(dir).map_err(|e| Error::IoAt(dir.to_path_buf(), e))?;
+    }
+    fs::write(&config_path, content).map_err(|e| Error::IoAt(config_path.clone(), e))?;
+    let config_path = to_absolute(&config_path)?;
+    printer.say(format_args!("Wrote config to {}", config_path.display()))?;
+    fs::create_dir_all(&repo_dir).map_err(|e| Error::IoAt(repo_dir.clone(), e))?;
+    printer.say(format_args!("Repository is at {}", repo_dir.display()))?;
+    if printer.output == OutputMode::Json {
+        let report = InitReport { config_path: &config_path, repo_dir: &repo_dir };
+        printer.line(serde_json::to_string_pretty(&report)?)?;
     }
 
     Ok(())
 }
 
-#[derive(Eq, PartialEq, Debug)]
-enum FileType {
-    Dir,
-    File,
-    Symlink,
+// Generate a completion script from the command line definition itself, so
+// that it never falls out of date
+fn completions<W: WriteColor>(shell: Shell, output: Option<PathBuf>, printer: &mut Printer<W>) -> Result<(), Error> {
+    let mut app = Opt::clap();
+    match output {
+        Some(path) => {
+            let mut file = File::create(&path).map_err(|e| Error::IoAt(path.clone(), e))?;
+            app.gen_completions_to(env!("CARGO_BIN_NAME"), shell, &mut file);
+        },
+        None => app.gen_completions_to(env!("CARGO_BIN_NAME"), shell, &mut printer.out),
+    }
+    Ok(())
 }
 
-fn file_type<P: AsRef<Path>>(path: P) -> io::Result<FileType> {
-    let path = path.as_ref();
-    let metadata = path.symlink_metadata()?;
-    if metadata.file_type().is_dir() {
-        Ok(FileType::Dir)
+const CONFIG_KEYS: &[&str] = &["repo_dir", "default_repo", "symlink_style", "link_type", "viewer", "text_extractor"];
+
+// Read and write single settings.  The file is handled as a plain TOML table
+// so that `set` leaves everything else in it as it was.
+fn config_command<W: WriteColor>(path: PathBuf, cmd: ConfigCommand, dry_run: bool, printer: &mut Printer<W>) -> Result<(), Error> {
+    // `symlink_type` is read and written as the `symlink_style` it stands for
+    let normalize_key = |key: String| if key == "symlink_type" { "symlink_style".to_string() } else { key };
+    let check_key = |key: &str| if CONFIG_KEYS.contains(&key) {
+        Ok(())
     }
-    else if metadata.file_type().is_file() {
-        Ok(FileType::File)
+    else {
+        Err(Error::UnknownKey(key.to_string(), CONFIG_KEYS.join(", ")))
+    };
+
+    match cmd {
+        ConfigCommand::Path => {
+            let path = to_absolute(&path)?;
+            match printer.output {
+                OutputMode::Human => printer.line(path.display())?,
+                OutputMode::Json => printer.line(serde_json::to_string_pretty(&path)?)?,
+            }
+        },
+        ConfigCommand::Get { key } => {
+            let key = normalize_key(key);
+            check_key(&key)?;
+            let table = read_config_table(&path)?;
+            let value = match table.get(&key) {
+                Some(value) => value.clone(),
+                None if key == "symlink_style" => toml::Value::String("relative".to_string()),
+                None if key == "link_type" => toml::Value::String("symlink".to_string()),
+                None => return Err(Error::Path(format!("{} is not set in {}", key, path.display()))),
+            };
+            match (printer.output, value) {
+                (OutputMode::Human, toml::Value::String(value)) => printer.line(value)?,
+                (OutputMode::Human, value) => printer.line(value)?,
+                (OutputMode::Json, value) => printer.line(serde_json::to_string_pretty(&value)?)?,
+            }
+        },
+        ConfigCommand::Set { key, value } => {
+            let key = normalize_key(key);
+            check_key(&key)?;
+            if key == "symlink_style" && value != "relative" && value != "absolute" {
+                return Err(Error::InvalidValue(key, value));
+            }
+            if key == "repo_dir" && value.is_empty() {
+                return Err(Error::InvalidValue(key, value));
+            }
+            let mut table = match read_config_table(&path) {
+                Err(Error::IoAt(_, ref e)) if e.kind() == io::ErrorKind::NotFound => toml::value::Table::new(),
+                result => result?,
+            };
+            table.insert(key, toml::Value::String(value));
+            let content = toml::to_string(&table)?;
+            // Make sure that what is written is still a valid config
+            let config: Config = toml::from_str(&content)?;
+            if config.repo_dir.as_os_str().is_empty() && config.default_repo.is_none() {
+                return Err(Error::NoRepo);
+            }
+            if dry_run {
+                printer.say(format_args!("[dry-run] write {}", path.display()))?;
+                return Ok(());
+            }
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).map_err(|e| Error::IoAt(dir.to_path_buf(), e))?;
+            }
+            let tmp = path.with_file_name(format!(".{}.paperman-tmp", path.file_name().unwrap().to_string_lossy()));
+            fs::write(&tmp, content).map_err(|e| Error::IoAt(tmp.clone(), e))?;
+            fs::rename(&tmp, &path).map_err(|e| Error::IoAt(path.clone(), e))?;
+        },
     }
-    else if metadata.file_type().is_symlink() {
-        Ok(FileType::Symlink)
+
+    Ok(())
+}
+
+// The config as a TOML table, with `symlink_type` renamed to `symlink_style`
+// so that a value set under either spelling is found and replaced
+fn read_config_table(path: &Path) -> Result<toml::value::Table, Error> {
+    let buf = fs::read_to_string(path).map_err(|e| Error::IoAt(path.to_path_buf(), e))?;
+    let mut table: toml::value::Table = toml::from_str(&buf)?;
+    if let Some(value) = table.remove("symlink_type") {
+        table.entry("symlink_style").or_insert(value);
     }
-    else {
-        unreachable!()
+    Ok(table)
+}
+
+// What the checks of `doctor` have found out so far, for later checks to
+// build on
+#[derive(Default)]
+struct Diagnosis {
+    config_path: Option<PathBuf>,
+    config: Option<Config>,
+    repo_dir: Option<PathBuf>,
+}
+
+// The hint tells how to resolve a warning or failure
+enum Verdict {
+    Ok(String),
+    Warn(String, String),
+    Fail(String, String),
+    Skip(String),
+}
+
+trait Check {
+    fn run(&self, diagnosis: &mut Diagnosis) -> Verdict;
+}
+
+struct ConfigCheck;
+
+impl Check for ConfigCheck {
+    fn run(&self, diagnosis: &mut Diagnosis) -> Verdict {
+        let path = match config_path(diagnosis.config_path.take()) {
+            Ok(path) => path,
+            Err(e) => return Verdict::Fail(format!("config file: {}", e), "pass --config or set PAPERMAN_CONFIG".into()),
+        };
+        match read_config(&path) {
+            Ok(config) => {
+                diagnosis.config = Some(config);
+                Verdict::Ok(format!("config file {} parses", path.display()))
+            },
+            Err(Error::IoAt(_, ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                Verdict::Fail(format!("config file {} does not exist", path.display()), "run `pm init <REPO_DIR>` to create it".into())
+            },
+            Err(e @ Error::Config(_)) => {
+                Verdict::Fail(format!("config file {}: {}", path.display(), e), "the file must contain a line like repo_dir = \"~/papers\"".into())
+            },
+            Err(e) => Verdict::Fail(format!("config file {}: {}", path.display(), e), "check that the file is readable".into()),
+        }
     }
 }
 
-fn relative_path_from<P: AsRef<Path>, Q: AsRef<Path>>(base: P, target: Q) -> Result<PathBuf, String> {
-    let mut base = to_absolute(base).map_err(|e| e.to_string())?;
-    let target = to_absolute(target).map_err(|e| e.to_string())?;
+struct RepoDirCheck;
 
-    let mut count = 0;
-    while !target.starts_with(&base) {
-        if base.pop() {
-            count += 1;
+impl Check for RepoDirCheck {
+    fn run(&self, diagnosis: &mut Diagnosis) -> Verdict {
+        let repo_dir = match diagnosis.config.as_ref().map(|config| to_absolute(&config.repo_dir)) {
+            None => return Verdict::Skip("repository directory".into()),
+            Some(Ok(ref repo_dir)) if repo_dir.as_os_str().is_empty() => {
+                return Verdict::Fail(Error::NoRepo.to_string(), "set repo_dir, or default_repo to one of repos".into());
+            },
+            Some(Err(e)) => return Verdict::Fail(format!("repository directory: {}", e), "set repo_dir to an absolute path".into()),
+            Some(Ok(repo_dir)) => repo_dir,
+        };
+        if repo_dir.is_dir() {
+            diagnosis.repo_dir = Some(repo_dir.clone());
+            Verdict::Ok(format!("repository directory {} exists", repo_dir.display()))
+        }
+        else if repo_dir.exists() {
+            Verdict::Fail(format!("{} is not a directory", repo_dir.display()), "point repo_dir at a directory".into())
         }
         else {
-            return Err("base cannot be a prefix of target".into());
+            let ancestor = repo_dir.ancestors().find(|dir| dir.is_dir()).unwrap();
+            if probe_writable(ancestor).is_ok() {
+                Verdict::Ok(format!("repository directory {} does not exist but can be created", repo_dir.display()))
+            }
+            else {
+                Verdict::Fail(format!("repository directory {} does not exist and cannot be created", repo_dir.display()),
+                              format!("create it by hand or make {} writable", ancestor.display()))
+            }
         }
     }
+}
 
-    let mut relpath = PathBuf::new();
-    for _ in 0..count {
-        relpath.push("..");
+struct WritableCheck;
+
+impl Check for WritableCheck {
+    fn run(&self, diagnosis: &mut Diagnosis) -> Verdict {
+        let repo_dir = match &diagnosis.repo_dir {
+            Some(repo_dir) => repo_dir,
+            None => return Verdict::Skip("repository is writable".into()),
+        };
+        match probe_writable(repo_dir) {
+            Ok(()) => Verdict::Ok("repository is writable".into()),
+            Err(e) => Verdict::Fail(format!("repository is not writable: {}", e), "check the owner and permissions of the directory".into()),
+        }
     }
-    Ok(relpath.join(target.strip_prefix(base).unwrap()))
 }
 
-fn to_absolute<P: AsRef<Path>>(path: P) -> Result<PathBuf, String> {
-    let path = path.as_ref();
-    if path.is_absolute() {
-        Ok(path.to_path_buf())
+struct SymlinkSupportCheck;
+
+impl Check for SymlinkSupportCheck {
+    fn run(&self, diagnosis: &mut Diagnosis) -> Verdict {
+        let repo_dir = match &diagnosis.repo_dir {
+            Some(repo_dir) => repo_dir,
+            None => return Verdict::Skip("repository supports symlinks".into()),
+        };
+        match probe_symlink(repo_dir) {
+            Ok(()) => Verdict::Ok("repository supports symlinks".into()),
+            Err(e) => Verdict::Fail(format!("cannot create symlinks in the repository: {}", e),
+                                    "move the repository to a filesystem with symlink support, not e.g. FAT or some network mounts".into()),
+        }
+    }
+}
+
+struct BrokenLinksCheck;
+
+impl Check for BrokenLinksCheck {
+    fn run(&self, diagnosis: &mut Diagnosis) -> Verdict {
+        let repo_dir = match &diagnosis.repo_dir {
+            Some(repo_dir) => repo_dir,
+            None => return Verdict::Skip("no broken symlinks in the repository".into()),
+        };
+        let entries = match walk(repo_dir) {
+            Ok(entries) => entries,
+            Err(e) => return Verdict::Fail(format!("cannot read the repository: {}", e), "check the permissions of the directory".into()),
+        };
+        let broken: Vec<_> = entries.into_iter()
+            .filter(|(path, ft)| *ft == FileType::Symlink && !path.starts_with(repo_dir.join(META_DIR)) && !path.exists())
+            .collect();
+        match broken.len() {
+            0 => Verdict::Ok("no broken symlinks in the repository".into()),
+            n => Verdict::Fail(format!("{} broken symlink(s) in the repository, e.g. {}", n, broken[0].0.display()),
+                               "restore their targets or remove them".into()),
+        }
+    }
+}
+
+struct OrphanedSidecarsCheck;
+
+impl Check for OrphanedSidecarsCheck {
+    fn run(&self, diagnosis: &mut Diagnosis) -> Verdict {
+        let repo_dir = match &diagnosis.repo_dir {
+            Some(repo_dir) => repo_dir,
+            None => return Verdict::Skip("no orphaned sidecars".into()),
+        };
+        let orphans = match orphaned_sidecars(repo_dir) {
+            Ok(orphans) => orphans,
+            Err(e) => return Verdict::Fail(format!("cannot read the repository: {}", e), "check the permissions of the directory".into()),
+        };
+        match orphans.len() {
+            0 => Verdict::Ok("no orphaned sidecars".into()),
+            n => Verdict::Warn(format!("{} sidecar(s) without their file, e.g. {}", n, orphans[0].display()),
+                               "run `pm prune --apply` to remove them".into()),
+        }
+    }
+}
+
+struct SharedInodesCheck;
+
+impl Check for SharedInodesCheck {
+    fn run(&self, diagnosis: &mut Diagnosis) -> Verdict {
+        use std::os::unix::fs::MetadataExt;
+
+        let repo_dir = match &diagnosis.repo_dir {
+            Some(repo_dir) => repo_dir,
+            None => return Verdict::Skip("no files in the repository share an inode".into()),
+        };
+        let files = match repo_files(repo_dir) {
+            Ok(files) => files,
+            Err(e) => return Verdict::Fail(format!("cannot read the repository: {}", e), "check the permissions of the directory".into()),
+        };
+        let mut inodes: BTreeMap<(u64, u64), Vec<PathBuf>> = BTreeMap::new();
+        for fp in files {
+            if let Ok(m) = fs::metadata(&fp) {
+                inodes.entry((m.dev(), m.ino())).or_default().push(fp);
+            }
+        }
+        let shared: Vec<_> = inodes.values().filter(|paths| paths.len() > 1).collect();
+        match shared.len() {
+            0 => Verdict::Ok("no files in the repository share an inode".into()),
+            n => Verdict::Warn(format!("{} group(s) of repository files share an inode, e.g. {} and {}", n, shared[0][0].display(), shared[0][1].display()),
+                               "editing one of them changes the others; replace all but one with a copy or remove them".into()),
+        }
+    }
+}
+
+struct PlatformCheck;
+
+impl Check for PlatformCheck {
+    fn run(&self, _: &mut Diagnosis) -> Verdict {
+        if cfg!(unix) {
+            Verdict::Ok(format!("platform {} supports symlinks and atomic renames", std::env::consts::OS))
+        }
+        else {
+            Verdict::Fail(format!("platform {} is not supported", std::env::consts::OS), "paperman needs a Unix-like system".into())
+        }
+    }
+}
+
+// The outcome of one check of `doctor`, for `--json`
+#[derive(Serialize, Debug)]
+struct CheckResult<'a> {
+    status: &'static str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<&'a str>,
+}
+
+// Check the configuration and the environment step by step, printing an
+// actionable hint for each warning and failure
+fn doctor<W: WriteColor>(config: Option<PathBuf>, printer: &mut Printer<W>) -> Result<(), Error> {
+    printer.say(format_args!("{} {} on {}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), std::env::consts::OS, std::env::consts::ARCH))?;
+    let checks: Vec<Box<dyn Check>> = vec![
+        Box::new(ConfigCheck),
+        Box::new(RepoDirCheck),
+        Box::new(WritableCheck),
+        Box::new(SymlinkSupportCheck),
+        Box::new(BrokenLinksCheck),
+        Box::new(OrphanedSidecarsCheck),
+        Box::new(SharedInodesCheck),
+        Box::new(PlatformCheck),
+    ];
+
+    let mut diagnosis = Diagnosis { config_path: config, ..Diagnosis::default() };
+    let mut failures = 0;
+    let mut verdicts = Vec::new();
+    for check in &checks {
+        let verdict = check.run(&mut diagnosis);
+        if let Verdict::Fail(..) = verdict {
+            failures += 1;
+        }
+        if printer.output == OutputMode::Json {
+            verdicts.push(verdict);
+            continue;
+        }
+        match verdict {
+            Verdict::Ok(what) => {
+                printer.colored("[OK]", Some(Color::Green))?;
+                printer.line(format_args!("   {}", what))?;
+            },
+            Verdict::Warn(what, hint) => {
+                printer.colored("[WARN]", Some(Color::Yellow))?;
+                printer.line(format_args!(" {}", what))?;
+                printer.line(format_args!("       hint: {}", hint))?;
+            },
+            Verdict::Fail(what, hint) => {
+                printer.colored("[FAIL]", Some(Color::Red))?;
+                printer.line(format_args!(" {}", what))?;
+                printer.line(format_args!("       hint: {}", hint))?;
+            },
+            Verdict::Skip(what) => printer.line(format_args!("[SKIP] {}", what))?,
+        }
+    }
+    if printer.output == OutputMode::Json {
+        let results: Vec<_> = verdicts.iter().map(|verdict| match verdict {
+            Verdict::Ok(what) => CheckResult { status: "ok", message: what, hint: None },
+            Verdict::Warn(what, hint) => CheckResult { status: "warn", message: what, hint: Some(hint) },
+            Verdict::Fail(what, hint) => CheckResult { status: "fail", message: what, hint: Some(hint) },
+            Verdict::Skip(what) => CheckResult { status: "skip", message: what, hint: None },
+        }).collect();
+        printer.line(serde_json::to_string_pretty(&results)?)?;
+    }
+
+    if failures == 0 {
+        Ok(())
     }
     else {
-        let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
-        Ok(cwd.join(path))
+        Err(Error::Problems(failures))
     }
 }
 
-fn main() {
-    let opt = Opt::from_args();
-    let config = read_config().unwrap();
+fn probe_path(dir: &Path) -> PathBuf {
+    dir.join(format!(".paperman-doctor-{}", std::process::id()))
+}
 
-    match opt.cmd {
-        Command::Add { files } => {
-            add(files, config).unwrap();
-        },
+fn probe_writable(dir: &Path) -> io::Result<()> {
+    let probe = probe_path(dir);
+    fs::write(&probe, "")?;
+    fs::remove_file(&probe)
+}
+
+fn probe_symlink(dir: &Path) -> io::Result<()> {
+    let probe = probe_path(dir);
+    unix::fs::symlink("target", &probe)?;
+    let target = fs::read_link(&probe);
+    fs::remove_file(&probe)?;
+    if target? != Path::new("target") {
+        return Err(io::Error::other("symlink target was not preserved"));
     }
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn expand_tilde<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    expand_tilde_with(path, dirs::home_dir().as_deref())
+}
 
-    #[test]
-    fn test_expand_tilde() {
-        std::env::set_var("HOME", "/home/alice");
-        assert_eq!(expand_tilde("~"), Some("/home/alice".into()));
-        assert_eq!(expand_tilde("~/"), Some("/home/alice/".into()));
-        assert_eq!(expand_tilde("~/foo"), Some("/home/alice/foo".into()));
-        assert_eq!(expand_tilde("/foo/bar"), Some("/foo/bar".into()));
-        assert_eq!(expand_tilde("~bob/foo/bar"), Some("~bob/foo/bar".into()));
+// Expand `~` to `home_dir`, which is `None` when the home directory is unknown
+fn expand_tilde_with<P: AsRef<Path>>(path: P, home_dir: Option<&Path>) -> Option<PathBuf> {
+    use std::ffi::{OsStr, OsString};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use uzers::os::unix::UserExt;
 
-        std::env::set_var("HOME", "/");
-        assert_eq!(expand_tilde("~"), Some("/".into()));
-        assert_eq!(expand_tilde("~/"), Some("/".into()));
-        assert_eq!(expand_tilde("~/foo"), Some("/foo".into()));
-        assert_eq!(expand_tilde("/foo/bar"), Some("/foo/bar".into()));
-        assert_eq!(expand_tilde("~bob/foo/bar"), Some("~bob/foo/bar".into()));
+    let path = path.as_ref();
+    let bytes = path.as_os_str().as_bytes();
+    if !bytes.starts_with(b"~") {
+        return Some(path.to_path_buf());
+    }
+    // `~` is followed by the name of the user up to the first slash, which
+    // is empty for the current user
+    let end = bytes.iter().position(|&b| b == b'/').unwrap_or(bytes.len());
+    let (name, rest) = (&bytes[1..end], &bytes[end..]);
+    let home_dir = if name.is_empty() {
+        home_dir?.to_path_buf()
+    }
+    else {
+        match uzers::get_user_by_name(OsStr::from_bytes(name)) {
+            Some(user) => user.home_dir().to_path_buf(),
+            // Unknown users are left alone, as a shell would
+            None => return Some(path.to_path_buf()),
+        }
+    };
+    if rest.is_empty() {
+        return Some(home_dir);
     }
 
-    #[test]
-    fn test_to_absolute() {
-        std::env::set_current_dir("/usr");
-        assert_eq!(to_absolute("foo/bar"), Ok("/usr/foo/bar".into()));
-        assert_eq!(to_absolute("/"), Ok("/".into()));
-        assert_eq!(to_absolute("/foo/bar"), Ok("/foo/bar".into()));
+    // Join as strings, since `Path` drops a trailing slash, which matters to
+    // whoever wrote it
+    let mut joined = home_dir.into_os_string().into_vec();
+    while joined.ends_with(b"/") {
+        joined.pop();
+    }
+    joined.extend_from_slice(rest);
+    Some(PathBuf::from(OsString::from_vec(joined)))
+}
 
-        std::env::set_current_dir("/");
-        assert_eq!(to_absolute("foo/bar"), Ok("/foo/bar".into()));
-        assert_eq!(to_absolute("/"), Ok("/".into()));
-        assert_eq!(to_absolute("/foo/bar"), Ok("/foo/bar".into()));
+// Substitute `$VAR` and `${VAR}` with the values of environment variables.
+// A `$` not followed by a variable name is kept as it is.
+fn expand_env_vars<P: AsRef<Path>>(path: P) -> Result<PathBuf, Error> {
+    let path = path.as_ref();
+    let s = match path.to_str() {
+        Some(s) if s.contains('$') => s,
+        _ => return Ok(path.to_path_buf()),
+    };
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    let mut expanded = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let (name, after) = if let Some(braced) = rest.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => return Err(Error::Path(format!("unterminated ${{ in {}", s))),
+            }
+        }
+        else {
+            let end = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) || !name.chars().all(is_name_char) {
+            expanded.push('$');
+            continue;
+        }
+        let value = std::env::var(name).map_err(|_| Error::UnsetVar(name.to_string()))?;
+        expanded.push_str(&value);
+        rest = after;
     }
+    expanded.push_str(rest);
+    Ok(PathBuf::from(expanded))
+}
 
-    #[test]
-    fn test_relative_path_from() {
-        assert_eq!(relative_path_from("/usr", "/usr/share"), Ok("share".into()));
-        assert_eq!(relative_path_from("/usr/", "/usr/share"), Ok("share".into()));
-        assert_eq!(relative_path_from("/usr/bin", "/usr/share"), Ok("../share".into()));
+#[derive(StructOpt, Debug)]
+struct Opt {
+    /// Print what would be done without touching the filesystem
+    #[structopt(short = "n", long, global = true)]
+    dry_run: bool,
+    /// Print each operation performed; repeat for debugging output
+    #[structopt(short, long, global = true, parse(from_occurrences))]
+    verbose: u8,
+    /// Print errors only, without the summary of files that were skipped
+    #[structopt(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print the results as JSON
+    #[structopt(long, global = true)]
+    json: bool,
+    /// Do not color the output, even on a terminal
+    #[structopt(long, global = true)]
+    no_color: bool,
+    /// Config file to use instead of $PAPERMAN_CONFIG or the default location
+    #[structopt(long, global = true, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Repository directory to use instead of repo_dir in the config
+    #[structopt(long, global = true, parse(from_os_str))]
+    repo_dir: Option<PathBuf>,
+    /// Use the repository of this name from `repos` in the config instead of
+    /// the default one; repo_dir is named "default"
+    #[structopt(long, global = true, conflicts_with = "repo-dir")]
+    repo: Option<String>,
+    /// Create symlinks with absolute targets, overriding symlink_style in the config
+    #[structopt(long, global = true, conflicts_with = "relative")]
+    absolute: bool,
+    /// Create symlinks with relative targets, overriding symlink_style in the config
+    #[structopt(long, global = true)]
+    relative: bool,
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "init")]
+    Init {
+        // The name has to differ from that of the global --repo-dir
+        /// Directory to keep the files in [default: ask]
+        #[structopt(name = "DIR", value_name = "REPO_DIR", parse(from_os_str))]
+        repo_dir: Option<PathBuf>,
+        /// Overwrite an existing config file
+        #[structopt(short, long)]
+        force: bool,
+    },
+    #[structopt(name = "doctor")]
+    Doctor,
+    #[structopt(name = "completions")]
+    Completions {
+        #[structopt(name = "SHELL", possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+        /// Write the script to this file instead of stdout
+        #[structopt(long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    #[structopt(name = "config")]
+    Config {
+        #[structopt(subcommand)]
+        cmd: ConfigCommand,
+    },
+    #[structopt(name = "add")]
+    Add {
+        #[structopt(name = "FILE", parse(from_os_str))]
+        files: Vec<PathBuf>,
+        /// Add every regular file inside the given directories
+        #[structopt(short, long)]
+        recursive: bool,
+        /// What to do when a file of the same name exists in the repository
+        /// [default: refuse to add the file]
+        #[structopt(long = "on-conflict", alias = "conflict", possible_values = &["suffix", "overwrite", "skip", "rename"])]
+        conflict: Option<Conflict>,
+        /// Append a numeric suffix to the name of a file that collides with
+        /// one in the repository; same as --on-conflict suffix
+        #[structopt(long, conflicts_with = "conflict")]
+        rename_on_collision: bool,
+        /// Replace a file of the same name in the repository unless it is
+        /// still linked; same as --on-conflict overwrite
+        #[structopt(long, conflicts_with_all = &["conflict", "rename-on-collision"])]
+        force: bool,
+        /// Copy the files into the repository and leave the originals in
+        /// place without symlinks.  Collisions are handled as usual but only
+        /// ever affect the copy in the repository.
+        #[structopt(long)]
+        copy: bool,
+        /// With --copy, replace each original with a symlink to its copy
+        #[structopt(long, requires = "copy")]
+        link: bool,
+        /// Create the symlinks in this directory instead of in place of the
+        /// original files
+        #[structopt(long, alias = "target-dir", parse(from_os_str), conflicts_with = "copy")]
+        link_dir: Option<PathBuf>,
+        /// Ask before adding each file
+        #[structopt(short, long)]
+        interactive: bool,
+        /// Add files whose content is already in the repository without
+        /// asking
+        #[structopt(short, long)]
+        yes: bool,
+    },
+    /// Copy files into the repository, leaving the originals untouched and
+    /// without symlinks
+    #[structopt(name = "copy")]
+    Copy {
+        #[structopt(name = "FILE", parse(from_os_str))]
+        files: Vec<PathBuf>,
+        /// Copy every regular file inside the given directories
+        #[structopt(short, long)]
+        recursive: bool,
+        /// What to do when a file of the same name exists in the repository
+        /// [default: refuse to copy the file]
+        #[structopt(long = "on-conflict", alias = "conflict", possible_values = &["suffix", "overwrite", "skip", "rename"])]
+        conflict: Option<Conflict>,
+    },
+    #[structopt(name = "import")]
+    Import {
+        #[structopt(name = "DIR", parse(from_os_str))]
+        dir: PathBuf,
+        /// Descend at most this many levels; 1 imports only the files
+        /// directly inside DIR
+        #[structopt(long)]
+        max_depth: Option<usize>,
+        /// Import only files with these extensions, e.g. pdf,djvu
+        #[structopt(long = "ext", use_delimiter = true)]
+        exts: Vec<String>,
+        /// Import empty files as well
+        #[structopt(long)]
+        include_empty: bool,
+        /// What to do when a file of the same name exists in the repository
+        /// [default: refuse to add the file]
+        #[structopt(long = "on-conflict", alias = "conflict", possible_values = &["suffix", "overwrite", "skip", "rename"])]
+        conflict: Option<Conflict>,
+    },
+    #[structopt(name = "remove", alias = "rm")]
+    Remove {
+        /// Symlinks to restore, or names of files in the repository
+        #[structopt(name = "FILE", parse(from_os_str))]
+        files: Vec<PathBuf>,
+        /// Directory to search for the symlink when a repository file name is given
+        #[structopt(long, parse(from_os_str))]
+        search_root: Option<PathBuf>,
+    },
+    #[structopt(name = "mv")]
+    Mv {
+        /// Symlink to the file to rename
+        #[structopt(name = "LINK", parse(from_os_str))]
+        link: PathBuf,
+        /// New file name in the repository
+        #[structopt(name = "NEW_NAME")]
+        new_name: String,
+    },
+    #[structopt(name = "link")]
+    Link {
+        /// Name of a file in the repository or a symlink to it
+        #[structopt(name = "REPO_NAME", parse(from_os_str))]
+        name: PathBuf,
+        /// Where to create the symlinks; a directory gets one of the same
+        /// name inside it
+        #[structopt(name = "DEST", parse(from_os_str), required = true)]
+        dests: Vec<PathBuf>,
+    },
+    #[structopt(name = "unmanage")]
+    Unmanage {
+        /// Symlinks into the repository to remove
+        #[structopt(name = "LINK", parse(from_os_str), required = true)]
+        links: Vec<PathBuf>,
+    },
+    #[structopt(name = "relink")]
+    Relink {
+        /// Where the repository used to be
+        #[structopt(name = "OLD_REPO", parse(from_os_str))]
+        old_repo: PathBuf,
+        /// Directory to search for symlinks [default: home directory]
+        #[structopt(long, parse(from_os_str))]
+        search_root: Option<PathBuf>,
+    },
+    #[structopt(name = "export-links")]
+    ExportLinks {
+        /// File to write the list of symlinks to, as JSON if it ends in
+        /// .json and as TOML otherwise
+        #[structopt(name = "OUT", parse(from_os_str))]
+        out: PathBuf,
+        /// Directory to search for symlinks [default: home directory]
+        #[structopt(long, parse(from_os_str))]
+        search_root: Option<PathBuf>,
+    },
+    #[structopt(name = "import-links")]
+    ImportLinks {
+        /// Manifest written by export-links
+        #[structopt(name = "MANIFEST", parse(from_os_str))]
+        manifest: PathBuf,
+    },
+    #[structopt(name = "mvlink")]
+    MvLink {
+        /// Symlink into the repository to move
+        #[structopt(name = "FROM", parse(from_os_str))]
+        from: PathBuf,
+        /// New location of the symlink; if it is a directory the symlink is
+        /// moved into it
+        #[structopt(name = "TO", parse(from_os_str))]
+        to: PathBuf,
+    },
+    #[structopt(name = "rename")]
+    Rename {
+        /// Name of the file in the repository or a symlink to it
+        #[structopt(name = "OLD_NAME", parse(from_os_str))]
+        old_name: PathBuf,
+        /// New file name in the repository
+        #[structopt(name = "NEW_NAME")]
+        new_name: String,
+        /// Directory to search for symlinks to the file [default: home directory]
+        #[structopt(long, parse(from_os_str))]
+        search_root: Option<PathBuf>,
+    },
+    #[structopt(name = "move")]
+    Move {
+        /// Name of the file in the repository or a symlink to it
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+        /// Subdirectory of the repository to move the file into
+        #[structopt(name = "SUBDIR", parse(from_os_str))]
+        subdir: PathBuf,
+        /// Directory to search for symlinks to the file [default: home directory]
+        #[structopt(long, parse(from_os_str))]
+        search_root: Option<PathBuf>,
+    },
+    #[structopt(name = "restore")]
+    Restore {
+        /// Name of the file in the repository
+        #[structopt(name = "REPO_FILE_NAME", parse(from_os_str))]
+        name: PathBuf,
+        /// Where to create the symlink
+        #[structopt(name = "DEST_PATH", parse(from_os_str))]
+        dest: PathBuf,
+        /// Overwrite an existing file at the destination
+        #[structopt(short, long)]
+        force: bool,
+        /// Create missing parent directories of the destination
+        #[structopt(short, long)]
+        parents: bool,
+    },
+    #[structopt(name = "export")]
+    Export {
+        /// Directory to copy the files into; created if missing
+        #[structopt(name = "DEST_DIR", parse(from_os_str))]
+        dest_dir: PathBuf,
+        /// Names of files in the repository or symlinks to them
+        // The conflict is declared here rather than on --all, where it
+        // would break the generation of zsh completions
+        #[structopt(name = "NAME", parse(from_os_str), required_unless = "all", conflicts_with = "all")]
+        names: Vec<PathBuf>,
+        /// Export every file in the repository
+        #[structopt(long)]
+        all: bool,
+        /// Overwrite existing files in DEST_DIR
+        #[structopt(short, long)]
+        force: bool,
+    },
+    #[structopt(name = "list")]
+    List {
+        /// Show the size and the modification time of each file
+        #[structopt(short, long)]
+        long: bool,
+        /// Print full paths instead of file names
+        #[structopt(long)]
+        full_path: bool,
+        /// Directory to search for symlinks pointing into the repository,
+        /// instead of looking only where each file was added from
+        #[structopt(long, parse(from_os_str))]
+        search_root: Option<PathBuf>,
+        /// Show only files carrying this tag
+        #[structopt(long)]
+        tag: Option<String>,
+        /// Show only files whose name matches this wildcard pattern
+        #[structopt(name = "PATTERN")]
+        pattern: Option<String>,
+    },
+    #[structopt(name = "search")]
+    Search {
+        /// Substring to look for in file names
+        #[structopt(name = "PATTERN")]
+        pattern: String,
+        /// Treat PATTERN as a regular expression
+        #[structopt(long)]
+        regex: bool,
+        /// Ignore case when matching
+        #[structopt(short, long)]
+        ignore_case: bool,
+        /// Show the size and the modification time of each file
+        #[structopt(short, long)]
+        long: bool,
+        /// Print full paths instead of file names
+        #[structopt(long)]
+        full_path: bool,
+        /// Directory to search for symlinks pointing into the repository,
+        /// instead of looking only where each file was added from
+        #[structopt(long, parse(from_os_str))]
+        search_root: Option<PathBuf>,
+    },
+    #[structopt(name = "stats")]
+    Stats,
+    #[structopt(name = "find")]
+    Find {
+        /// Substrings to look for in file names, ignoring case
+        #[structopt(name = "PATTERN", required = true)]
+        patterns: Vec<String>,
+        /// Require all the patterns to match instead of any of them
+        #[structopt(long)]
+        and: bool,
+        /// Print bare file names instead of full paths
+        #[structopt(long)]
+        name_only: bool,
+    },
+    #[structopt(name = "check", alias = "fsck")]
+    Check {
+        /// Directories to search for symlinks into the repository; orphaned
+        /// files and broken links are only detected when given
+        #[structopt(name = "ROOT", parse(from_os_str))]
+        search_roots: Vec<PathBuf>,
+        /// Repoint broken links at the repository file of the same name, or
+        /// at a file chosen interactively
+        #[structopt(long, requires = "ROOT")]
+        fix: bool,
+        /// Skip broken links that cannot be repaired automatically instead
+        /// of asking
+        #[structopt(short, long, requires = "fix")]
+        yes: bool,
+    },
+    #[structopt(name = "gc")]
+    Gc {
+        /// Directories to search for symlinks into the repository [default: home directory]
+        #[structopt(name = "SEARCH_ROOT", parse(from_os_str))]
+        search_roots: Vec<PathBuf>,
+        /// Move the files no symlink refers to into this directory instead of
+        /// deleting them
+        #[structopt(long, parse(from_os_str))]
+        move_to: Option<PathBuf>,
+        /// Delete without asking for confirmation
+        #[structopt(short, long)]
+        yes: bool,
+        /// Delete the files no symlink refers to without asking; same as --yes
+        #[structopt(short, long, conflicts_with = "move-to")]
+        force: bool,
+    },
+    #[structopt(name = "verify")]
+    Verify {
+        /// Record the current digests of files whose digest is missing or
+        /// does not match
+        #[structopt(long)]
+        update: bool,
+    },
+    #[structopt(name = "prune")]
+    Prune {
+        /// Remove the sidecars of files that are gone instead of only listing
+        /// them
+        #[structopt(long)]
+        apply: bool,
+    },
+    #[structopt(name = "dedupe", alias = "dup")]
+    Dedupe {
+        /// Remove all but one file of each group of identical files and point
+        /// the symlinks to the removed ones at the remaining one
+        #[structopt(long, possible_values = &["keep-oldest"])]
+        resolve: Option<Resolve>,
+        /// Directory to search for symlinks to the duplicates [default: home directory]
+        #[structopt(long, parse(from_os_str))]
+        search_root: Option<PathBuf>,
+    },
+    #[structopt(name = "status")]
+    Status {
+        /// Directories to scan for symlinks into the repository [default: home directory]
+        #[structopt(name = "DIR", parse(from_os_str))]
+        dirs: Vec<PathBuf>,
+    },
+    #[structopt(name = "info")]
+    Info {
+        /// Symlink into the repository or name of a file in the repository
+        #[structopt(name = "PATH", parse(from_os_str))]
+        path: PathBuf,
+        /// Directory to search for symlinks into the repository [default: home directory]
+        #[structopt(long, parse(from_os_str))]
+        search_root: Option<PathBuf>,
+    },
+    #[structopt(name = "which")]
+    Which {
+        /// Symlink into the repository, or a file in it
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+    },
+    #[structopt(name = "open")]
+    Open {
+        /// Symlink into the repository, name of a file in the repository or
+        /// the beginning of one
+        #[structopt(name = "NAME_OR_LINK", parse(from_os_str))]
+        name: PathBuf,
+        /// Print the path of the file instead of opening it
+        #[structopt(long)]
+        print: bool,
+        /// Command to open the file with instead of the configured viewer
+        #[structopt(long, value_name = "CMD", conflicts_with = "print")]
+        app: Option<String>,
+    },
+    #[structopt(name = "grep")]
+    Grep {
+        #[structopt(name = "QUERY")]
+        query: String,
+        /// Distinguish upper and lower case
+        #[structopt(long)]
+        case_sensitive: bool,
+    },
+    #[structopt(name = "log")]
+    Log {
+        /// Show only this many of the most recent entries
+        #[structopt(long)]
+        limit: Option<usize>,
+        /// Show only entries concerning this file in the repository
+        #[structopt(long)]
+        file: Option<String>,
+    },
+    #[structopt(name = "undo")]
+    Undo {
+        /// Number of operations to undo
+        #[structopt(long, default_value = "1")]
+        steps: usize,
+        /// Undo even if the files no longer match the journal; a file found in
+        /// the original place is kept and the file is put back next to it
+        #[structopt(short, long)]
+        force: bool,
+    },
+    #[structopt(name = "tag")]
+    Tag {
+        #[structopt(subcommand)]
+        cmd: TagCommand,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ConfigCommand {
+    /// Print the value of a setting
+    #[structopt(name = "get")]
+    Get {
+        #[structopt(name = "KEY")]
+        key: String,
+    },
+    /// Change a setting, creating the config file if needed
+    #[structopt(name = "set")]
+    Set {
+        #[structopt(name = "KEY")]
+        key: String,
+        #[structopt(name = "VALUE")]
+        value: String,
+    },
+    /// Print the location of the config file
+    #[structopt(name = "path")]
+    Path,
+}
+
+#[derive(StructOpt, Debug)]
+enum TagCommand {
+    #[structopt(name = "add")]
+    Add {
+        /// Symlink into the repository or name of a file in the repository
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+        #[structopt(name = "TAG", required = true)]
+        tags: Vec<String>,
+    },
+    #[structopt(name = "remove", alias = "rm")]
+    Remove {
+        /// Symlink into the repository or name of a file in the repository
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+        #[structopt(name = "TAG", required = true)]
+        tags: Vec<String>,
+    },
+    #[structopt(name = "list")]
+    List {
+        /// List the tags of this file instead of all tags with their usage counts
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+enum Conflict {
+    Overwrite,
+    Skip,
+    Rename,
+}
+
+impl std::str::FromStr for Conflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(Conflict::Overwrite),
+            "skip" => Ok(Conflict::Skip),
+            // Numbers are appended as in `paper-1.pdf`
+            "suffix" | "rename" => Ok(Conflict::Rename),
+            _ => Err(format!("unknown conflict strategy: {}", s)),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+enum Resolve {
+    // Keep the file with the oldest modification time
+    KeepOldest,
+}
+
+impl std::str::FromStr for Resolve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-oldest" => Ok(Resolve::KeepOldest),
+            _ => Err(format!("unknown resolution strategy: {}", s)),
+        }
+    }
+}
+
+#[derive(Default, Eq, PartialEq, Clone, Copy, Debug)]
+enum AddMode {
+    // Move the file into the repository and leave a symlink in its place
+    #[default]
+    Move,
+    // Copy the file into the repository and leave the original alone
+    Copy,
+    // Copy the file into the repository and replace the original with a
+    // symlink
+    CopyAndLink,
+    // Hard link the file into the repository; both names refer to the same
+    // inode
+    HardLink,
+}
+
+// How add and import bring files into the repository
+#[derive(Default, Debug)]
+struct AddOptions {
+    // Add the files inside directories as well
+    recursive: bool,
+    // What to do about a name already in the repository; fail if unset
+    conflict: Option<Conflict>,
+    mode: AddMode,
+    // Where to leave the symlinks instead of in place of the files
+    link_dir: Option<PathBuf>,
+    // Ask before adding each file
+    interactive: bool,
+    // Add files whose content is already in the repository without asking
+    yes: bool,
+    dry_run: bool,
+}
+
+// What has become of the files given to add or import so far
+#[derive(Default, Debug)]
+struct AddOutcome {
+    added: Vec<(PathBuf, PathBuf)>,
+    skipped: Vec<PathBuf>,
+    failed: Vec<(PathBuf, String)>,
+}
+
+#[derive(Serialize, Debug)]
+struct AddReport<'a> {
+    added: Vec<AddedFile<'a>>,
+    skipped: &'a [PathBuf],
+    failed: Vec<FileReason<'a>>,
+    // Set when add was aborted, in which case the lists above tell how far
+    // it got
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Like `AddReport`, with the reason each file was skipped for
+#[derive(Serialize, Debug)]
+struct ImportReport<'a> {
+    added: Vec<AddedFile<'a>>,
+    skipped: Vec<FileReason<'a>>,
+    failed: Vec<FileReason<'a>>,
+}
+
+#[derive(Serialize, Debug)]
+struct AddedFile<'a> {
+    source: &'a Path,
+    repo_path: &'a Path,
+}
+
+#[derive(Serialize, Debug)]
+struct FileReason<'a> {
+    path: &'a Path,
+    reason: &'a str,
+}
+
+fn add<W: WriteColor>(files: Vec<PathBuf>, options: AddOptions, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    if let Some(ref dir) = options.link_dir {
+        if options.mode != AddMode::Move {
+            return Err(Error::Path("Symlinks can only be placed elsewhere when files are moved into the repository".to_string()));
+        }
+        if !dir.is_dir() {
+            return Err(Error::NotDir(dir.clone()));
+        }
+    }
+    let mut outcome = AddOutcome::default();
+    let result = add_files(files, &options, &config, &mut outcome, printer);
+
+    if printer.output == OutputMode::Json {
+        let report = AddReport {
+            added: outcome.added.iter().map(|(source, repo_path)| AddedFile { source, repo_path }).collect(),
+            skipped: &outcome.skipped,
+            failed: outcome.failed.iter().map(|(path, reason)| FileReason { path, reason }).collect(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        };
+        printer.line(serde_json::to_string_pretty(&report)?)?;
+    }
+    else {
+        report_failed(printer, &outcome.failed)?;
+        if printer.verbosity != Verbosity::Quiet && !outcome.skipped.is_empty() {
+            printer.err_line("The following files are skipped:")?;
+            for fp in &outcome.skipped {
+                printer.err_line(fp.display())?;
+            }
+        }
+    }
+    result?;
+
+    if outcome.failed.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(Error::NotAdded(outcome.failed.len()))
+    }
+}
+
+fn add_files<W: WriteColor>(files: Vec<PathBuf>, options: &AddOptions, config: &Config, outcome: &mut AddOutcome, printer: &mut Printer<W>) -> Result<(), Error> {
+    let failed = &mut outcome.failed;
+
+    // Expand wildcards the shell has left untouched
+    let mut expanded = Vec::new();
+    for fp in files {
+        if fp.symlink_metadata().is_err() && has_glob_meta(&fp.to_string_lossy()) {
+            let matches = expand_glob(&fp);
+            if matches.is_empty() {
+                printer.warn(format_args!("{} did not match any files", fp.display()))?;
+            }
+            expanded.extend(matches);
+        }
+        else {
+            expanded.push(fp);
+        }
+    }
+
+    // Collect regular files paired with their destinations in the repository
+    let mut targets = Vec::new();
+    for fp in expanded {
+        match file_type(&fp)? {
+            FileType::Dir if options.recursive => {
+                // Files are flattened into the top of the repository
+                for (path, ft) in walk(&fp)? {
+                    if ft == FileType::File {
+                        let rel = PathBuf::from(path.file_name().unwrap());
+                        targets.push((path, rel));
+                    }
+                    else if is_managed(&path, &config.repo_dir)? {
+                        failed.push((path, "file is already managed".into()));
+                    }
+                    else {
+                        failed.push((path, "file is a symlink, which cannot be added".into()));
+                    }
+                }
+            },
+            FileType::Dir => {
+                failed.push((fp.clone(), "file is a directory, which cannot be added".into()));
+            },
+            FileType::Symlink if is_managed(&fp, &config.repo_dir)? => {
+                failed.push((fp.clone(), "file is already managed".into()));
+            },
+            FileType::Symlink => {
+                failed.push((fp.clone(), "file is a symlink, which cannot be added".into()));
+            },
+            FileType::Other => {
+                failed.push((fp.clone(), "file is not a regular file, which cannot be added".into()));
+            },
+            FileType::File => {
+                let rel = PathBuf::from(fp.file_name().unwrap());
+                targets.push((fp, rel));
+            },
+        }
+    }
+
+    if options.interactive && !options.dry_run {
+        let declined = confirm_targets_with(&mut targets, &config.repo_dir, confirm)?;
+        outcome.skipped.extend(declined);
+    }
+
+    add_targets(targets, options, config, outcome, printer)
+}
+
+// Ask about each target, dropping and returning those the user declines
+fn confirm_targets_with<F>(targets: &mut Vec<(PathBuf, PathBuf)>, repo_dir: &Path, confirm: F) -> io::Result<Vec<PathBuf>>
+    where F: Fn(&str) -> io::Result<bool>
+{
+    let mut accepted = Vec::new();
+    let mut declined = Vec::new();
+    for (fp, rel) in targets.drain(..) {
+        if confirm(&format!("Add {} -> {}?", fp.display(), repo_dir.join(&rel).display()))? {
+            accepted.push((fp, rel));
+        }
+        else {
+            declined.push(fp);
+        }
+    }
+    *targets = accepted;
+    Ok(declined)
+}
+
+// Move each file into the repository under its paired name and leave a
+// symlink behind, recording the files moved with their new locations.  A
+// file that cannot be added is recorded in `failed` without stopping the
+// others.  Unless `yes` is given, the user is asked before adding a file whose
+// content is already in the repository.
+fn add_targets<W: WriteColor>(targets: Vec<(PathBuf, PathBuf)>, options: &AddOptions, config: &Config, outcome: &mut AddOutcome, printer: &mut Printer<W>) -> Result<(), Error> {
+    let AddOptions { conflict, mode, yes, dry_run, .. } = *options;
+    let link_dir = options.link_dir.as_deref();
+    let AddOutcome { added, skipped, failed } = outcome;
+    let mut digests = None;
+    for (fp, rel) in targets {
+        // It would be moved onto itself and replaced with a symlink to itself
+        if is_in_repo(&fp, &config.repo_dir) {
+            failed.push((fp.clone(), "already inside repo".into()));
+            continue;
+        }
+        let link = match link_dir {
+            Some(dir) => dir.join(fp.file_name().unwrap()),
+            None => fp.clone(),
+        };
+        if link != fp && link.symlink_metadata().is_ok() {
+            failed.push((fp.clone(), format!("{} exists", link.display())));
+            continue;
+        }
+        // Move
+        let mut to = config.repo_dir.join(rel);
+        if to == config.repo_dir.join(DIGEST_INDEX) {
+            failed.push((fp.clone(), "name is used by paperman itself".into()));
+            continue;
+        }
+        if to.symlink_metadata().is_ok() {
+            match conflict {
+                None => {
+                    failed.push((fp.clone(), "name already exists in repository".into()));
+                    continue;
+                },
+                Some(Conflict::Skip) => {
+                    printer.note(format_args!("skipped {} -> {}", fp.display(), to.display()))?;
+                    skipped.push(fp);
+                    continue;
+                },
+                Some(Conflict::Overwrite) => {
+                    // Its link would silently start pointing at other content
+                    if let Some(link) = recorded_link(&to, &config.repo_dir)? {
+                        failed.push((fp.clone(), format!("the file it would replace is still linked from {}", link.display())));
+                        continue;
+                    }
+                },
+                Some(Conflict::Rename) => {
+                    to = (1..).map(|i| numbered_path(&to, i)).find(|p| p.symlink_metadata().is_err()).unwrap();
+                },
+            }
+        }
+        // Dry runs neither read the files nor ask about them
+        if dry_run {
+            let link_ref = link_ref(link.parent().unwrap(), &to, config.symlink_style)?;
+            match mode {
+                AddMode::Move => printer.say(format_args!("[dry-run] move {} -> {}", fp.display(), to.display()))?,
+                AddMode::HardLink => printer.say(format_args!("[dry-run] hard link {} -> {}", to.display(), fp.display()))?,
+                AddMode::Copy | AddMode::CopyAndLink => printer.say(format_args!("[dry-run] copy {} -> {}", fp.display(), to.display()))?,
+            }
+            if mode == AddMode::Move || mode == AddMode::CopyAndLink {
+                printer.say(format_args!("[dry-run] symlink {} -> {}", link.display(), link_ref.display()))?;
+            }
+            printer.say(format_args!("[dry-run] write {}", sidecar_path(&to, &config.repo_dir).display()))?;
+            added.push((fp, to));
+            continue;
+        }
+        let digest = match sha256_file(&fp) {
+            Ok(digest) => digest,
+            Err(e) => {
+                failed.push((fp.clone(), e.to_string()));
+                continue;
+            },
+        };
+        if digests.is_none() {
+            digests = Some(recorded_digests(&config.repo_dir)?);
+        }
+        let digests = digests.as_mut().unwrap();
+        match digests.get(&digest) {
+            Some(existing) if *existing != to => {
+                printer.warn(format_args!("{} has the same content as {}", fp.display(), existing.display()))?;
+                if !yes && !confirm(&format!("Add {} anyway?", fp.display()))? {
+                    printer.note(format_args!("skipped {} -> {}", fp.display(), to.display()))?;
+                    skipped.push(fp);
+                    continue;
+                }
+            },
+            _ => (),
+        }
+        digests.entry(digest).or_insert_with(|| to.clone());
+        let original_path = to_absolute(&fp)?;
+        debug!("add {} -> {} ({:?}, link at {})", fp.display(), to.display(), mode, link.display());
+        let to_dir = to.parent().unwrap();
+        let created_to_dir = !to_dir.exists();
+        if let Err(e) = fs::create_dir_all(to_dir) {
+            failed.push((fp.clone(), e.to_string()));
+            continue;
+        }
+        let (result, action) = match mode {
+            AddMode::Move => (move_and_link(&fp, &to, &link, config.symlink_style), "move"),
+            AddMode::Copy | AddMode::CopyAndLink => (copy_and_link(&fp, &to, mode == AddMode::CopyAndLink, config.symlink_style), "copy"),
+            AddMode::HardLink => (hard_link(&fp, &to), "hard link"),
+        };
+        if let Err(e) = result {
+            // Do not leave behind an empty directory we have just created
+            if created_to_dir {
+                let _ = fs::remove_dir(to_dir);
+            }
+            debug!("failed to add {}: {:?}", fp.display(), e);
+            failed.push((fp.clone(), format!("failed to {}: {}", action, e)));
+            continue;
+        }
+        match mode {
+            AddMode::Move => printer.note(format_args!("moved {} to {}", fp.display(), to.display()))?,
+            AddMode::Copy | AddMode::CopyAndLink => printer.note(format_args!("copied {} to {}", fp.display(), to.display()))?,
+            AddMode::HardLink => printer.note(format_args!("hard linked {} to {}", to.display(), fp.display()))?,
+        }
+        if mode == AddMode::Move || mode == AddMode::CopyAndLink {
+            printer.note(format_args!("created symlink {}", link.display()))?;
+        }
+        // The file is in place by now, so a missing sidecar is only worth a
+        // warning
+        if let Err(e) = record_metadata(&to, original_path.clone(), mode == AddMode::Copy, &config.repo_dir) {
+            printer.warn(format_args!("failed to record metadata of {}: {}", to.display(), e))?;
+        }
+        journal(&config.repo_dir, "add", &original_path, &to, printer)?;
+        added.push((fp, to));
+    }
+    Ok(())
+}
+
+// Add the files found under `dir` as they are, with only `conflict` and
+// `dry_run` taken from `options`
+fn import<W: WriteColor>(dir: PathBuf, max_depth: Option<usize>, exts: Vec<String>, include_empty: bool, options: AddOptions, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    if file_type(&dir).map_err(|e| Error::IoAt(dir.clone(), e))? != FileType::Dir {
+        return Err(Error::NotDir(dir));
+    }
+    let exts: Vec<_> = exts.iter().map(|ext| ext.trim_start_matches('.').to_lowercase()).collect();
+
+    let mut skipped = Vec::new();
+    let mut targets = Vec::new();
+    for (path, ft) in walk_max_depth(&dir, max_depth)? {
+        let ext = path.extension().map(|ext| ext.to_string_lossy().to_lowercase());
+        if ft == FileType::Symlink {
+            skipped.push((path, "symlink"));
+        }
+        else if !exts.is_empty() && !matches!(ext, Some(ref ext) if exts.contains(ext)) {
+            skipped.push((path, "extension not selected"));
+        }
+        else if !include_empty && fs::metadata(&path).map(|m| m.len() == 0).unwrap_or(false) {
+            skipped.push((path, "empty file"));
+        }
+        else {
+            let rel = PathBuf::from(path.file_name().unwrap());
+            targets.push((path, rel));
+        }
+    }
+
+    let mut outcome = AddOutcome::default();
+    let options = AddOptions { conflict: options.conflict, mode: config.link_type.add_mode(), yes: true, dry_run: options.dry_run, ..AddOptions::default() };
+    add_targets(targets, &options, &config, &mut outcome, printer)?;
+    skipped.extend(outcome.skipped.into_iter().map(|fp| (fp, "name already exists in repository")));
+    skipped.sort();
+    if printer.output == OutputMode::Json {
+        let report = ImportReport {
+            added: outcome.added.iter().filter(|_| !options.dry_run).map(|(source, repo_path)| AddedFile { source, repo_path }).collect(),
+            skipped: skipped.iter().map(|(path, reason)| FileReason { path, reason }).collect(),
+            failed: outcome.failed.iter().map(|(path, reason)| FileReason { path, reason }).collect(),
+        };
+        printer.line(serde_json::to_string_pretty(&report)?)?;
+    }
+    else {
+        if !options.dry_run {
+            for (fp, to) in &outcome.added {
+                printer.line(format_args!("added\t{}\t-> {}", fp.display(), to.display()))?;
+            }
+        }
+        for (fp, reason) in &skipped {
+            printer.line(format_args!("skipped\t{}\t({})", fp.display(), reason))?;
+        }
+        report_failed(printer, &outcome.failed)?;
+        printer.line(format_args!("{} added, {} skipped, {} failed", outcome.added.len(), skipped.len(), outcome.failed.len()))?;
+    }
+
+    if outcome.failed.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(Error::NotAdded(outcome.failed.len()))
+    }
+}
+
+// Insert a number before the extension, e.g. `paper.pdf` to `paper-1.pdf`
+fn numbered_path(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    match path.extension() {
+        Some(ext) => path.with_file_name(format!("{}-{}.{}", stem, n, ext.to_string_lossy())),
+        None => path.with_file_name(format!("{}-{}", stem, n)),
+    }
+}
+
+fn move_and_link(fp: &Path, to: &Path, link: &Path, style: SymlinkStyle) -> Result<(), Error> {
+    move_and_link_with(fp, to, link, style, |src, dst| unix::fs::symlink(src, dst))
+}
+
+// Move `fp` to `to` and create a symlink to it at `link`, which is usually
+// `fp` itself, moving the file back if the symlink cannot be created
+fn move_and_link_with<F>(fp: &Path, to: &Path, link: &Path, style: SymlinkStyle, symlink: F) -> Result<(), Error>
+    where F: Fn(&Path, &Path) -> io::Result<()>
+{
+    // Move
+    move_file(fp, to)?;
+
+    // Link
+    let result = link_ref(link.parent().unwrap(), to, style)
+        .and_then(|link_ref| {
+            debug!("symlink src={} dst={}", link_ref.display(), link.display());
+            symlink(&link_ref, link).map_err(Error::from)
+        });
+    if let Err(e) = result {
+        // Roll back, unless something else has taken the original place
+        if fp.symlink_metadata().is_ok() {
+            return Err(Error::LeftBehind { error: Box::new(e), path: to.to_path_buf() });
+        }
+        if let Err(rollback) = move_file(to, fp) {
+            return Err(Error::RollbackFailed { error: Box::new(e), path: to.to_path_buf(), rollback });
+        }
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+// Give `fp` the additional name `to`, replacing whatever is there
+fn hard_link(fp: &Path, to: &Path) -> Result<(), Error> {
+    let tmp = to.with_file_name(format!(".{}.paperman-tmp", to.file_name().unwrap().to_string_lossy()));
+    fs::hard_link(fp, &tmp)?;
+    if let Err(e) = fs::rename(&tmp, to) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+// Find the repository file that `path` is a hard link to
+fn hard_link_in_repo(path: &Path, repo_dir: &Path) -> Result<Option<PathBuf>, Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::metadata(path).map_err(|e| Error::IoAt(path.to_path_buf(), e))?;
+    if metadata.nlink() < 2 || !repo_dir.is_dir() {
+        return Ok(None);
+    }
+    for fp in repo_files(repo_dir)? {
+        let m = fs::metadata(&fp).map_err(|e| Error::IoAt(fp.clone(), e))?;
+        if (m.dev(), m.ino()) == (metadata.dev(), metadata.ino()) && fp != to_absolute(path)? {
+            return Ok(Some(fp));
+        }
+    }
+    Ok(None)
+}
+
+// Like `find_links`, but for regular files sharing their inode with a file in
+// the repository
+fn find_hard_links<P: AsRef<Path>, Q: AsRef<Path>>(root: P, repo_dir: Q) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let repo_dir = to_absolute(repo_dir)?;
+    let mut inodes = BTreeMap::new();
+    if repo_dir.is_dir() {
+        for fp in repo_files(&repo_dir)? {
+            if let Ok(m) = fs::metadata(&fp) {
+                if m.nlink() > 1 {
+                    inodes.insert((m.dev(), m.ino()), fp);
+                }
+            }
+        }
+    }
+    let mut links = Vec::new();
+    if inodes.is_empty() {
+        return Ok(links);
+    }
+    let canonical_repo_dir = repo_dir.canonicalize().unwrap_or_else(|_| repo_dir.clone());
+    let mut stack = vec![to_absolute(root)?];
+    while let Some(dir) = stack.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match file_type(&path) {
+                Ok(FileType::Dir) if path != repo_dir && path != canonical_repo_dir => {
+                    stack.push(path);
+                },
+                Ok(FileType::File) => {
+                    if let Some(target) = fs::metadata(&path).ok().and_then(|m| inodes.get(&(m.dev(), m.ino()))) {
+                        links.push((path, target.clone()));
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+    links.sort();
+    Ok(links)
+}
+
+// Copy a file into the repository, optionally replacing the original with a
+// symlink to the copy.  The copy is written under a temporary name and then
+// renamed into place, so that an interrupted copy never leaves a truncated
+// file under the final name, and it is removed again if anything fails.
+fn copy_and_link(fp: &Path, to: &Path, link: bool, style: SymlinkStyle) -> Result<(), Error> {
+    let tmp = to.with_file_name(format!(".{}.paperman-tmp", to.file_name().unwrap().to_string_lossy()));
+    if let Err(e) = copy_with_mtime(fp, &tmp).and_then(|_| fs::rename(&tmp, to)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    if link {
+        let result = link_ref(fp.parent().unwrap(), to, style)
+            .and_then(|link_ref| replace_symlink(&link_ref, fp));
+        if let Err(e) = result {
+            let _ = fs::remove_file(to);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn move_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    move_file_with(from.as_ref(), to.as_ref(), |from, to| fs::rename(from, to))
+}
+
+// Rename `from` to `to`, falling back to copy-then-delete when they reside on
+// different filesystems.  The copy is made under a temporary name, flushed to
+// disk and renamed into place before `from` is removed, so that `from` is
+// left intact if anything goes wrong.
+fn move_file_with<F>(from: &Path, to: &Path, rename: F) -> io::Result<()>
+    where F: Fn(&Path, &Path) -> io::Result<()>
+{
+    debug!("rename from={} to={}", from.display(), to.display());
+    match rename(from, to) {
+        Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            info!("{} is on another filesystem; copying it instead", from.display());
+            let metadata = fs::metadata(from)?;
+            let tmp = to.with_file_name(format!(".{}.paperman-tmp", to.file_name().unwrap().to_string_lossy()));
+            // `fs::copy` happens to copy the permission bits as well, but
+            // they are set explicitly so as not to depend on it.  The
+            // timestamps have to be restored by hand anyway.
+            let result = fs::copy(from, &tmp)
+                .and_then(|_| fs::set_permissions(&tmp, metadata.permissions()))
+                .and_then(|_| copy_times(&metadata, &tmp))
+                .and_then(|_| File::open(&tmp)?.sync_all())
+                .and_then(|_| fs::rename(&tmp, to));
+            if let Err(e) = result {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
+            if let Err(e) = fs::remove_file(from) {
+                let _ = fs::remove_file(to);
+                return Err(e);
+            }
+            Ok(())
+        },
+        result => result,
+    }
+}
+
+fn remove<W: WriteColor>(files: Vec<PathBuf>, search_root: Option<PathBuf>, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let mut links = None;
+    let mut failed = Vec::new();
+    let mut removed = Vec::new();
+    for fp in files {
+        // Accept either a symlink into the repository or the name of a file
+        // inside the repository, in which case its symlink is searched for
+        let link = match file_type(&fp) {
+            Ok(FileType::Symlink) => fp.clone(),
+            Ok(FileType::File) => {
+                // The file and its repository entry are the same inode, so
+                // dropping the latter is all there is to do
+                match hard_link_in_repo(&fp, &to_absolute(&config.repo_dir)?)? {
+                    Some(target) => {
+                        if dry_run {
+                            printer.say(format_args!("[dry-run] remove {}", target.display()))?;
+                            continue;
+                        }
+                        if fs::remove_file(&target).is_err() {
+                            failed.push((fp.clone(), "failed to remove the file from the repository".into()));
+                            continue;
+                        }
+                        journal(&config.repo_dir, "remove", &fp, &target, printer)?;
+                        removed.push(target);
+                    },
+                    None => failed.push((fp.clone(), "file exists and is not a symlink".into())),
+                }
+                continue;
+            },
+            Ok(_) => {
+                failed.push((fp.clone(), "file exists and is not a symlink".into()));
+                continue;
+            },
+            Err(_) => {
+                let target = to_absolute(config.repo_dir.join(&fp))?;
+                if fp.components().count() != 1 || !target.is_file() {
+                    failed.push((fp.clone(), "no such symlink or file in the repository".into()));
+                    continue;
+                }
+                if links.is_none() {
+                    links = Some(find_links(search_root_or_home(search_root.clone())?, &config.repo_dir)?);
+                }
+                let referrers: Vec<_> = links.as_ref().unwrap().iter().filter(|(_, t)| *t == target).collect();
+                match referrers.len() {
+                    0 => {
+                        failed.push((fp.clone(), "no symlink to the file was found".into()));
+                        continue;
+                    },
+                    1 => referrers[0].0.clone(),
+                    _ => {
+                        failed.push((fp.clone(), "file is referenced by multiple symlinks".into()));
+                        continue;
+                    },
+                }
+            },
+        };
+        let from = match target_in_repo(&link, &config.repo_dir)? {
+            Some(target) => target,
+            None => {
+                failed.push((fp.clone(), "symlink does not point into the repository".into()));
+                continue;
+            },
+        };
+        match file_type(&from) {
+            Ok(FileType::File) => (),
+            Ok(_) => {
+                failed.push((fp.clone(), "symlink does not point to a regular file".into()));
+                continue;
+            },
+            Err(_) => {
+                failed.push((fp.clone(), "symlink is broken; the file has been deleted from the repository".into()));
+                continue;
+            },
+        }
+
+        if dry_run {
+            printer.say(format_args!("[dry-run] move {} -> {}", from.display(), link.display()))?;
+            continue;
+        }
+        // Move back.  Renaming onto the symlink replaces it atomically, so
+        // there is no window in which both or neither of them exist.
+        if let Err(e) = move_file(&from, &link) {
+            failed.push((fp.clone(), format!("failed to move the file back: {}", e)));
+            continue;
+        }
+        journal(&config.repo_dir, "remove", &link, &from, printer)?;
+        removed.push(from);
+    }
+
+    forget_metadata(&removed, &config.repo_dir)?;
+    report_changes(printer, &removed, &failed)?;
+
+    if failed.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(Error::NotRemoved(failed.len()))
+    }
+}
+
+fn mv<W: WriteColor>(link: PathBuf, new_name: String, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    validate_name(&new_name)?;
+    if file_type(&link)? != FileType::Symlink {
+        return Err(Error::NotManaged(link));
+    }
+    let from = match target_in_repo(&link, &config.repo_dir)? {
+        Some(target) => target,
+        None => return Err(Error::NotManaged(link)),
+    };
+    if file_type(&from).ok() != Some(FileType::File) {
+        return Err(Error::BrokenLink(link));
+    }
+    let to = from.with_file_name(&new_name);
+    if to.symlink_metadata().is_ok() {
+        return Err(Error::AlreadyInRepo(to));
+    }
+    let link_ref = link_ref(to_absolute(&link)?.parent().unwrap(), &to, config.symlink_style)?;
+
+    if dry_run {
+        printer.say(format_args!("[dry-run] move {} -> {}", from.display(), to.display()))?;
+        printer.say(format_args!("[dry-run] symlink {} -> {}", link.display(), link_ref.display()))?;
+        let sidecar = sidecar_path(&from, &config.repo_dir);
+        if sidecar.exists() {
+            printer.say(format_args!("[dry-run] move {} -> {}", sidecar.display(), sidecar_path(&to, &config.repo_dir).display()))?;
+        }
+        return Ok(());
+    }
+    fs::rename(&from, &to)?;
+    if let Err(e) = replace_symlink(&link_ref, &link) {
+        if let Err(rollback) = fs::rename(&to, &from) {
+            return Err(Error::RollbackFailed { error: Box::new(e), path: to, rollback });
+        }
+        return Err(e);
+    }
+
+    // Carry the sidecar over to the new name
+    move_sidecar(&from, &to, &config.repo_dir)?;
+    journal(&config.repo_dir, "rename", &from, &to, printer)?;
+
+    report_changed(printer, &to)
+}
+
+// Create further symlinks to a repository file, one at each destination
+fn link<W: WriteColor>(name: PathBuf, dests: Vec<PathBuf>, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let target = resolve_repo_file(&name, &to_absolute(&config.repo_dir)?)?;
+
+    let mut linked = Vec::new();
+    let mut failed = Vec::new();
+    for dest in dests {
+        // A directory stands for the file of the same name inside it
+        let link = if dest.is_dir() { dest.join(target.file_name().unwrap()) } else { dest.clone() };
+        let link = to_absolute(&link)?;
+        if link.symlink_metadata().is_ok() {
+            failed.push((dest, "destination file exists".to_string()));
+            continue;
+        }
+        let parent = link.parent().unwrap();
+        if !parent.is_dir() {
+            failed.push((dest, "parent directory does not exist".to_string()));
+            continue;
+        }
+        let link_ref = link_ref(parent, &target, config.symlink_style)?;
+
+        if dry_run {
+            printer.say(format_args!("[dry-run] symlink {} -> {}", link.display(), link_ref.display()))?;
+            continue;
+        }
+        match unix::fs::symlink(&link_ref, &link) {
+            Ok(()) => linked.push(link),
+            Err(e) => failed.push((dest, e.to_string())),
+        }
+    }
+
+    report_changes(printer, &linked, &failed)?;
+    if failed.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(Error::NotLinked(failed.len()))
+    }
+}
+
+// Remove symlinks into the repository, leaving the files they point to alone
+fn unmanage<W: WriteColor>(links: Vec<PathBuf>, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let mut unlinked = Vec::new();
+    let mut failed = Vec::new();
+    for link in links {
+        match file_type(&link) {
+            Ok(FileType::Symlink) => (),
+            Ok(_) => {
+                failed.push((link, "file exists and is not a symlink"));
+                continue;
+            },
+            Err(_) => {
+                failed.push((link, "no such symlink"));
+                continue;
+            },
+        }
+        if target_in_repo(&link, &config.repo_dir)?.is_none() {
+            failed.push((link, "symlink does not point into the repository"));
+            continue;
+        }
+
+        if dry_run {
+            printer.say(format_args!("[dry-run] remove {}", link.display()))?;
+            continue;
+        }
+        if fs::remove_file(&link).is_err() {
+            failed.push((link, "failed to remove the symlink"));
+            continue;
+        }
+        if printer.verbosity != Verbosity::Quiet {
+            printer.say(format_args!("unlinked {}", link.display()))?;
+        }
+        unlinked.push(link);
+    }
+
+    report_changes(printer, &unlinked, &failed)?;
+    if failed.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(Error::NotUnmanaged(failed.len()))
+    }
+}
+
+// Point the symlinks left dangling by moving the repository away from
+// `old_repo` at the same files in the current repository
+fn relink<W: WriteColor>(old_repo: PathBuf, search_root: Option<PathBuf>, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let old_repo = normalize(to_absolute(expand_tilde(&old_repo).ok_or(Error::HomeDir)?)?);
+
+    let mut fixed = Vec::new();
+    let mut failed = Vec::new();
+    for link in find_symlinks(search_root_or_home(search_root)?, &repo_dir)? {
+        // Links that still work are none of our business
+        if link.exists() {
+            continue;
+        }
+        let rest = match link_target(&link) {
+            Ok(target) => match target.strip_prefix(&old_repo) {
+                Ok(rest) => rest.to_path_buf(),
+                Err(_) => continue,
+            },
+            Err(_) => continue,
+        };
+        let target = repo_dir.join(rest);
+        if file_type(&target).ok() != Some(FileType::File) {
+            failed.push((link, format!("{} does not exist", target.display())));
+            continue;
+        }
+        let link_ref = link_ref(link.parent().unwrap(), &target, config.symlink_style)?;
+
+        if dry_run {
+            printer.say(format_args!("[dry-run] symlink {} -> {}", link.display(), link_ref.display()))?;
+            fixed.push(link);
+            continue;
+        }
+        match replace_symlink(&link_ref, &link) {
+            Ok(()) => {
+                printer.say(format_args!("relinked {} -> {}", link.display(), link_ref.display()))?;
+                fixed.push(link);
+            },
+            Err(e) => failed.push((link, e.to_string())),
+        }
+    }
+
+    report_changes(printer, &fixed, &failed)?;
+    printer.say(format_args!("Fixed {} symlink(s), {} could not be mapped", fixed.len(), failed.len()))?;
+    if failed.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(Error::LinksNotUpdated(failed.len()))
+    }
+}
+
+// Where the symlinks into the repository are, so that they can be recreated
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Manifest {
+    links: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ManifestEntry {
+    // Relative to the repository
+    repo_path: PathBuf,
+    link: PathBuf,
+}
+
+fn collect_manifest(search_root: &Path, repo_dir: &Path) -> Result<Manifest, Error> {
+    let links = find_links(search_root, repo_dir)?.into_iter()
+        .map(|(link, target)| ManifestEntry {
+            repo_path: target.strip_prefix(repo_dir).unwrap().to_path_buf(),
+            link,
+        })
+        .collect();
+    Ok(Manifest { links })
+}
+
+// Write the manifest as JSON if `out` ends in `.json` and as TOML otherwise
+fn export_links<W: WriteColor>(out: PathBuf, search_root: Option<PathBuf>, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let manifest = collect_manifest(&search_root_or_home(search_root)?, &repo_dir)?;
+    let content = if out.extension() == Some("json".as_ref()) {
+        serde_json::to_string_pretty(&manifest)? + "\n"
+    }
+    else {
+        toml::to_string(&manifest)?
+    };
+
+    if dry_run {
+        printer.say(format_args!("[dry-run] write {}", out.display()))?;
+        return Ok(());
+    }
+    fs::write(&out, content).map_err(|e| Error::IoAt(out.clone(), e))?;
+    printer.say(format_args!("Wrote {} symlink(s) to {}", manifest.links.len(), out.display()))?;
+    report_changed(printer, &out)
+}
+
+// Recreate the symlinks recorded by `export_links`, e.g. on a machine where
+// only the repository has been synced
+fn import_links<W: WriteColor>(manifest: PathBuf, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let content = fs::read_to_string(&manifest).map_err(|e| Error::IoAt(manifest.clone(), e))?;
+    let manifest: Manifest = if manifest.extension() == Some("json".as_ref()) {
+        serde_json::from_str(&content).map_err(|e| Error::JsonParse(manifest.clone(), e))?
+    }
+    else {
+        toml::from_str(&content).map_err(|e| Error::TomlParse(manifest.clone(), e))?
+    };
+
+    let mut created = Vec::new();
+    let mut failed = Vec::new();
+    for entry in manifest.links {
+        let target = normalize(repo_dir.join(&entry.repo_path));
+        if !target.starts_with(&repo_dir) {
+            failed.push((entry.link, format!("{} is outside the repository", entry.repo_path.display())));
+            continue;
+        }
+        if file_type(&target).ok() != Some(FileType::File) {
+            failed.push((entry.link, format!("{} is missing from the repository", entry.repo_path.display())));
+            continue;
+        }
+        let link = to_absolute(&entry.link)?;
+        if link.symlink_metadata().is_ok() {
+            if file_type(&link)? == FileType::Symlink && target_in_repo(&link, &repo_dir)?.as_ref() == Some(&target) {
+                continue;
+            }
+            failed.push((entry.link, "destination file exists".to_string()));
+            continue;
+        }
+        let parent = link.parent().unwrap();
+        let link_ref = link_ref(parent, &target, config.symlink_style)?;
+
+        if dry_run {
+            printer.say(format_args!("[dry-run] symlink {} -> {}", link.display(), link_ref.display()))?;
+            created.push(link);
+            continue;
+        }
+        if let Err(e) = fs::create_dir_all(parent).and_then(|_| unix::fs::symlink(&link_ref, &link)) {
+            failed.push((entry.link, e.to_string()));
+            continue;
+        }
+        printer.say(format_args!("linked {} -> {}", link.display(), link_ref.display()))?;
+        created.push(link);
+    }
+
+    report_changes(printer, &created, &failed)?;
+    printer.say(format_args!("Created {} symlink(s)", created.len()))?;
+    if failed.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(Error::NotLinked(failed.len()))
+    }
+}
+
+// Move a symlink into the repository elsewhere, leaving the repository file
+// where it is
+fn mvlink<W: WriteColor>(from: PathBuf, to: PathBuf, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    if file_type(&from)? != FileType::Symlink {
+        return Err(Error::NotManaged(from));
+    }
+    let target = match target_in_repo(&from, &config.repo_dir)? {
+        Some(target) => target,
+        None => return Err(Error::NotManaged(from)),
+    };
+    let to = if to.is_dir() { to.join(from.file_name().unwrap()) } else { to };
+    let to = to_absolute(&to)?;
+    if to.symlink_metadata().is_ok() {
+        return Err(Error::Exists(to));
+    }
+    let parent = to.parent().unwrap();
+    if !parent.is_dir() {
+        return Err(Error::NoParentDir(parent.to_path_buf()));
+    }
+    let link_ref = link_ref(parent, &target, config.symlink_style)?;
+
+    if dry_run {
+        printer.say(format_args!("[dry-run] symlink {} -> {}", to.display(), link_ref.display()))?;
+        printer.say(format_args!("[dry-run] remove {}", from.display()))?;
+        return Ok(());
+    }
+    unix::fs::symlink(&link_ref, &to).map_err(|e| Error::IoAt(to.clone(), e))?;
+    if let Err(e) = fs::remove_file(&from) {
+        let _ = fs::remove_file(&to);
+        return Err(Error::IoAt(from, e));
+    }
+
+    report_changed(printer, &to)
+}
+
+fn validate_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(Error::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+fn rename<W: WriteColor>(old_name: PathBuf, new_name: String, search_root: Option<PathBuf>, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    validate_name(&new_name)?;
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let from = resolve_repo_file(&old_name, &repo_dir)?;
+    let to = from.with_file_name(&new_name);
+    relocate(&from, &to, search_root, dry_run, &config, printer)
+}
+
+// Move a file to a subdirectory of the repository, creating it if needed
+fn move_to_subdir<W: WriteColor>(file: PathBuf, subdir: PathBuf, search_root: Option<PathBuf>, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let from = resolve_repo_file(&file, &repo_dir)?;
+    let dest_dir = normalize(repo_dir.join(&subdir));
+    if subdir.is_absolute() || !dest_dir.starts_with(&repo_dir) || dest_dir.starts_with(repo_dir.join(META_DIR)) {
+        return Err(Error::NotInRepo(subdir));
+    }
+    if dest_dir.exists() && !dest_dir.is_dir() {
+        return Err(Error::NotDir(dest_dir));
+    }
+    let to = dest_dir.join(from.file_name().unwrap());
+
+    let created = !dest_dir.exists();
+    if created {
+        if dry_run {
+            printer.say(format_args!("[dry-run] mkdir {}", dest_dir.display()))?;
+        }
+        else {
+            fs::create_dir_all(&dest_dir).map_err(|e| Error::IoAt(dest_dir.clone(), e))?;
+        }
+    }
+    let result = relocate(&from, &to, search_root, dry_run, &config, printer);
+    if result.is_err() && created && !dry_run {
+        let _ = fs::remove_dir(&dest_dir);
+    }
+    result
+}
+
+// Move a file within the repository and repoint every symlink to it found
+// under the search root.  Unless all the symlinks can be updated, everything
+// is put back the way it was.
+fn relocate<W: WriteColor>(from: &Path, to: &Path, search_root: Option<PathBuf>, dry_run: bool, config: &Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if to.symlink_metadata().is_ok() {
+        return Err(Error::AlreadyInRepo(to.to_path_buf()));
+    }
+    let links: Vec<_> = find_links(search_root_or_home(search_root)?, &repo_dir)?.into_iter()
+        .filter(|(_, target)| target == from)
+        .map(|(link, _)| link)
+        .collect();
+
+    if dry_run {
+        printer.say(format_args!("[dry-run] move {} -> {}", from.display(), to.display()))?;
+        let sidecar = sidecar_path(from, &repo_dir);
+        if sidecar.exists() {
+            printer.say(format_args!("[dry-run] move {} -> {}", sidecar.display(), sidecar_path(to, &repo_dir).display()))?;
+        }
+        for link in &links {
+            let link_ref = link_ref(link.parent().unwrap(), to, config.symlink_style)?;
+            printer.say(format_args!("[dry-run] symlink {} -> {}", link.display(), link_ref.display()))?;
+        }
+        return Ok(());
+    }
+
+    fs::rename(from, to)?;
+    let mut updated = Vec::new();
+    let mut failed = Vec::new();
+    for link in &links {
+        let result = fs::read_link(link).map_err(Error::from).and_then(|old_ref| {
+            replace_symlink(&link_ref(link.parent().unwrap(), to, config.symlink_style)?, link)?;
+            Ok(old_ref)
+        });
+        match result {
+            Ok(old_ref) => updated.push((link, old_ref)),
+            Err(e) => failed.push((link.clone(), e.to_string())),
+        }
+    }
+    if !failed.is_empty() {
+        for (link, old_ref) in updated {
+            if let Err(e) = replace_symlink(&old_ref, link) {
+                failed.push((link.clone(), format!("failed to restore the original target: {}", e)));
+            }
+        }
+        if let Err(rollback) = fs::rename(to, from) {
+            report_failed(printer, &failed)?;
+            return Err(Error::RollbackFailed { error: Box::new(Error::LinksNotUpdated(failed.len())), path: to.to_path_buf(), rollback });
+        }
+        report_failed(printer, &failed)?;
+        return Err(Error::LinksNotUpdated(failed.len()));
+    }
+    move_sidecar(from, to, &repo_dir)?;
+    journal(&repo_dir, "rename", from, to, printer)?;
+    for link in &links {
+        printer.say(format_args!("updated {}", link.display()))?;
+    }
+
+    report_changed(printer, to)
+}
+
+// Point an existing symlink at a new target by renaming a fresh symlink over
+// it, so that the link never goes missing
+fn replace_symlink(link_ref: &Path, link: &Path) -> Result<(), Error> {
+    let tmp = link.with_file_name(format!(".{}.paperman-tmp", link.file_name().unwrap().to_string_lossy()));
+    unix::fs::symlink(link_ref, &tmp)?;
+    if let Err(e) = fs::rename(&tmp, link) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn restore<W: WriteColor>(name: PathBuf, dest: PathBuf, force: bool, parents: bool, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let target = to_absolute(config.repo_dir.join(&name))?;
+    if file_type(&target).ok() != Some(FileType::File) {
+        return Err(Error::NotInRepo(name));
+    }
+
+    let dest = to_absolute(dest)?;
+    let parent = dest.parent().unwrap();
+    if !parent.is_dir() {
+        if !parents {
+            return Err(Error::NoParentDir(parent.to_path_buf()));
+        }
+        if dry_run {
+            printer.say(format_args!("[dry-run] mkdir {}", parent.display()))?;
+        }
+        else {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    match file_type(&dest) {
+        Ok(FileType::Dir) => return Err(Error::IsDir(dest)),
+        Ok(_) if !force => return Err(Error::Exists(dest)),
+        Ok(_) => {
+            if dry_run {
+                printer.say(format_args!("[dry-run] remove {}", dest.display()))?;
+            }
+            else {
+                fs::remove_file(&dest)?;
+            }
+        },
+        Err(_) => (),
+    }
+
+    let link_ref = link_ref(parent, &target, config.symlink_style)?;
+    if dry_run {
+        printer.say(format_args!("[dry-run] symlink {} -> {}", dest.display(), link_ref.display()))?;
+    }
+    else {
+        unix::fs::symlink(link_ref, &dest)?;
+        report_changed(printer, &dest)?;
+    }
+
+    Ok(())
+}
+
+fn export<W: WriteColor>(dest_dir: PathBuf, names: Vec<PathBuf>, all: bool, force: bool, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+
+    let mut failed = Vec::new();
+    let files = if all {
+        repo_files(&repo_dir)?
+    }
+    else {
+        let mut files = Vec::new();
+        for name in names {
+            match resolve_repo_file(&name, &repo_dir) {
+                Ok(file) => files.push(file),
+                Err(e) => failed.push((name, e.to_string())),
+            }
+        }
+        files
+    };
+
+    if !dest_dir.is_dir() {
+        if dry_run {
+            printer.say(format_args!("[dry-run] mkdir {}", dest_dir.display()))?;
+        }
+        else {
+            fs::create_dir_all(&dest_dir).map_err(|e| Error::IoAt(dest_dir.clone(), e))?;
+        }
+    }
+
+    let mut copied = Vec::new();
+    let mut bytes = 0;
+    for file in files {
+        let dest = dest_dir.join(file.file_name().unwrap());
+        if dest.symlink_metadata().is_ok() && !force {
+            failed.push((file, format!("{} already exists", dest.display())));
+            continue;
+        }
+        if dry_run {
+            printer.say(format_args!("[dry-run] copy {} -> {}", file.display(), dest.display()))?;
+            continue;
+        }
+        match copy_with_mtime(&file, &dest) {
+            Ok(n) => {
+                copied.push(dest);
+                bytes += n;
+            },
+            Err(e) => failed.push((file, e.to_string())),
+        }
+    }
+
+    report_changes(printer, &copied, &failed)?;
+    if !dry_run {
+        printer.say(format_args!("Copied {} file(s), {} byte(s)", copied.len(), bytes))?;
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(Error::NotExported(failed.len()))
+    }
+}
+
+// Copy a file, replacing whatever is at `to`, and carry over its access and
+// modification times
+fn copy_with_mtime(from: &Path, to: &Path) -> io::Result<u64> {
+    let metadata = fs::metadata(from)?;
+    if file_type(to).ok() == Some(FileType::Symlink) {
+        // Do not write through a symlink at the destination
+        fs::remove_file(to)?;
+    }
+    let n = fs::copy(from, to)?;
+    copy_times(&metadata, to)?;
+    Ok(n)
+}
+
+// Apply the access and modification times in `metadata` to `to`.  Setting
+// them only takes owning the file, so it is opened read-only in case it has
+// just been given read-only permissions.
+fn copy_times(metadata: &fs::Metadata, to: &Path) -> io::Result<()> {
+    let times = fs::FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(metadata.modified()?);
+    File::open(to)?.set_times(times)
+}
+
+fn report_failed<W: WriteColor, S: AsRef<str>>(printer: &mut Printer<W>, failed: &[(PathBuf, S)]) -> io::Result<()> {
+    if printer.verbosity != Verbosity::Quiet && !failed.is_empty() {
+        printer.err_line("The following paths are ignored:")?;
+        for (fp, reason) in failed {
+            printer.err_line(format_args!("{}\t({})", fp.display(), reason.as_ref()))?;
+        }
+    }
+    Ok(())
+}
+
+// What a command that changes things has done, for `--json`
+#[derive(Serialize, Debug)]
+struct ChangeReport<'a> {
+    changed: Vec<&'a Path>,
+    failed: Vec<FileReason<'a>>,
+}
+
+// Report the paths a command has changed as JSON, or only the failures as
+// `report_failed` does otherwise
+fn report_changes<W: WriteColor, P: AsRef<Path>, S: AsRef<str>>(printer: &mut Printer<W>, changed: &[P], failed: &[(PathBuf, S)]) -> Result<(), Error> {
+    match printer.output {
+        OutputMode::Human => report_failed(printer, failed)?,
+        OutputMode::Json => {
+            let report = ChangeReport {
+                changed: changed.iter().map(AsRef::as_ref).collect(),
+                failed: failed.iter().map(|(path, reason)| FileReason { path, reason: reason.as_ref() }).collect(),
+            };
+            printer.line(serde_json::to_string_pretty(&report)?)?;
+        },
+    }
+    Ok(())
+}
+
+// Same for a command that changes a single path and fails as a whole
+fn report_changed<W: WriteColor, P: AsRef<Path>>(printer: &mut Printer<W>, changed: P) -> Result<(), Error> {
+    report_changes::<_, _, &str>(printer, &[changed], &[])
+}
+
+// One per symlink to a file, as in the human-readable list, or one without
+// `path` for a file that has no symlink.  A broken symlink has no size.
+#[derive(Serialize, Debug)]
+struct ListEntry {
+    // The file relative to the repository, whether a symlink to it exists
+    // and its size if it is still there
+    repo_path: PathBuf,
+    linked: bool,
+    size: Option<u64>,
+    name: String,
+    // The symlink and the file in the repository it points to
+    path: Option<PathBuf>,
+    symlink_target: PathBuf,
+    broken: bool,
+    original_path: Option<PathBuf>,
+    added: Option<String>,
+    tags: BTreeSet<String>,
+}
+
+// The symlinks `list` shows: those under `search_root`, or else those at the
+// locations recorded in the sidecars, including the ones whose file has gone
+// from the repository
+fn listed_links(files: &[PathBuf], search_root: Option<PathBuf>, repo_dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+    if let Some(root) = search_root {
+        return find_links(root, repo_dir);
+    }
+    let mut links = Vec::new();
+    for fp in files {
+        if let Some(link) = recorded_link(fp, repo_dir)? {
+            links.push((link, fp.clone()));
+        }
+    }
+    let sidecar_dir = repo_dir.join(META_DIR).join(SIDECAR_DIR);
+    for sidecar in orphaned_sidecars(repo_dir).map_err(|e| Error::IoAt(sidecar_dir.clone(), e))? {
+        let fp = repo_dir.join(sidecar.strip_prefix(&sidecar_dir).unwrap().with_extension(""));
+        let link = match read_metadata(&fp, repo_dir)?.and_then(|meta| meta.original_path) {
+            Some(link) => link,
+            None => continue,
+        };
+        if file_type(&link).ok() == Some(FileType::Symlink) && target_in_repo(&link, repo_dir)?.as_deref() == Some(&fp) {
+            links.push((link, fp));
+        }
+    }
+    Ok(links)
+}
+
+// The entries of `list --json` for the files and links `matches` accepts
+fn list_entries<F: Fn(&Path) -> bool>(files: &[PathBuf], links: &[(PathBuf, PathBuf)], repo_dir: &Path, matches: F) -> Result<Vec<ListEntry>, Error> {
+    let entry = |path: Option<PathBuf>, target: &Path, size: Option<u64>| -> Result<ListEntry, Error> {
+        let meta = read_metadata(target, repo_dir)?;
+        Ok(ListEntry {
+            repo_path: repo_relative(target, repo_dir),
+            linked: path.is_some() && size.is_some(),
+            size,
+            name: target.file_name().unwrap().to_string_lossy().into_owned(),
+            path,
+            symlink_target: target.to_path_buf(),
+            broken: size.is_none(),
+            original_path: meta.as_ref().and_then(|meta| meta.original_path.clone()),
+            added: meta.as_ref().and_then(|meta| meta.added.clone()),
+            tags: meta.map(|meta| meta.tags).unwrap_or_default(),
+        })
+    };
+
+    let mut entries = Vec::new();
+    for fp in files.iter().filter(|fp| matches(fp)) {
+        let size = fs::metadata(fp)?.len();
+        let mut referrers: Vec<_> = links.iter().filter(|(_, target)| target == fp).map(|(link, _)| Some(link.clone())).collect();
+        if referrers.is_empty() {
+            referrers.push(None);
+        }
+        for path in referrers {
+            entries.push(entry(path, fp, Some(size))?);
+        }
+    }
+    for (link, target) in links.iter().filter(|(_, target)| matches(target) && !files.contains(target)) {
+        entries.push(entry(Some(link.clone()), target, None)?);
+    }
+    Ok(entries)
+}
+
+// How `list` and `search` select files by name
+enum NameFilter {
+    Glob(String),
+    Substring(String, bool),
+    Regex(regex::Regex),
+}
+
+impl NameFilter {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            NameFilter::Glob(pattern) => glob_match(pattern, name),
+            NameFilter::Substring(pattern, false) => name.contains(pattern.as_str()),
+            NameFilter::Substring(pattern, true) => name.to_lowercase().contains(&pattern.to_lowercase()),
+            NameFilter::Regex(re) => re.is_match(name),
+        }
+    }
+}
+
+fn list<W: WriteColor>(long: bool, full_path: bool, filter: Option<NameFilter>, tag: Option<String>, search_root: Option<PathBuf>, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let matches = |fp: &Path| {
+        let name_matches = match &filter {
+            Some(filter) => filter.matches(&fp.file_name().unwrap().to_string_lossy()),
+            None => true,
+        };
+        name_matches && match &tag {
+            Some(tag) => has_tag(fp, tag, &repo_dir),
+            None => true,
+        }
+    };
+    let display_name = |fp: &Path| if full_path {
+        fp.display().to_string()
+    }
+    else {
+        fp.strip_prefix(&repo_dir).unwrap().display().to_string()
+    };
+    let files = repo_files(&repo_dir)?;
+    // Walking the whole home directory is slow, so without a search root only
+    // the location each file was added from is looked at
+    let links = listed_links(&files, search_root, &repo_dir)?;
+
+    if printer.output == OutputMode::Json {
+        printer.line(serde_json::to_string_pretty(&list_entries(&files, &links, &repo_dir, matches)?)?)?;
+        return Ok(());
+    }
+
+    for fp in files.iter().filter(|fp| matches(fp)) {
+        let mut line = String::new();
+        if long {
+            let metadata = fs::metadata(fp)?;
+            let mtime = metadata.modified()?;
+            line.push_str(&format!("{:>10}\t{}\t", metadata.len(), format_time(mtime)));
+        }
+        line.push_str(&display_name(fp));
+        let referrers: Vec<_> = links.iter().filter(|(_, target)| target == fp).collect();
+        if referrers.is_empty() {
+            // Say where the file came from so that it can be restored
+            match read_metadata(fp, &repo_dir)?.and_then(|meta| meta.original_path) {
+                Some(original_path) if long => printer.line(format_args!("{}\t(no link; added from {})", line, original_path.display()))?,
+                _ => printer.line(format_args!("{}\t(no link)", line))?,
+            }
+        }
+        for (link, _) in referrers {
+            printer.line(format_args!("{}\t{}", line, link.display()))?;
+        }
+    }
+
+    // Links whose target has disappeared from the repository
+    for (link, target) in links.iter().filter(|(_, target)| matches(target)) {
+        if !files.contains(target) {
+            if long {
+                printer.line(format_args!("{:>10}\t{:19}\t{}\t{}\t(broken)", "-", "-", display_name(target), link.display()))?;
+            }
+            else {
+                printer.line(format_args!("{}\t{}\t(broken)", display_name(target), link.display()))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Default, Debug)]
+struct Stats {
+    files: usize,
+    total_size: u64,
+    by_extension: BTreeMap<String, ExtensionStats>,
+    largest: Vec<FileStats>,
+    oldest: Option<String>,
+    newest: Option<String>,
+}
+
+#[derive(Serialize, Default, Debug)]
+struct ExtensionStats {
+    files: usize,
+    size: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct FileStats {
+    repo_path: PathBuf,
+    size: u64,
+}
+
+// Summarize the repository from file system metadata alone, so that this
+// stays fast however large the files are
+fn collect_stats(repo_dir: &Path) -> Result<Stats, Error> {
+    let mut stats = Stats::default();
+    if !repo_dir.is_dir() {
+        return Ok(stats);
+    }
+    let mut sizes = Vec::new();
+    let mut mtimes = Vec::new();
+    for fp in repo_files(repo_dir)? {
+        let metadata = fs::metadata(&fp).map_err(|e| Error::IoAt(fp.clone(), e))?;
+        let ext = fp.extension().map(|ext| ext.to_string_lossy().to_lowercase()).unwrap_or_default();
+        let entry = stats.by_extension.entry(ext).or_default();
+        entry.files += 1;
+        entry.size += metadata.len();
+        stats.files += 1;
+        stats.total_size += metadata.len();
+        mtimes.push(metadata.modified()?);
+        sizes.push((metadata.len(), fp));
+    }
+    sizes.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    stats.largest = sizes.into_iter()
+        .take(10)
+        .map(|(size, fp)| FileStats { repo_path: fp.strip_prefix(repo_dir).unwrap().to_path_buf(), size })
+        .collect();
+    stats.oldest = mtimes.iter().min().map(|&t| format_rfc3339(t));
+    stats.newest = mtimes.iter().max().map(|&t| format_rfc3339(t));
+    Ok(stats)
+}
+
+fn stats<W: WriteColor>(config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let stats = collect_stats(&to_absolute(&config.repo_dir)?)?;
+    if printer.output == OutputMode::Json {
+        printer.line(serde_json::to_string_pretty(&stats)?)?;
+        return Ok(());
+    }
+
+    printer.line(format_args!("Files:      {}", stats.files))?;
+    printer.line(format_args!("Total size: {}", stats.total_size))?;
+    if let (Some(oldest), Some(newest)) = (&stats.oldest, &stats.newest) {
+        printer.line(format_args!("Oldest:     {}", oldest))?;
+        printer.line(format_args!("Newest:     {}", newest))?;
+    }
+    if !stats.by_extension.is_empty() {
+        printer.line("")?;
+        let mut exts: Vec<_> = stats.by_extension.iter().collect();
+        exts.sort_by(|a, b| b.1.size.cmp(&a.1.size).then(a.0.cmp(b.0)));
+        let rows: Vec<_> = exts.into_iter()
+            .map(|(ext, e)| vec![
+                if ext.is_empty() { "(none)".to_string() } else { ext.clone() },
+                e.files.to_string(),
+                e.size.to_string(),
+            ])
+            .collect();
+        printer.table(&["EXTENSION", "FILES", "SIZE"], &rows, &[])?;
+    }
+    if !stats.largest.is_empty() {
+        printer.line("")?;
+        let rows: Vec<_> = stats.largest.iter()
+            .map(|f| vec![f.size.to_string(), f.repo_path.display().to_string()])
+            .collect();
+        printer.table(&["SIZE", "LARGEST FILES"], &rows, &[])?;
+    }
+
+    Ok(())
+}
+
+fn find<W: WriteColor>(patterns: Vec<String>, and: bool, name_only: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let found: Vec<_> = repo_files(&repo_dir)?.into_iter()
+        .filter(|fp| name_matches(&fp.file_name().unwrap().to_string_lossy(), &patterns, and))
+        .collect();
+    if printer.output == OutputMode::Json {
+        printer.line(serde_json::to_string_pretty(&found)?)?;
+        return Ok(());
+    }
+    for fp in &found {
+        if name_only {
+            printer.line(fp.file_name().unwrap().to_string_lossy())?;
+        }
+        else {
+            printer.line(fp.display())?;
+        }
+    }
+
+    Ok(())
+}
+
+// Case-insensitive substring match against any, or with `and` all, of the
+// patterns
+fn name_matches(name: &str, patterns: &[String], and: bool) -> bool {
+    let name = name.to_lowercase();
+    let contains = |p: &String| name.contains(&p.to_lowercase());
+    if and {
+        patterns.iter().all(contains)
+    }
+    else {
+        patterns.iter().any(contains)
+    }
+}
+
+#[derive(Eq, PartialEq, Debug)]
+enum LinkState {
+    Ok,
+    Broken,
+    Foreign,
+}
+
+fn link_state<P: AsRef<Path>>(target: P) -> LinkState {
+    match fs::metadata(target) {
+        Ok(ref metadata) if metadata.is_file() => LinkState::Ok,
+        Ok(_) => LinkState::Foreign,
+        Err(_) => LinkState::Broken,
+    }
+}
+
+fn status<W: WriteColor>(dirs: Vec<PathBuf>, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    // Scanning only the current directory would report every file linked from
+    // elsewhere as orphaned, so look where the other commands do
+    let dirs = if dirs.is_empty() {
+        vec![search_root_or_home(None)?]
+    }
+    else {
+        dirs
+    };
+    let mut links = Vec::new();
+    for dir in &dirs {
+        links.extend(find_links(dir, &repo_dir)?);
+    }
+    links.sort();
+    links.dedup();
+
+    // Repository files are checked for a symlink in the scanned locations
+    let files = if repo_dir.is_dir() {
+        repo_files(&repo_dir)?
+    }
+    else {
+        Vec::new()
+    };
+
+    let mut hard_links = Vec::new();
+    for dir in &dirs {
+        hard_links.extend(find_hard_links(dir, &repo_dir)?);
+    }
+    hard_links.sort();
+    hard_links.dedup();
+
+    let entries = classify(&links, &hard_links, &files, &repo_dir);
+    let ok = entries.iter().filter(|(health, _, _)| matches!(health, Health::Healthy | Health::HardLinked | Health::CopyMode)).count();
+    let problems = entries.len() - ok;
+    if printer.output == OutputMode::Json {
+        let entries: Vec<_> = entries.iter()
+            .map(|&(health, repo_path, link)| StatusEntry { status: health.label(), repo_path, link })
+            .collect();
+        printer.line(serde_json::to_string_pretty(&entries)?)?;
+        return if problems == 0 { Ok(()) } else { Err(Error::Problems(problems)) };
+    }
+    let rows: Vec<_> = entries.iter()
+        .map(|(health, file, link)| vec![
+            health.label().to_string(),
+            file.display().to_string(),
+            link.map(|link| link.display().to_string()).unwrap_or_else(|| "-".to_string()),
+        ])
+        .collect();
+    let colors: Vec<_> = entries.iter().map(|(health, _, _)| Some(health.color())).collect();
+    printer.table(&["STATUS", "REPO FILE", "SYMLINK"], &rows, &colors)?;
+    printer.line("")?;
+    printer.line(format_args!("{} ok, {} problem(s)", ok, problems))?;
+
+    if problems == 0 {
+        Ok(())
+    }
+    else {
+        Err(Error::Problems(problems))
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct StatusEntry<'a> {
+    status: &'static str,
+    repo_path: &'a Path,
+    link: Option<&'a Path>,
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+enum Health {
+    Healthy,
+    Broken,
+    Foreign,
+    Orphaned,
+    HardLinked,
+    CopyMode,
+}
+
+impl Health {
+    fn label(self) -> &'static str {
+        match self {
+            Health::Healthy => "healthy",
+            Health::Broken => "broken",
+            Health::Foreign => "foreign",
+            Health::Orphaned => "orphaned",
+            Health::HardLinked => "hard-link",
+            Health::CopyMode => "copy-mode",
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Health::Healthy | Health::HardLinked | Health::CopyMode => Color::Green,
+            Health::Orphaned => Color::Yellow,
+            Health::Broken | Health::Foreign => Color::Red,
+        }
+    }
+}
+
+// Classify every symlink by the state of its target and every repository
+// file with neither a symlink nor a hard link as orphaned, unless it was
+// added in copy mode, ordered by repository path
+fn classify<'a>(links: &'a [(PathBuf, PathBuf)], hard_links: &'a [(PathBuf, PathBuf)], files: &'a [PathBuf], repo_dir: &Path) -> Vec<(Health, &'a Path, Option<&'a Path>)> {
+    let mut entries: Vec<_> = links.iter()
+        .map(|(link, target)| {
+            let health = match link_state(target) {
+                LinkState::Ok => Health::Healthy,
+                LinkState::Broken => Health::Broken,
+                LinkState::Foreign => Health::Foreign,
+            };
+            (health, target.as_path(), Some(link.as_path()))
+        })
+        .collect();
+    entries.extend(hard_links.iter().map(|(link, target)| (Health::HardLinked, target.as_path(), Some(link.as_path()))));
+    entries.extend(files.iter()
+        .filter(|fp| !links.iter().chain(hard_links).any(|(_, target)| target == *fp))
+        .map(|fp| (if is_copy_mode(fp, repo_dir) { Health::CopyMode } else { Health::Orphaned }, fp.as_path(), None)));
+    entries.sort_by(|a, b| a.1.cmp(b.1).then(a.2.cmp(&b.2)));
+    entries
+}
+
+// Whether to color the output: `--no-color` and a non-empty $NO_COLOR turn
+// it off, and it needs a terminal that is not dumb
+fn wants_color(no_color: bool, no_color_var: Option<std::ffi::OsString>, term: Option<std::ffi::OsString>, tty: bool) -> bool {
+    !no_color
+        && no_color_var.unwrap_or_default().is_empty()
+        && term.is_some_and(|term| !term.is_empty() && term != "dumb")
+        && tty
+}
+
+// Standard output and error, or other writers in tests, along with how the
+// command line asks for them to be used.  Only labels on `out` are colored.
+struct Printer<W> {
+    out: W,
+    err: W,
+    output: OutputMode,
+    verbosity: Verbosity,
+    // Whether to show progress on `err`
+    progress: bool,
+}
+
+impl Printer<StandardStream> {
+    fn new(color: bool, output: OutputMode, verbosity: Verbosity) -> Printer<StandardStream> {
+        let choice = if color { ColorChoice::Always } else { ColorChoice::Never };
+        Printer {
+            out: StandardStream::stdout(choice),
+            err: StandardStream::stderr(ColorChoice::Never),
+            output,
+            verbosity,
+            progress: verbosity != Verbosity::Quiet && unsafe { libc::isatty(libc::STDERR_FILENO) } == 1,
+        }
+    }
+}
+
+impl<W: WriteColor> Printer<W> {
+    // Print `text` without a line break, in `color` if there is one
+    fn colored(&mut self, text: &str, color: Option<Color>) -> io::Result<()> {
+        match color {
+            Some(color) => {
+                self.out.set_color(ColorSpec::new().set_fg(Some(color)).set_bold(true))?;
+                write!(self.out, "{}", text)?;
+                self.out.reset()
+            },
+            None => write!(self.out, "{}", text),
+        }
+    }
+
+    fn line<T: std::fmt::Display>(&mut self, text: T) -> io::Result<()> {
+        writeln!(self.out, "{}", text)
+    }
+
+    // Print a line on stderr, for what is not part of the results
+    fn err_line<T: std::fmt::Display>(&mut self, text: T) -> io::Result<()> {
+        writeln!(self.err, "{}", text)
+    }
+
+    // Replace the progress shown on stderr with `text`, which is left empty
+    // to clear it
+    fn show_progress<T: std::fmt::Display>(&mut self, text: T) -> io::Result<()> {
+        write!(self.err, "\r\x1b[K{}", text)?;
+        self.err.flush()
+    }
+
+    // Report an operation that has been performed, with `--verbose`
+    fn note<T: std::fmt::Display>(&mut self, message: T) -> io::Result<()> {
+        if self.verbosity == Verbosity::Verbose {
+            self.say(message)?;
+        }
+        Ok(())
+    }
+
+    // Print a line about what is being done, which goes to stderr with
+    // `--json` so as to keep stdout for the results
+    fn say<T: std::fmt::Display>(&mut self, message: T) -> io::Result<()> {
+        match self.output {
+            OutputMode::Human => writeln!(self.out, "{}", message),
+            OutputMode::Json => writeln!(self.err, "{}", message),
+        }
+    }
+
+    // Warn about something that does not make the command fail, unless
+    // `--quiet` is given
+    fn warn<T: std::fmt::Display>(&mut self, message: T) -> io::Result<()> {
+        if self.verbosity != Verbosity::Quiet {
+            writeln!(self.err, "Warning: {}", message)?;
+        }
+        Ok(())
+    }
+
+    // Print rows as columns padded to the widest cell, leaving the last
+    // column unpadded, and the first cell of each row in the color given for it
+    fn table<S: AsRef<str>>(&mut self, header: &[&str], rows: &[Vec<S>], colors: &[Option<Color>]) -> io::Result<()> {
+        let mut widths: Vec<_> = header.iter().map(|h| h.chars().count()).collect();
+        for row in rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.as_ref().chars().count());
+            }
+        }
+        let mut print_row = |cells: Vec<&str>, color: Option<Color>| {
+            let last = cells.len() - 1;
+            // The padding is left uncolored
+            self.colored(cells[0], color)?;
+            let mut line = String::new();
+            for (i, cell) in cells.iter().enumerate() {
+                if i > 0 {
+                    line.push_str("  ");
+                    line.push_str(cell);
+                }
+                if i != last {
+                    line.push_str(&" ".repeat(widths[i] - cell.chars().count()));
+                }
+            }
+            self.line(&line)
+        };
+        print_row(header.to_vec(), None)?;
+        for (i, row) in rows.iter().enumerate() {
+            print_row(row.iter().map(AsRef::as_ref).collect(), colors.get(i).cloned().flatten())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct DedupeReport<'a> {
+    duplicates: Vec<DuplicateGroup<'a>>,
+    similar: &'a [(&'a PathBuf, &'a PathBuf)],
+    removed: &'a [PathBuf],
+}
+
+#[derive(Serialize, Debug)]
+struct DuplicateGroup<'a> {
+    sha256: &'a str,
+    // The file `--resolve keep-oldest` keeps
+    keep: &'a Path,
+    files: &'a [PathBuf],
+}
+
+// The file with the oldest modification time
+fn oldest(files: &[PathBuf]) -> Result<&PathBuf, Error> {
+    let mut mtimes = Vec::new();
+    for fp in files {
+        mtimes.push((fs::metadata(fp).and_then(|m| m.modified()).map_err(|e| Error::IoAt(fp.clone(), e))?, fp));
+    }
+    Ok(mtimes.into_iter().min().unwrap().1)
+}
+
+// Pairs of files whose names differ by at most two edits, ignoring case, as
+// the same paper is often downloaded under slightly different names
+fn similar_names(files: &[PathBuf]) -> Vec<(&PathBuf, &PathBuf)> {
+    let names: Vec<_> = files.iter().map(|fp| fp.file_name().unwrap().to_string_lossy().to_lowercase()).collect();
+    let lens: Vec<_> = names.iter().map(|name| name.chars().count()).collect();
+    // Names whose lengths differ by more than two are further apart than
+    // that, so each name is only compared with those following it in order
+    // of length up to two characters longer
+    let mut by_len: Vec<_> = (0..files.len()).collect();
+    by_len.sort_by_key(|&i| lens[i]);
+    let mut pairs = Vec::new();
+    for (k, &i) in by_len.iter().enumerate() {
+        for &j in by_len[k + 1..].iter().take_while(|&&j| lens[j] - lens[i] <= 2) {
+            if levenshtein(&names[i], &names[j]) <= 2 {
+                pairs.push((i.min(j), i.max(j)));
+            }
+        }
+    }
+    pairs.sort();
+    pairs.into_iter().map(|(i, j)| (&files[i], &files[j])).collect()
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = prev + if ca == cb { 0 } else { 1 };
+            prev = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(prev + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// Group the repository files by the SHA-256 of their contents and list the
+// groups with more than one member, followed by files with similar names.
+// With a strategy given, keep one file of each group, point the symlinks to
+// the others at it and remove them.
+fn dedupe<W: WriteColor>(resolve: Option<Resolve>, search_root: Option<PathBuf>, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let files = repo_files(&repo_dir)?;
+    let mut groups: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for (i, fp) in files.iter().enumerate() {
+        // Digests in sidecars save reading every file just to list them, but
+        // nothing is removed on the strength of a digest that may be stale
+        let recorded = match resolve {
+            None => read_metadata(fp, &repo_dir)?.map(|meta| meta.sha256).filter(|sha256| !sha256.is_empty()),
+            Some(_) => None,
+        };
+        let digest = match recorded {
+            Some(digest) => digest,
+            None => {
+                if printer.progress {
+                    printer.show_progress(format_args!("Hashing {}/{} {}", i + 1, files.len(), fp.strip_prefix(&repo_dir).unwrap().display()))?;
+                }
+                sha256_file(fp).map_err(|e| Error::IoAt(fp.clone(), e))?
+            },
+        };
+        groups.entry(digest).or_default().push(fp.clone());
+    }
+    if printer.progress {
+        printer.show_progress("")?;
+    }
+    groups.retain(|_, files| files.len() > 1);
+
+    let similar = similar_names(&files);
+    // With `--json` everything goes into a single report at the end
+    let print_report = |printer: &mut Printer<W>, removed: &[PathBuf]| -> Result<(), Error> {
+        let mut duplicates = Vec::new();
+        for (sha256, files) in &groups {
+            duplicates.push(DuplicateGroup { sha256, keep: oldest(files)?, files });
+        }
+        let report = DedupeReport { duplicates, similar: &similar, removed };
+        printer.line(serde_json::to_string_pretty(&report)?)?;
+        Ok(())
+    };
+    if printer.output == OutputMode::Human {
+        for (digest, files) in &groups {
+            printer.line(digest)?;
+            let keep = oldest(files)?;
+            for fp in files {
+                let size = fs::metadata(fp).map_err(|e| Error::IoAt(fp.clone(), e))?.len();
+                let suggestion = if fp == keep { "  (keep; oldest)" } else { "" };
+                printer.line(format_args!("  {:>12}  {}{}", size, fp.strip_prefix(&repo_dir).unwrap().display(), suggestion))?;
+            }
+        }
+        if !similar.is_empty() {
+            printer.line("Similar names:")?;
+            for (a, b) in &similar {
+                printer.line(format_args!("  {}  {}", a.strip_prefix(&repo_dir).unwrap().display(), b.strip_prefix(&repo_dir).unwrap().display()))?;
+            }
+        }
+    }
+    let resolve = match resolve {
+        Some(resolve) if !groups.is_empty() => resolve,
+        _ if printer.output == OutputMode::Json => return print_report(printer, &[]),
+        _ => return Ok(()),
+    };
+
+    let links = find_links(search_root_or_home(search_root)?, &repo_dir)?;
+    let mut removed = Vec::new();
+    let mut failed = Vec::new();
+    for files in groups.values() {
+        let keep = match resolve {
+            Resolve::KeepOldest => oldest(files)?,
+        };
+        for fp in files.iter().filter(|fp| *fp != keep) {
+            let referring: Vec<_> = links.iter()
+                .filter(|(_, target)| target == fp)
+                .map(|(link, _)| link)
+                .collect();
+            if dry_run {
+                for link in &referring {
+                    let link_ref = link_ref(link.parent().unwrap(), keep, config.symlink_style)?;
+                    printer.say(format_args!("[dry-run] symlink {} -> {}", link.display(), link_ref.display()))?;
+                }
+                printer.say(format_args!("[dry-run] remove {}", fp.display()))?;
+                continue;
+            }
+            let mut all_updated = true;
+            for link in referring {
+                match link_ref(link.parent().unwrap(), keep, config.symlink_style).and_then(|link_ref| replace_symlink(&link_ref, link)) {
+                    Ok(()) => printer.say(format_args!("updated {}", link.display()))?,
+                    Err(e) => {
+                        failed.push((link.clone(), e.to_string()));
+                        all_updated = false;
+                    },
+                }
+            }
+            // A duplicate still referred to by a symlink has to stay
+            if !all_updated {
+                continue;
+            }
+            // Tags of the duplicate carry over to the file that is kept
+            if let Some(meta) = read_metadata(fp, &repo_dir)? {
+                if !meta.tags.is_empty() {
+                    let mut kept = read_or_new_metadata(keep, &repo_dir)?;
+                    kept.tags.extend(meta.tags);
+                    write_metadata(keep, &repo_dir, &kept)?;
+                }
+            }
+            fs::remove_file(fp).map_err(|e| Error::IoAt(fp.clone(), e))?;
+            printer.say(format_args!("removed {}", fp.display()))?;
+            removed.push(fp.clone());
+        }
+    }
+    forget_metadata(&removed, &repo_dir)?;
+    if printer.output == OutputMode::Json {
+        print_report(printer, &removed)?;
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    }
+    else {
+        report_failed(printer, &failed)?;
+        Err(Error::LinksNotUpdated(failed.len()))
+    }
+}
+
+// Delete the repository files no symlink refers to, asking about each unless
+// `yes` is given, or move them to `move_to`.  Dry runs and `--json` without
+// `yes` only list them, as there is no one to ask.
+fn gc<W: WriteColor>(search_roots: Vec<PathBuf>, move_to: Option<PathBuf>, yes: bool, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    gc_with(search_roots, move_to, yes, dry_run, config, printer, confirm)
+}
+
+fn gc_with<W: WriteColor, F>(search_roots: Vec<PathBuf>, move_to: Option<PathBuf>, yes: bool, dry_run: bool, config: Config, printer: &mut Printer<W>, confirm: F) -> Result<(), Error>
+    where F: Fn(&str) -> io::Result<bool>
+{
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let search_roots = if search_roots.is_empty() {
+        vec![search_root_or_home(None)?]
+    }
+    else {
+        search_roots
+    };
+    let mut links = Vec::new();
+    for root in &search_roots {
+        links.extend(find_links(root, &repo_dir)?);
+    }
+    // Files added in copy mode never had a symlink to begin with, and hard
+    // linked ones are still reachable under another name.  The link recorded
+    // in the sidecar counts even if it is outside the search roots.
+    let mut orphans = Vec::new();
+    for fp in repo_files(&repo_dir)? {
+        if !links.iter().any(|(_, target)| *target == fp) && !is_copy_mode(&fp, &repo_dir) && !has_other_names(&fp)
+            && recorded_link(&fp, &repo_dir)?.is_none() {
+            orphans.push(fp);
+        }
+    }
+
+    if let Some(dir) = move_to {
+        // Quarantine the files together with their sidecars
+        if !dry_run && !orphans.is_empty() {
+            fs::create_dir_all(&dir).map_err(|e| Error::IoAt(dir.clone(), e))?;
+        }
+        for fp in &orphans {
+            let mut to = dir.join(fp.file_name().unwrap());
+            if to.symlink_metadata().is_ok() {
+                to = (1..).map(|i| numbered_path(&to, i)).find(|p| p.symlink_metadata().is_err()).unwrap();
+            }
+            if dry_run {
+                printer.say(format_args!("[dry-run] move {} -> {}", fp.display(), to.display()))?;
+                continue;
+            }
+            move_file(fp, &to).map_err(|e| Error::IoAt(fp.clone(), e))?;
+            let sidecar = sidecar_path(fp, &repo_dir);
+            if sidecar.exists() {
+                let moved_sidecar = sidecar_path(&to, &dir);
+                fs::create_dir_all(moved_sidecar.parent().unwrap())
+                    .and_then(|()| move_file(&sidecar, moved_sidecar))
+                    .map_err(|e| Error::IoAt(sidecar, e))?;
+            }
+            printer.say(format_args!("moved {} -> {}", fp.display(), to.display()))?;
+        }
+    }
+    else if printer.output == OutputMode::Json && (dry_run || !yes) {
+        printer.line(serde_json::to_string_pretty(&orphans)?)?;
+        return Ok(());
+    }
+    else if dry_run {
+        for fp in &orphans {
+            printer.say(format_args!("[dry-run] remove {}", fp.display()))?;
+        }
+        return Ok(());
+    }
+    else {
+        let mut removed = Vec::new();
+        for fp in orphans {
+            if !yes && !confirm(&format!("Delete {}?", fp.display()))? {
+                continue;
+            }
+            fs::remove_file(&fp).map_err(|e| Error::IoAt(fp.clone(), e))?;
+            printer.say(format_args!("removed {}", fp.display()))?;
+            removed.push(fp);
+        }
+        orphans = removed;
+    }
+    if !dry_run {
+        forget_metadata(&orphans, &repo_dir)?;
+        report_changes::<_, _, &str>(printer, &orphans, &[])?;
+    }
+
+    Ok(())
+}
+
+// Ask a yes/no question on the terminal, defaulting to no
+fn confirm(prompt: &str) -> io::Result<bool> {
+    let answer = ask(&format!("{} [y/N]", prompt))?;
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+// Ask a question on the terminal and return the answer with surrounding
+// whitespace removed
+fn ask(prompt: &str) -> io::Result<String> {
+    eprint!("{} ", prompt);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+const METADATA_FORMAT_VERSION: u32 = 1;
+
+// Contents of the sidecar file kept for each file in the repository.
+// Files added before sidecars existed get one without the original path and
+// the time of addition when they are first tagged.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Metadata {
+    format_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original_path: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    added: Option<String>,
+    sha256: String,
+    #[serde(default)]
+    tags: BTreeSet<String>,
+    // Added by copying, so the original stays where it was and no symlink
+    // points at the file
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    copy_mode: bool,
+}
+
+// The path of a repository file relative to the repository, whichever of
+// the two is given relative to the current directory
+fn repo_relative(path: &Path, repo_dir: &Path) -> PathBuf {
+    match path.strip_prefix(repo_dir) {
+        Ok(rel) => rel.to_path_buf(),
+        Err(_) => {
+            let cwd = std::env::current_dir().unwrap_or_default();
+            let path = normalize(cwd.join(path));
+            path.strip_prefix(normalize(cwd.join(repo_dir))).map(Path::to_path_buf).unwrap_or(path)
+        },
+    }
+}
+
+// The sidecars live under the metadata directory in the same layout as the
+// repository, so that they cannot collide with a file in the repository
+fn sidecar_path(path: &Path, repo_dir: &Path) -> PathBuf {
+    let rel = repo_relative(path, repo_dir);
+    let mut sidecar = repo_dir.join(META_DIR).join(SIDECAR_DIR).join(rel).into_os_string();
+    sidecar.push(".toml");
+    PathBuf::from(sidecar)
+}
+
+fn read_metadata(path: &Path, repo_dir: &Path) -> Result<Option<Metadata>, Error> {
+    let sidecar = sidecar_path(path, repo_dir);
+    let buf = match fs::read_to_string(&sidecar) {
+        Ok(buf) => buf,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::IoAt(sidecar, e)),
+    };
+    toml::from_str(&buf).map(Some).map_err(|e| Error::TomlParse(sidecar, e))
+}
+
+// Read the sidecar of a file, or start a new one if it has none
+fn read_or_new_metadata(path: &Path, repo_dir: &Path) -> Result<Metadata, Error> {
+    match read_metadata(path, repo_dir)? {
+        Some(meta) => Ok(meta),
+        None => Ok(Metadata {
+            format_version: METADATA_FORMAT_VERSION,
+            original_path: None,
+            added: None,
+            sha256: sha256_file(path).map_err(|e| Error::IoAt(path.to_path_buf(), e))?,
+            tags: BTreeSet::new(),
+            copy_mode: false,
+        }),
+    }
+}
+
+// Write the sidecar to a temporary file first and rename it into place, so
+// that an interrupted write cannot lose the existing metadata
+fn write_metadata(path: &Path, repo_dir: &Path, meta: &Metadata) -> Result<(), Error> {
+    let sidecar = sidecar_path(path, repo_dir);
+    let sidecar_dir = sidecar.parent().unwrap();
+    fs::create_dir_all(sidecar_dir).map_err(|e| Error::IoAt(sidecar_dir.to_path_buf(), e))?;
+    let tmp = sidecar.with_file_name(format!(".{}.paperman-tmp", sidecar.file_name().unwrap().to_string_lossy()));
+    fs::write(&tmp, toml::to_string(meta)?).map_err(|e| Error::IoAt(tmp.clone(), e))?;
+    fs::rename(&tmp, &sidecar).map_err(|e| Error::IoAt(sidecar, e))?;
+    let rel = repo_relative(path, repo_dir);
+    update_index(repo_dir, |digests| {
+        forget_paths(digests, std::slice::from_ref(&rel));
+        if !meta.sha256.is_empty() {
+            digests.entry(meta.sha256.clone()).or_default().insert(rel.clone());
+        }
+    })
+}
+
+// Write the sidecar of a file that has just been added to the repository
+fn record_metadata(path: &Path, original_path: PathBuf, copy_mode: bool, repo_dir: &Path) -> Result<(), Error> {
+    let meta = Metadata {
+        format_version: METADATA_FORMAT_VERSION,
+        original_path: Some(original_path),
+        added: Some(format_rfc3339(SystemTime::now())),
+        sha256: sha256_file(path).map_err(|e| Error::IoAt(path.to_path_buf(), e))?,
+        tags: BTreeSet::new(),
+        copy_mode,
+    };
+    write_metadata(path, repo_dir, &meta)
+}
+
+fn has_other_names(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    matches!(fs::metadata(path), Ok(m) if m.nlink() > 1)
+}
+
+fn is_copy_mode(path: &Path, repo_dir: &Path) -> bool {
+    matches!(read_metadata(path, repo_dir), Ok(Some(meta)) if meta.copy_mode)
+}
+
+fn has_tag(path: &Path, tag: &str, repo_dir: &Path) -> bool {
+    match read_metadata(path, repo_dir) {
+        Ok(Some(meta)) => meta.tags.contains(tag),
+        _ => false,
+    }
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut io::BufReader::with_capacity(1 << 20, File::open(path)?), &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Map the digests recorded for the files in the repository to one of the
+// files still there with each of them
+fn recorded_digests(repo_dir: &Path) -> Result<BTreeMap<String, PathBuf>, Error> {
+    if !repo_dir.is_dir() {
+        return Ok(BTreeMap::new());
+    }
+    let index = match read_index(repo_dir)? {
+        Some(index) => index,
+        None => DigestIndex { digests: sidecar_digests(repo_dir)? },
+    };
+    Ok(index.digests.into_iter()
+        .filter_map(|(digest, paths)| {
+            let fp = paths.into_iter().map(|rel| repo_dir.join(rel)).find(|fp| fp.is_file())?;
+            Some((digest, fp))
+        })
+        .collect())
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct DigestIndex {
+    // The files with each digest, relative to the repository
+    #[serde(default)]
+    digests: BTreeMap<String, BTreeSet<PathBuf>>,
+}
+
+fn read_index(repo_dir: &Path) -> Result<Option<DigestIndex>, Error> {
+    let path = repo_dir.join(DIGEST_INDEX);
+    let buf = match fs::read_to_string(&path) {
+        Ok(buf) => buf,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::IoAt(path, e)),
+    };
+    toml::from_str(&buf).map(Some).map_err(|e| Error::TomlParse(path, e))
+}
+
+// The digests in the sidecars, to build the index from in a repository that
+// has none yet
+fn sidecar_digests(repo_dir: &Path) -> Result<BTreeMap<String, BTreeSet<PathBuf>>, Error> {
+    let mut digests = BTreeMap::new();
+    for fp in repo_files(repo_dir).map_err(|e| Error::IoAt(repo_dir.to_path_buf(), e))? {
+        if let Some(meta) = read_metadata(&fp, repo_dir)? {
+            if !meta.sha256.is_empty() {
+                digests.entry(meta.sha256).or_insert_with(BTreeSet::new).insert(repo_relative(&fp, repo_dir));
+            }
+        }
+    }
+    Ok(digests)
+}
+
+// Change the index as `update` does and write it back, through a temporary
+// file as with the sidecars
+fn update_index<F>(repo_dir: &Path, update: F) -> Result<(), Error>
+    where F: FnOnce(&mut BTreeMap<String, BTreeSet<PathBuf>>)
+{
+    let mut index = match read_index(repo_dir)? {
+        Some(index) => index,
+        None => DigestIndex { digests: sidecar_digests(repo_dir)? },
+    };
+    update(&mut index.digests);
+    let path = repo_dir.join(DIGEST_INDEX);
+    // Kept out of the repository's own files until it is complete
+    let tmp = repo_dir.join(META_DIR).join(format!("{}.paperman-tmp", DIGEST_INDEX));
+    fs::create_dir_all(tmp.parent().unwrap()).map_err(|e| Error::IoAt(tmp.clone(), e))?;
+    fs::write(&tmp, toml::to_string(&index)?).map_err(|e| Error::IoAt(tmp.clone(), e))?;
+    fs::rename(&tmp, &path).map_err(|e| Error::IoAt(path, e))
+}
+
+// Drop `paths` from the index, along with the digests left without files
+fn forget_paths(digests: &mut BTreeMap<String, BTreeSet<PathBuf>>, paths: &[PathBuf]) {
+    for files in digests.values_mut() {
+        files.retain(|rel| !paths.contains(rel));
+    }
+    digests.retain(|_, files| !files.is_empty());
+}
+
+// The sidecars whose file has disappeared from the repository
+fn orphaned_sidecars(repo_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let sidecar_dir = repo_dir.join(META_DIR).join(SIDECAR_DIR);
+    if !sidecar_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    Ok(walk(&sidecar_dir)?.into_iter()
+        .filter(|(path, ft)| *ft == FileType::File && path.extension() == Some("toml".as_ref()))
+        .map(|(path, _)| path)
+        .filter(|path| repo_dir.join(path.strip_prefix(&sidecar_dir).unwrap().with_extension("")).symlink_metadata().is_err())
+        .collect())
+}
+
+// Paths relative to the repository
+#[derive(Serialize, Debug)]
+struct VerifyReport<'a> {
+    files: usize,
+    mismatched: &'a [&'a Path],
+    unrecorded: &'a [&'a Path],
+    missing: &'a [PathBuf],
+}
+
+// Compare the files in the repository against the digests in their sidecars,
+// or with `update`, record the current digests instead
+fn verify<W: WriteColor>(update: bool, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let files = repo_files(&repo_dir).map_err(|e| Error::IoAt(repo_dir.clone(), e))?;
+    let orphans = orphaned_sidecars(&repo_dir).map_err(|e| Error::IoAt(repo_dir.clone(), e))?;
+
+    let progress = printer.progress;
+    let human = printer.output == OutputMode::Human;
+    let mut mismatched = Vec::new();
+    let mut unrecorded = Vec::new();
+    for (i, fp) in files.iter().enumerate() {
+        let rel = fp.strip_prefix(&repo_dir).unwrap();
+        if progress {
+            printer.show_progress(format_args!("Verifying {}/{} {}", i + 1, files.len(), rel.display()))?;
+        }
+        let digest = sha256_file(fp).map_err(|e| Error::IoAt(fp.clone(), e))?;
+        let recorded = read_metadata(fp, &repo_dir)?.map(|meta| meta.sha256).filter(|sha256| !sha256.is_empty());
+        if recorded.as_ref() == Some(&digest) {
+            continue;
+        }
+        if progress {
+            printer.show_progress("")?;
+        }
+        if recorded.is_some() {
+            if human {
+                printer.line(format_args!("mismatch    {}", rel.display()))?;
+            }
+            mismatched.push(rel);
+        }
+        else {
+            if human {
+                printer.line(format_args!("no digest   {}", rel.display()))?;
+            }
+            unrecorded.push(rel);
+        }
+        if update {
+            if dry_run {
+                printer.say(format_args!("[dry-run] write {}", sidecar_path(fp, &repo_dir).display()))?;
+                continue;
+            }
+            let mut meta = read_or_new_metadata(fp, &repo_dir)?;
+            meta.sha256 = digest;
+            write_metadata(fp, &repo_dir, &meta)?;
+        }
+    }
+    if progress {
+        printer.show_progress("")?;
+    }
+    let missing: Vec<_> = orphans.iter()
+        .map(|sidecar| sidecar.strip_prefix(repo_dir.join(META_DIR).join(SIDECAR_DIR)).unwrap().with_extension(""))
+        .collect();
+
+    if human {
+        for rel in &missing {
+            printer.line(format_args!("missing     {}", rel.display()))?;
+        }
+        printer.line(format_args!("Verified {} file(s): {} mismatch(es), {} without digest, {} missing",
+            files.len(), mismatched.len(), unrecorded.len(), missing.len()))?;
+    }
+    else {
+        let report = VerifyReport { files: files.len(), mismatched: &mismatched, unrecorded: &unrecorded, missing: &missing };
+        printer.line(serde_json::to_string_pretty(&report)?)?;
+    }
+    match if update { missing.len() } else { mismatched.len() + missing.len() } {
+        0 => Ok(()),
+        n => Err(Error::Problems(n)),
+    }
+}
+
+// The original location recorded for a repository file, if it still holds a
+// symlink or a hard link to the file
+fn recorded_link(fp: &Path, repo_dir: &Path) -> Result<Option<PathBuf>, Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let original_path = match read_metadata(fp, repo_dir)? {
+        Some(Metadata { original_path: Some(path), .. }) => path,
+        _ => return Ok(None),
+    };
+    let linked = match file_type(&original_path) {
+        Ok(FileType::Symlink) => target_in_repo(&original_path, repo_dir)?.as_deref() == Some(fp),
+        Ok(FileType::File) => match (fs::metadata(&original_path), fs::metadata(fp)) {
+            (Ok(a), Ok(b)) => (a.dev(), a.ino()) == (b.dev(), b.ino()),
+            _ => false,
+        },
+        _ => false,
+    };
+    Ok(if linked { Some(original_path) } else { None })
+}
+
+#[derive(Serialize, Debug)]
+struct PruneReport<'a> {
+    stale: &'a [PathBuf],
+    moved: &'a [MovedLink<'a>],
+}
+
+// A repository file whose recorded original location no longer links to it
+#[derive(Serialize, Debug)]
+struct MovedLink<'a> {
+    path: &'a Path,
+    original_path: PathBuf,
+}
+
+// List sidecars whose file has gone, removing them with `apply`, and those
+// whose original location no longer holds a link to their file
+fn prune<W: WriteColor>(apply: bool, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let files = repo_files(&repo_dir).map_err(|e| Error::IoAt(repo_dir.clone(), e))?;
+    let orphans = orphaned_sidecars(&repo_dir).map_err(|e| Error::IoAt(repo_dir.clone(), e))?;
+
+    let mut moved = Vec::new();
+    for fp in &files {
+        let original_path = match read_metadata(fp, &repo_dir)? {
+            Some(Metadata { copy_mode: true, .. }) | None => continue,
+            Some(Metadata { original_path: Some(path), .. }) => path,
+            Some(_) => continue,
+        };
+        if recorded_link(fp, &repo_dir)?.is_none() {
+            moved.push(MovedLink { path: fp.strip_prefix(&repo_dir).unwrap(), original_path });
+        }
+    }
+    if printer.output == OutputMode::Json {
+        printer.line(serde_json::to_string_pretty(&PruneReport { stale: &orphans, moved: &moved })?)?;
+    }
+    else {
+        for sidecar in &orphans {
+            printer.line(format_args!("stale       {}", sidecar.strip_prefix(&repo_dir).unwrap().display()))?;
+        }
+        for MovedLink { path, original_path } in &moved {
+            printer.line(format_args!("moved       {} (no link at {})", path.display(), original_path.display()))?;
+        }
+    }
+
+    if !apply {
+        if !orphans.is_empty() {
+            printer.err_line(format_args!("Pass --apply to remove the {} stale sidecar(s)", orphans.len()))?;
+        }
+        return Ok(());
+    }
+    for sidecar in &orphans {
+        if dry_run {
+            printer.say(format_args!("[dry-run] remove {}", sidecar.display()))?;
+            continue;
+        }
+        fs::remove_file(sidecar).map_err(|e| Error::IoAt(sidecar.clone(), e))?;
+    }
+    Ok(())
+}
+
+// Carry the sidecar of a file over to its new name in the repository
+fn move_sidecar(from: &Path, to: &Path, repo_dir: &Path) -> Result<(), Error> {
+    let sidecar = sidecar_path(from, repo_dir);
+    if !sidecar.exists() {
+        return Ok(());
+    }
+    let moved = sidecar_path(to, repo_dir);
+    fs::create_dir_all(moved.parent().unwrap())
+        .and_then(|()| fs::rename(&sidecar, &moved))
+        .map_err(|e| Error::IoAt(sidecar, e))?;
+    let (from, to) = (repo_relative(from, repo_dir), repo_relative(to, repo_dir));
+    update_index(repo_dir, |digests| {
+        for files in digests.values_mut() {
+            if files.remove(&from) {
+                files.insert(to.clone());
+            }
+        }
+    })
+}
+
+// Drop the sidecars of files that have left the repository
+fn forget_metadata(paths: &[PathBuf], repo_dir: &Path) -> Result<(), Error> {
+    for path in paths {
+        let sidecar = sidecar_path(path, repo_dir);
+        if let Err(e) = fs::remove_file(&sidecar) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(Error::IoAt(sidecar, e));
+            }
+        }
+    }
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let rels: Vec<_> = paths.iter().map(|path| repo_relative(path, repo_dir)).collect();
+    update_index(repo_dir, |digests| forget_paths(digests, &rels))
+}
+
+// Resolve a symlink into the repository, or the path of a file relative to
+// the repository, to the file inside the repository
+fn resolve_repo_file(file: &Path, repo_dir: &Path) -> Result<PathBuf, Error> {
+    let target = if file_type(file).ok() == Some(FileType::Symlink) {
+        match target_in_repo(file, repo_dir)? {
+            Some(ref target) if link_state(target) == LinkState::Broken => {
+                return Err(Error::BrokenLink(file.to_path_buf()));
+            },
+            Some(target) => target,
+            None => return Err(Error::NotManaged(file.to_path_buf())),
+        }
+    }
+    else {
+        normalize(repo_dir.join(file))
+    };
+    if !target.starts_with(repo_dir) || target.starts_with(repo_dir.join(META_DIR))
+        || file_type(&target).ok() != Some(FileType::File) {
+        return Err(Error::NotInRepo(file.to_path_buf()));
+    }
+    Ok(target)
+}
+
+fn tag<W: WriteColor>(cmd: TagCommand, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+
+    match cmd {
+        TagCommand::Add { file, tags } => {
+            if let Some(tag) = tags.iter().find(|tag| tag.is_empty() || tag.contains(char::is_whitespace)) {
+                return Err(Error::InvalidTag(tag.clone()));
+            }
+            let file = resolve_repo_file(&file, &repo_dir)?;
+            if dry_run {
+                printer.say(format_args!("[dry-run] tag {} with {}", file.display(), tags.join(", ")))?;
+                return Ok(());
+            }
+            let mut meta = read_or_new_metadata(&file, &repo_dir)?;
+            meta.tags.extend(tags);
+            write_metadata(&file, &repo_dir, &meta)?;
+            report_changed(printer, &file)
+        },
+        TagCommand::Remove { file, tags } => {
+            let file = resolve_repo_file(&file, &repo_dir)?;
+            if dry_run {
+                printer.say(format_args!("[dry-run] untag {} from {}", file.display(), tags.join(", ")))?;
+                return Ok(());
+            }
+            if let Some(mut meta) = read_metadata(&file, &repo_dir)? {
+                let before = meta.tags.len();
+                for tag in &tags {
+                    meta.tags.remove(tag);
+                }
+                if meta.tags.len() != before {
+                    write_metadata(&file, &repo_dir, &meta)?;
+                }
+            }
+            report_changed(printer, &file)
+        },
+        TagCommand::List { file: Some(file) } => {
+            let file = resolve_repo_file(&file, &repo_dir)?;
+            let tags = read_metadata(&file, &repo_dir)?.map(|meta| meta.tags).unwrap_or_default();
+            if printer.output == OutputMode::Json {
+                printer.line(serde_json::to_string_pretty(&tags)?)?;
+                return Ok(());
+            }
+            for tag in tags {
+                printer.line(tag)?;
+            }
+            Ok(())
+        },
+        TagCommand::List { file: None } => {
+            let counts = tag_counts(&repo_dir)?;
+            if printer.output == OutputMode::Json {
+                printer.line(serde_json::to_string_pretty(&counts)?)?;
+                return Ok(());
+            }
+            for (tag, count) in counts {
+                printer.line(format_args!("{}\t{}", tag, count))?;
+            }
+            Ok(())
+        },
+    }
+}
+
+// The repository file behind a symlink or hard link to it, or the file itself
+// if it is in the repository already
+fn which_path(file: &Path, repo_dir: &Path) -> Result<PathBuf, Error> {
+    match file_type(file).map_err(|e| Error::IoAt(file.to_path_buf(), e))? {
+        FileType::Symlink => resolve_repo_file(file, repo_dir),
+        FileType::File if is_in_repo(file, repo_dir) => Ok(normalize(to_absolute(file)?)),
+        FileType::File => hard_link_in_repo(file, repo_dir)?.ok_or_else(|| Error::NotManaged(file.to_path_buf())),
+        FileType::Dir => Err(Error::IsDir(file.to_path_buf())),
+        FileType::Other => Err(Error::NotManaged(file.to_path_buf())),
+    }
+}
+
+fn which<W: WriteColor>(file: PathBuf, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    let path = which_path(&file, &repo_dir)?;
+    match printer.output {
+        OutputMode::Human => printer.line(path.display())?,
+        OutputMode::Json => printer.line(serde_json::to_string_pretty(&path)?)?,
+    }
+    Ok(())
+}
+
+// Resolve like `resolve_repo_file`, but fall back to a case-insensitive
+// prefix match against the repository files
+fn find_document<W: WriteColor>(query: &Path, repo_dir: &Path, printer: &mut Printer<W>) -> Result<PathBuf, Error> {
+    match resolve_repo_file(query, repo_dir) {
+        Err(Error::NotInRepo(_)) if file_type(query).ok() != Some(FileType::Symlink) => (),
+        result => return result,
+    }
+    // Either the file name or the path in the repository may begin with it
+    let needle = query.to_string_lossy().to_lowercase();
+    let mut matches: Vec<_> = repo_files(repo_dir)?.into_iter()
+        .filter(|fp| {
+            let name = fp.file_name().unwrap().to_string_lossy().to_lowercase();
+            let rel = fp.strip_prefix(repo_dir).unwrap().to_string_lossy().to_lowercase();
+            name.starts_with(&needle) || rel.starts_with(&needle)
+        })
+        .collect();
+    match matches.len() {
+        0 => Err(Error::NotInRepo(query.to_path_buf())),
+        1 => Ok(matches.remove(0)),
+        n => {
+            printer.err_line("Candidates:")?;
+            for fp in &matches {
+                printer.err_line(fp.strip_prefix(repo_dir).unwrap().display())?;
+            }
+            Err(Error::Ambiguous(query.to_path_buf(), n))
+        },
+    }
+}
+
+// The command the desktop uses to open a file with its default application
+fn default_opener() -> Vec<String> {
+    let opener: &[&str] = if cfg!(target_os = "macos") {
+        &["open"]
+    }
+    else if cfg!(windows) {
+        // `start` is built into cmd, and takes the first quoted argument as
+        // the window title
+        &["cmd", "/C", "start", ""]
+    }
+    else {
+        &["xdg-open"]
+    };
+    opener.iter().map(|s| s.to_string()).collect()
+}
+
+fn open<W: WriteColor>(name: PathBuf, print: bool, app: Option<String>, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let path = find_document(&name, &repo_dir, printer)?;
+    // With `--json` the path is printed as the result either way
+    if printer.output == OutputMode::Json {
+        printer.line(serde_json::to_string_pretty(&path)?)?;
+    }
+    if print {
+        if printer.output == OutputMode::Human {
+            printer.line(path.display())?;
+        }
+        return Ok(());
+    }
+
+    let opener = app.or_else(|| config.viewer.clone()).map(|app| vec![app]).unwrap_or_else(default_opener);
+    if dry_run {
+        printer.say(format_args!("[dry-run] run {} {}", opener.join(" "), path.display()))?;
+        return Ok(());
+    }
+    // Give the terminal back right away instead of waiting for the viewer
+    std::process::Command::new(&opener[0])
+        .args(&opener[1..])
+        .arg(&path)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .map_err(|e| Error::IoAt(PathBuf::from(&opener[0]), e))?;
+    Ok(())
+}
+
+// A line found by `grep`, with the path relative to the repository
+#[derive(Serialize, Debug)]
+struct GrepMatch {
+    path: PathBuf,
+    line: usize,
+    text: String,
+}
+
+// Search the text of every repository file line by line.  Files whose text
+// cannot be extracted are skipped.
+fn grep<W: WriteColor>(query: String, case_sensitive: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let extractor = config.text_extractor.clone().unwrap_or_else(|| "pdftotext".to_string());
+    let cache_dir = repo_dir.join(META_DIR).join(TEXT_CACHE_DIR);
+    let needle = if case_sensitive { query } else { query.to_lowercase() };
+
+    let mut matches = Vec::new();
+    for fp in repo_files(&repo_dir)? {
+        let name = fp.strip_prefix(&repo_dir).unwrap();
+        let text = match extract_text(&fp, &extractor, &cache_dir) {
+            Ok(text) => text,
+            Err(e) => {
+                printer.warn(format_args!("skipped {}: {}", name.display(), e))?;
+                continue;
+            },
+        };
+        for (i, line) in text.lines().enumerate() {
+            let found = if case_sensitive { line.contains(&needle) } else { line.to_lowercase().contains(&needle) };
+            if found && printer.output == OutputMode::Human {
+                printer.line(format_args!("{}:{}: {}", name.display(), i + 1, line.trim()))?;
+            }
+            else if found {
+                matches.push(GrepMatch { path: name.to_path_buf(), line: i + 1, text: line.trim().to_string() });
+            }
+        }
+    }
+    if printer.output == OutputMode::Json {
+        printer.line(serde_json::to_string_pretty(&matches)?)?;
+    }
+
+    Ok(())
+}
+
+// Extract the text of a file, reusing an earlier result for the same
+// contents if there is one in `cache_dir`
+fn extract_text(path: &Path, extractor: &str, cache_dir: &Path) -> Result<String, Error> {
+    let digest = sha256_file(path).map_err(|e| Error::IoAt(path.to_path_buf(), e))?;
+    let cached = cache_dir.join(format!("{}.txt", digest));
+    if let Ok(text) = fs::read_to_string(&cached) {
+        return Ok(text);
+    }
+
+    let output = std::process::Command::new(extractor)
+        .arg(path)
+        .arg("-")
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output()
+        .map_err(|e| Error::IoAt(PathBuf::from(extractor), e))?;
+    if !output.status.success() {
+        return Err(Error::Exited(extractor.to_string(), output.status.code().unwrap_or(1)));
+    }
+    let text = String::from_utf8_lossy(&output.stdout).into_owned();
+
+    // Failing to cache only makes the next search slower
+    let tmp = cache_dir.join(format!(".{}.txt.paperman-tmp", digest));
+    let _ = fs::create_dir_all(cache_dir)
+        .and_then(|_| fs::write(&tmp, &text))
+        .and_then(|_| fs::rename(&tmp, &cached));
+    Ok(text)
+}
+
+#[derive(Serialize, Debug)]
+struct InfoReport<'a> {
+    path: &'a Path,
+    size: u64,
+    modified: Option<String>,
+    links: Vec<InfoLink<'a>>,
+    tags: Vec<&'a str>,
+    original_path: Option<&'a Path>,
+    added: Option<&'a str>,
+    sha256: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+struct InfoLink<'a> {
+    path: &'a Path,
+    broken: bool,
+}
+
+fn info<W: WriteColor>(path: PathBuf, search_root: Option<PathBuf>, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let file = resolve_repo_file(&path, &repo_dir)?;
+    let metadata = fs::metadata(&file).map_err(|e| Error::IoAt(file.clone(), e))?;
+
+    let mut links: Vec<_> = find_links(search_root_or_home(search_root)?, &repo_dir)?.into_iter()
+        .filter(|(_, target)| *target == file)
+        .map(|(link, _)| link)
+        .collect();
+    if file_type(&path).ok() == Some(FileType::Symlink) {
+        let link = to_absolute(&path)?;
+        if !links.contains(&link) {
+            links.push(link);
+        }
+    }
+
+    let meta = read_metadata(&file, &repo_dir)?;
+    if printer.output == OutputMode::Json {
+        let report = InfoReport {
+            path: &file,
+            size: metadata.len(),
+            modified: metadata.modified().ok().map(format_time),
+            links: links.iter().map(|link| InfoLink { path: link, broken: fs::metadata(link).is_err() }).collect(),
+            tags: meta.as_ref().map(|meta| meta.tags.iter().map(String::as_str).collect()).unwrap_or_default(),
+            original_path: meta.as_ref().and_then(|meta| meta.original_path.as_deref()),
+            added: meta.as_ref().and_then(|meta| meta.added.as_deref()),
+            sha256: meta.as_ref().map(|meta| meta.sha256.as_str()),
+        };
+        printer.line(serde_json::to_string_pretty(&report)?)?;
+        return Ok(());
+    }
+
+    printer.line(format_args!("Path:     {}", file.display()))?;
+    printer.line(format_args!("Size:     {}", metadata.len()))?;
+    printer.line(format_args!("Modified: {}", metadata.modified().map(format_time).unwrap_or_else(|_| "-".to_string())))?;
+    printer.line(format_args!("Links ({}):", links.len()))?;
+    for link in &links {
+        let state = if fs::metadata(link).is_ok() { "ok" } else { "broken" };
+        printer.line(format_args!("    {} ({})", link.display(), state))?;
+    }
+    if let Some(meta) = meta {
+        let tags: Vec<_> = meta.tags.iter().map(String::as_str).collect();
+        printer.line(format_args!("Tags:     {}", tags.join(", ")))?;
+        if let Some(original_path) = meta.original_path {
+            printer.line(format_args!("Original: {}", original_path.display()))?;
+        }
+        if let Some(added) = meta.added {
+            printer.line(format_args!("Added:    {}", added))?;
+        }
+        printer.line(format_args!("SHA-256:  {}", meta.sha256))?;
+    }
+    else {
+        printer.line("Tags:     ")?;
+    }
+
+    Ok(())
+}
+
+fn tag_counts(repo_dir: &Path) -> Result<BTreeMap<String, usize>, Error> {
+    let mut counts = BTreeMap::new();
+    for fp in repo_files(repo_dir)? {
+        for tag in read_metadata(&fp, repo_dir)?.map(|meta| meta.tags).unwrap_or_default() {
+            *counts.entry(tag).or_insert(0) += 1;
+        }
+    }
+    Ok(counts)
+}
+
+#[derive(Default, Debug)]
+struct CheckSummary {
+    // Problems remaining after any repairs
+    problems: usize,
+    repaired: usize,
+    skipped: usize,
+    unrecoverable: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct Problem {
+    category: &'static str,
+    description: String,
+}
+
+#[derive(Serialize, Debug)]
+struct CheckReport<'a> {
+    problems: &'a [Problem],
+    repaired: usize,
+    skipped: usize,
+    unrecoverable: usize,
+}
+
+#[derive(Eq, PartialEq, Debug)]
+enum Repair {
+    Repaired,
+    Skipped,
+    Unrecoverable,
+}
+
+fn check<W: WriteColor>(search_roots: Vec<PathBuf>, fix: bool, yes: bool, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<CheckSummary, Error> {
+    let mut summary = CheckSummary::default();
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    let mut problems = Vec::new();
+
+    // Inspect the repository itself, descending into any subdirectories
+    if !repo_dir.is_dir() {
+        return Err(Error::NoRepoDir(repo_dir));
+    }
+    let mut entries = Vec::new();
+    let mut stack = vec![repo_dir.clone()];
+    while let Some(dir) = stack.pop() {
+        let read_dir = match fs::read_dir(&dir) {
+            Ok(read_dir) => read_dir,
+            Err(_) => {
+                problems.push(("UNREADABLE", dir.display().to_string()));
+                continue;
+            },
+        };
+        for entry in read_dir {
+            let path = entry?.path();
+            if path == repo_dir.join(META_DIR) || path == repo_dir.join(DIGEST_INDEX) {
+                continue;
+            }
+            if file_type(&path).ok() == Some(FileType::Dir) {
+                stack.push(path);
+            }
+            else {
+                entries.push(path);
+            }
+        }
+    }
+    entries.sort();
+    for path in &entries {
+        match file_type(path) {
+            Ok(FileType::File) => {
+                if File::open(path).is_err() {
+                    problems.push(("UNREADABLE", path.display().to_string()));
+                }
+                else if fs::metadata(path).map(|m| m.len() == 0).unwrap_or(false) {
+                    problems.push(("EMPTY", path.display().to_string()));
+                }
+            },
+            Ok(FileType::Symlink) => problems.push(("SYMLINK", path.display().to_string())),
+            Ok(FileType::Other) => problems.push(("SPECIAL", path.display().to_string())),
+            // Replaced by a directory since the walk above
+            Ok(FileType::Dir) => problems.push(("DIR", path.display().to_string())),
+            Err(_) => problems.push(("UNREADABLE", path.display().to_string())),
+        }
+    }
+
+    // Cross-check against the symlinks found under the search roots
+    if !search_roots.is_empty() {
+        let find_all_links = || -> Result<Vec<_>, Error> {
+            let mut links = Vec::new();
+            for root in &search_roots {
+                links.extend(find_links(root, &repo_dir)?);
+            }
+            Ok(links)
+        };
+        let mut links = find_all_links()?;
+        if fix {
+            let files: Vec<_> = entries.iter().filter(|path| file_type(path).ok() == Some(FileType::File)).cloned().collect();
+            for (link, target) in links.iter().filter(|(_, target)| link_state(target) == LinkState::Broken) {
+                match repair_link(link, target, &files, &config, yes, dry_run, printer)? {
+                    Repair::Repaired => summary.repaired += 1,
+                    Repair::Skipped => summary.skipped += 1,
+                    Repair::Unrecoverable => summary.unrecoverable += 1,
+                }
+            }
+            links = find_all_links()?;
+        }
+        for path in entries.iter().filter(|path| file_type(path).ok() == Some(FileType::File)) {
+            if !links.iter().any(|(_, target)| target == path) {
+                problems.push(("ORPHAN", path.display().to_string()));
+            }
+        }
+        for (link, target) in &links {
+            if link_state(target) == LinkState::Broken {
+                problems.push(("BROKEN", format!("{} -> {}", link.display(), target.display())));
+            }
+        }
+    }
+
+    let problems: Vec<_> = problems.into_iter()
+        .map(|(category, description)| Problem { category, description })
+        .collect();
+    match printer.output {
+        OutputMode::Human => {
+            for problem in &problems {
+                printer.line(format_args!("{}\t{}", problem.category, problem.description))?;
+            }
+            if fix {
+                printer.line(format_args!("{} repaired, {} skipped, {} unrecoverable", summary.repaired, summary.skipped, summary.unrecoverable))?;
+            }
+        },
+        OutputMode::Json => {
+            let report = CheckReport {
+                problems: &problems,
+                repaired: summary.repaired,
+                skipped: summary.skipped,
+                unrecoverable: summary.unrecoverable,
+            };
+            printer.line(serde_json::to_string_pretty(&report)?)?;
+        },
+    }
+
+    summary.problems = problems.len();
+    Ok(summary)
+}
+
+// Repoint a broken symlink at the only repository file sharing the name of
+// its missing target, or else at a repository file the user names
+fn repair_link<W: WriteColor>(link: &Path, target: &Path, files: &[PathBuf], config: &Config, yes: bool, dry_run: bool, printer: &mut Printer<W>) -> Result<Repair, Error> {
+    let candidates: Vec<_> = files.iter().filter(|fp| fp.file_name() == target.file_name()).collect();
+    let new_target = if candidates.len() == 1 {
+        candidates[0].clone()
+    }
+    else if yes || dry_run {
+        return Ok(Repair::Skipped);
+    }
+    else {
+        let answer = ask(&format!("{} -> {} is broken; new target in the repository (empty to skip):", link.display(), target.display()))?;
+        if answer.is_empty() {
+            return Ok(Repair::Skipped);
+        }
+        match resolve_repo_file(Path::new(&answer), &to_absolute(&config.repo_dir)?) {
+            Ok(new_target) => new_target,
+            Err(e) => {
+                printer.err_line(format_args!("Error: {}", e))?;
+                return Ok(Repair::Unrecoverable);
+            },
+        }
+    };
+
+    let link_ref = link_ref(link.parent().unwrap(), &new_target, config.symlink_style)?;
+    if dry_run {
+        printer.say(format_args!("[dry-run] symlink {} -> {}", link.display(), link_ref.display()))?;
+        return Ok(Repair::Skipped);
+    }
+    match replace_symlink(&link_ref, link) {
+        Ok(()) => {
+            printer.say(format_args!("repaired {} -> {}", link.display(), new_target.display()))?;
+            Ok(Repair::Repaired)
+        },
+        Err(e) => {
+            printer.err_line(format_args!("Error: {}: {}", link.display(), e))?;
+            Ok(Repair::Unrecoverable)
+        },
+    }
+}
+
+// Match a file name against a shell-style wildcard pattern supporting `*`,
+// `?` and bracket expressions such as `[abc]`, `[a-z]` and `[!0-9]`
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Position to resume from when the last `*` has to absorb one more character
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                '*' => {
+                    backtrack = Some((p, n));
+                    p += 1;
+                    continue;
+                },
+                '?' => {
+                    p += 1;
+                    n += 1;
+                    continue;
+                },
+                '[' => {
+                    if let Some((matched, len)) = match_bracket(&pattern[p..], name[n]) {
+                        if matched {
+                            p += len;
+                            n += 1;
+                            continue;
+                        }
+                    }
+                    else if name[n] == '[' {
+                        p += 1;
+                        n += 1;
+                        continue;
+                    }
+                },
+                c if c == name[n] => {
+                    p += 1;
+                    n += 1;
+                    continue;
+                },
+                _ => (),
+            }
+        }
+        match backtrack {
+            Some((bp, bn)) => {
+                backtrack = Some((bp, bn + 1));
+                p = bp + 1;
+                n = bn + 1;
+            },
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn has_glob_meta(s: &str) -> bool {
+    s.contains(&['*', '?', '['][..])
+}
+
+// Expand a path containing wildcards into the existing paths matching it,
+// resolving relative patterns against the current directory.  As in shells,
+// a leading dot has to be matched explicitly.
+fn expand_glob(pattern: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::new()];
+    for component in pattern.components() {
+        let component = component.as_os_str();
+        let pattern = component.to_string_lossy();
+        if !has_glob_meta(&pattern) {
+            for path in &mut paths {
+                path.push(component);
+            }
+            continue;
+        }
+
+        let mut next = Vec::new();
+        for base in &paths {
+            let dir = if base.as_os_str().is_empty() { Path::new(".") } else { base.as_path() };
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            let mut names: Vec<_> = entries.filter_map(|entry| entry.ok()).map(|entry| entry.file_name()).collect();
+            names.sort();
+            for name in names {
+                let name_str = name.to_string_lossy();
+                if name_str.starts_with('.') && !pattern.starts_with('.') {
+                    continue;
+                }
+                if glob_match(&pattern, &name_str) {
+                    next.push(base.join(&name));
+                }
+            }
+        }
+        paths = next;
+    }
+    paths.retain(|path| path.symlink_metadata().is_ok());
+    paths
+}
+
+// Match a character against the bracket expression at the start of `pattern`,
+// returning whether it matched and the length of the expression, or `None` if
+// the expression is not terminated
+fn match_bracket(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = i < pattern.len() && (pattern[i] == '!' || pattern[i] == '^');
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() && (first || pattern[i] != ']') {
+        first = false;
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            matched |= pattern[i] <= c && c <= pattern[i + 2];
+            i += 3;
+        }
+        else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    if i < pattern.len() {
+        Some((matched != negated, i + 1))
+    }
+    else {
+        None
+    }
+}
+
+fn search_root_or_home(search_root: Option<PathBuf>) -> Result<PathBuf, Error> {
+    match search_root {
+        Some(dir) => Ok(dir),
+        None => dirs::home_dir().ok_or(Error::HomeDir),
+    }
+}
+
+fn repo_files<P: AsRef<Path>>(repo_dir: P) -> io::Result<Vec<PathBuf>> {
+    let meta_dir = repo_dir.as_ref().join(META_DIR);
+    let index = repo_dir.as_ref().join(DIGEST_INDEX);
+    Ok(walk(repo_dir)?.into_iter()
+        .filter(|(path, ft)| *ft == FileType::File && !path.starts_with(&meta_dir) && *path != index)
+        .map(|(path, _)| path)
+        .collect())
+}
+
+// Recursively list the files and symlinks under `dir` in sorted order,
+// without following symlinks.  Other kinds of files are left out, and
+// subdirectories that cannot be read are warned about and skipped.
+fn walk<P: AsRef<Path>>(dir: P) -> io::Result<Vec<(PathBuf, FileType)>> {
+    walk_max_depth(dir, None)
+}
+
+// Like `walk`, but descend at most `max_depth` levels, where the entries of
+// `dir` itself are at depth 1
+fn walk_max_depth<P: AsRef<Path>>(dir: P, max_depth: Option<usize>) -> io::Result<Vec<(PathBuf, FileType)>> {
+    let mut found = Vec::new();
+    let mut stack = vec![(dir.as_ref().to_path_buf(), 1)];
+    while let Some((dir, depth)) = stack.pop() {
+        if matches!(max_depth, Some(max_depth) if depth > max_depth) {
+            continue;
+        }
+        let read_dir = match fs::read_dir(&dir) {
+            Ok(read_dir) => read_dir,
+            Err(e) if depth > 1 => {
+                warn!("skipping {}: {}", dir.display(), e);
+                continue;
+            },
+            Err(e) => return Err(e),
+        };
+        for entry in read_dir {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    warn!("skipping an entry of {}: {}", dir.display(), e);
+                    continue;
+                },
+            };
+            match file_type(&path) {
+                Ok(FileType::Dir) => stack.push((path, depth + 1)),
+                Ok(FileType::Other) => debug!("skipping {}: not a regular file", path.display()),
+                Ok(ft) => found.push((path, ft)),
+                Err(e) => warn!("skipping {}: {}", path.display(), e),
+            }
+        }
+    }
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(found)
+}
+
+// Walk `root` without following symlinks and collect every symlink whose
+// target lies inside `repo_dir`, paired with the target expressed in terms of
+// `repo_dir`.  Unreadable directories are skipped silently.
+fn find_links<P: AsRef<Path>, Q: AsRef<Path>>(root: P, repo_dir: Q) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+    let repo_dir = to_absolute(repo_dir)?;
+    let mut links = Vec::new();
+    for path in find_symlinks(root, &repo_dir)? {
+        if let Ok(Some(target)) = target_in_repo(&path, &repo_dir) {
+            links.push((path, target));
+        }
+    }
+    Ok(links)
+}
+
+// Every symlink under `root`, skipping over the repository itself and any
+// directory that cannot be read
+fn find_symlinks<P: AsRef<Path>>(root: P, repo_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let canonical_repo_dir = repo_dir.canonicalize().unwrap_or_else(|_| repo_dir.to_path_buf());
+
+    let mut links = Vec::new();
+    let mut stack = vec![to_absolute(root)?];
+    while let Some(dir) = stack.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(_) => continue,
+            };
+            match file_type(&path) {
+                Ok(FileType::Dir) if path != repo_dir && path != canonical_repo_dir => {
+                    stack.push(path);
+                },
+                Ok(FileType::Symlink) => links.push(path),
+                _ => (),
+            }
+        }
+    }
+    links.sort();
+    Ok(links)
+}
+
+// Whether `link` points into the repository, either directly or through other
+// symlinks
+fn is_managed(link: &Path, repo_dir: &Path) -> Result<bool, Error> {
+    if target_in_repo(link, repo_dir)?.is_some() {
+        return Ok(true);
+    }
+    Ok(is_in_repo(link, repo_dir))
+}
+
+// Whether `path` lies under `repo_dir` once symlinks in either are resolved
+fn is_in_repo(path: &Path, repo_dir: &Path) -> bool {
+    match (path.canonicalize(), repo_dir.canonicalize()) {
+        (Ok(path), Ok(repo_dir)) => path.starts_with(repo_dir),
+        _ => false,
+    }
+}
+
+// Return the target of a symlink in terms of `repo_dir` if it points into the
+// repository
+fn target_in_repo<P: AsRef<Path>, Q: AsRef<Path>>(link: P, repo_dir: Q) -> Result<Option<PathBuf>, Error> {
+    let repo_dir = to_absolute(repo_dir)?;
+    let canonical_repo_dir = repo_dir.canonicalize().unwrap_or_else(|_| repo_dir.clone());
+    let target = link_target(link)?;
+    if let Ok(rest) = target.strip_prefix(&repo_dir) {
+        Ok(Some(repo_dir.join(rest)))
+    }
+    else if let Ok(rest) = target.strip_prefix(&canonical_repo_dir) {
+        Ok(Some(repo_dir.join(rest)))
+    }
+    else {
+        Ok(None)
+    }
+}
+
+// Resolve the target of a symlink without requiring the target to exist
+fn link_target<P: AsRef<Path>>(link: P) -> Result<PathBuf, Error> {
+    let link = to_absolute(link)?;
+    let target = fs::read_link(&link)?;
+    Ok(normalize(link.parent().unwrap().join(target)))
+}
+
+fn normalize<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.as_ref().components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            c => normalized.push(c.as_os_str()),
+        }
+    }
+    normalized
+}
+
+// Format a time as an RFC 3339 timestamp in UTC, e.g. 2019-09-28T12:34:56Z
+fn format_rfc3339(time: SystemTime) -> String {
+    format!("{}Z", format_time(time).replacen(' ', "T", 1))
+}
+
+// One line of the journal: when, what, and the paths outside and inside
+// the repository
+#[derive(Serialize, PartialEq, Debug)]
+struct JournalEntry {
+    time: String,
+    op: String,
+    source: PathBuf,
+    repo_path: PathBuf,
+}
+
+// Append an entry to the journal.  Each entry is written with a single call
+// on a file opened in append mode, so that concurrent invocations cannot
+// interleave within a line.  Failing to write it is only worth a warning
+// as the operation itself has already succeeded.
+fn journal<W: WriteColor>(repo_dir: &Path, op: &str, source: &Path, repo_path: &Path, printer: &mut Printer<W>) -> io::Result<()> {
+    let path = repo_dir.join(META_DIR).join(JOURNAL);
+    let line = format!("{}\t{}\t{}\t{}\n",
+        format_rfc3339(SystemTime::now()),
+        op,
+        escape_field(&source.to_string_lossy()),
+        escape_field(&to_absolute(repo_path).unwrap_or_else(|_| repo_path.to_path_buf()).to_string_lossy()));
+    let result = fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| File::options().append(true).create(true).open(&path))
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(e) = result {
+        printer.warn(format_args!("failed to write to {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+fn read_journal(repo_dir: &Path) -> Result<Vec<JournalEntry>, Error> {
+    let path = repo_dir.join(META_DIR).join(JOURNAL);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::IoAt(path, e)),
+    };
+    // Lines cut short by a crash are skipped
+    Ok(content.lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split('\t').collect();
+            match fields[..] {
+                [time, op, source, repo_path] => Some(JournalEntry {
+                    time: time.to_string(),
+                    op: op.to_string(),
+                    source: PathBuf::from(unescape_field(source)),
+                    repo_path: PathBuf::from(unescape_field(repo_path)),
+                }),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+// Keep tabs and newlines in paths from breaking up the journal
+fn escape_field(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape_field(s: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('t')) => unescaped.push('\t'),
+            ('\\', Some('n')) => unescaped.push('\n'),
+            ('\\', Some('\\')) => unescaped.push('\\'),
+            _ => {
+                unescaped.push(c);
+                continue;
+            },
+        }
+        chars.next();
+    }
+    unescaped
+}
+
+fn log<W: WriteColor>(limit: Option<usize>, file: Option<String>, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    let entries: Vec<_> = read_journal(&repo_dir)?.into_iter()
+        .filter(|entry| match &file {
+            Some(name) => {
+                let rel = entry.repo_path.strip_prefix(&repo_dir).unwrap_or(&entry.repo_path);
+                rel == Path::new(name) || entry.repo_path.file_name() == Some(name.as_ref())
+            },
+            None => true,
+        })
+        .collect();
+    let skip = match limit {
+        Some(limit) => entries.len().saturating_sub(limit),
+        None => 0,
+    };
+    if printer.output == OutputMode::Json {
+        printer.line(serde_json::to_string_pretty(&entries[skip..])?)?;
+        return Ok(());
+    }
+    for entry in &entries[skip..] {
+        let rel = entry.repo_path.strip_prefix(&repo_dir).unwrap_or(&entry.repo_path);
+        match entry.op.as_str() {
+            "remove" => printer.line(format_args!("{}  {:6}  {} -> {}", entry.time, entry.op, rel.display(), entry.source.display()))?,
+            "rename" => {
+                let old = entry.source.strip_prefix(&repo_dir).unwrap_or(&entry.source);
+                printer.line(format_args!("{}  {:6}  {} -> {}", entry.time, entry.op, old.display(), rel.display()))?;
+            },
+            _ => printer.line(format_args!("{}  {:6}  {} -> {}", entry.time, entry.op, entry.source.display(), rel.display()))?,
+        }
+    }
+    Ok(())
+}
+
+// The latest `steps` entries that have not been undone yet, newest first.
+// Each "undo" entry cancels the newest entry before it for the same
+// repository file, as a step that could not be undone is passed over.
+fn pending_entries(entries: Vec<JournalEntry>, steps: usize) -> Vec<JournalEntry> {
+    let mut undone = Vec::new();
+    let mut pending = Vec::new();
+    for entry in entries.into_iter().rev() {
+        if pending.len() == steps {
+            break;
+        }
+        if entry.op == "undo" {
+            undone.push(entry.repo_path);
+        }
+        else if let Some(i) = undone.iter().position(|repo_path| *repo_path == entry.repo_path) {
+            undone.remove(i);
+        }
+        else {
+            pending.push(entry);
+        }
+    }
+    pending
+}
+
+fn undo<W: WriteColor>(steps: usize, force: bool, dry_run: bool, config: Config, printer: &mut Printer<W>) -> Result<(), Error> {
+    let repo_dir = to_absolute(&config.repo_dir)?;
+    let entries = pending_entries(read_journal(&repo_dir)?, steps);
+    if entries.is_empty() {
+        return Err(Error::NothingToUndo);
+    }
+    if let Some(entry) = entries.iter().find(|entry| entry.op != "add") {
+        return Err(Error::NotUndoable(entry.op.clone()));
+    }
+    // A step that cannot be undone does not keep the others from being undone
+    let mut undone = Vec::new();
+    let mut failed = Vec::new();
+    for entry in entries {
+        match undo_add(&entry.source, &entry.repo_path, force, dry_run, &repo_dir, printer) {
+            Ok(()) if dry_run => (),
+            Ok(()) => undone.push(entry.repo_path),
+            Err(e) => failed.push((entry.repo_path, e.to_string())),
+        }
+    }
+    report_changes(printer, &undone, &failed)?;
+
+    if failed.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(Error::NotUndone(failed.len()))
+    }
+}
+
+fn undo_add<W: WriteColor>(source: &Path, repo_path: &Path, force: bool, dry_run: bool, repo_dir: &Path, printer: &mut Printer<W>) -> Result<(), Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    // Without the file itself there is nothing to put back
+    if file_type(repo_path).ok() != Some(FileType::File) {
+        return Err(Error::NotInRepo(repo_path.to_path_buf()));
+    }
+    let same_inode = match (fs::symlink_metadata(source), fs::metadata(repo_path)) {
+        (Ok(a), Ok(b)) => (a.dev(), a.ino()) == (b.dev(), b.ino()),
+        _ => false,
+    };
+    // Either the file goes back to where it came from, or the original is
+    // still there and only the repository's copy or hard link has to go.
+    // Whatever else has taken the original place is never replaced; the
+    // file is put back next to it under a numbered name instead.
+    let next_to_source = || (1..).map(|i| numbered_path(source, i)).find(|p| p.symlink_metadata().is_err()).unwrap();
+    let mut differences = Vec::new();
+    let restore_to = match file_type(source) {
+        Ok(FileType::Symlink) if target_in_repo(source, repo_dir)?.as_deref() == Some(repo_path) => Some(source.to_path_buf()),
+        Ok(FileType::Symlink) => {
+            differences.push(format!("{} no longer points to {}", source.display(), repo_path.display()));
+            Some(next_to_source())
+        },
+        Ok(FileType::File) if same_inode || is_copy_mode(repo_path, repo_dir) => None,
+        Ok(FileType::File) => {
+            differences.push(format!("{} has been replaced by a regular file", source.display()));
+            Some(next_to_source())
+        },
+        Ok(FileType::Other) => {
+            differences.push(format!("{} has been replaced by a special file", source.display()));
+            Some(next_to_source())
+        },
+        Ok(FileType::Dir) => return Err(Error::IsDir(source.to_path_buf())),
+        Err(_) => {
+            differences.push(format!("{} no longer exists", source.display()));
+            Some(source.to_path_buf())
+        },
+    };
+    if !differences.is_empty() {
+        for difference in &differences {
+            printer.err_line(difference)?;
+        }
+        if !force {
+            return Err(Error::Diverged(differences.len()));
+        }
+    }
+
+    if dry_run {
+        match restore_to {
+            Some(dest) => printer.say(format_args!("[dry-run] move {} -> {}", repo_path.display(), dest.display()))?,
+            None => printer.say(format_args!("[dry-run] remove {}", repo_path.display()))?,
+        }
+        return Ok(());
+    }
+    let dest = match restore_to {
+        Some(dest) => {
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent).map_err(|e| Error::IoAt(parent.to_path_buf(), e))?;
+            }
+            // Renaming onto the symlink replaces it atomically
+            move_file(repo_path, &dest).map_err(|e| Error::IoAt(repo_path.to_path_buf(), e))?;
+            printer.say(format_args!("{} -> {}", repo_path.display(), dest.display()))?;
+            dest
+        },
+        None => {
+            fs::remove_file(repo_path).map_err(|e| Error::IoAt(repo_path.to_path_buf(), e))?;
+            printer.say(format_args!("removed {}", repo_path.display()))?;
+            source.to_path_buf()
+        },
+    };
+    forget_metadata(&[repo_path.to_path_buf()], repo_dir)?;
+    journal(repo_dir, "undo", &dest, repo_path, printer)?;
+    Ok(())
+}
+
+// Format a timestamp as `YYYY-MM-DD HH:MM:SS` in UTC
+fn format_time(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+
+    // Convert days since the epoch into a civil date
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+#[derive(Eq, PartialEq, Debug)]
+enum FileType {
+    Dir,
+    File,
+    Symlink,
+    // FIFOs, sockets and device nodes
+    Other,
+}
+
+fn file_type<P: AsRef<Path>>(path: P) -> io::Result<FileType> {
+    let path = path.as_ref();
+    let metadata = path.symlink_metadata()?;
+    if metadata.file_type().is_dir() {
+        Ok(FileType::Dir)
+    }
+    else if metadata.file_type().is_file() {
+        Ok(FileType::File)
+    }
+    else if metadata.file_type().is_symlink() {
+        Ok(FileType::Symlink)
+    }
+    else {
+        Ok(FileType::Other)
+    }
+}
+
+// The path a symlink in `link_dir` should hold to point at `target`.  Absolute
+// targets are canonicalized so that they do not go through other symlinks.
+fn link_ref(link_dir: &Path, target: &Path, style: SymlinkStyle) -> Result<PathBuf, Error> {
+    match style {
+        SymlinkStyle::Relative => relative_path_from(link_dir, target),
+        SymlinkStyle::Absolute => {
+            // The target itself may not have been moved into place yet
+            let target = to_absolute(target)?;
+            match target.parent().unwrap().canonicalize() {
+                Ok(dir) => Ok(dir.join(target.file_name().unwrap())),
+                Err(e) => {
+                    debug!("canonicalize {} failed: {}", target.parent().unwrap().display(), e);
+                    Ok(target)
+                },
+            }
+        },
+    }
+}
+
+// The path of `target` relative to the directory `base`, `.` if they are the
+// same.  The paths are compared as written without resolving symlinks, so a
+// symlink in `base` is treated like a directory of that name.  Note that the
+// kernel resolves `..` in a symlink from the directory the link physically
+// lives in.
+fn relative_path_from<P: AsRef<Path>, Q: AsRef<Path>>(base: P, target: Q) -> Result<PathBuf, Error> {
+    // `.` and `..` would otherwise be counted as directories of their own
+    let mut base = normalize(to_absolute(base)?);
+    let target = normalize(to_absolute(target)?);
+
+    let mut count = 0;
+    while !target.starts_with(&base) {
+        if base.pop() {
+            count += 1;
+        }
+        else {
+            return Err(Error::Path("base cannot be a prefix of target".into()));
+        }
+    }
+
+    let mut relpath = PathBuf::new();
+    for _ in 0..count {
+        relpath.push("..");
+    }
+    relpath.push(target.strip_prefix(base).unwrap());
+    if relpath.as_os_str().is_empty() {
+        relpath.push(".");
+    }
+    Ok(relpath)
+}
+
+fn to_absolute<P: AsRef<Path>>(path: P) -> Result<PathBuf, Error> {
+    let path = path.as_ref();
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    }
+    else {
+        let cwd = std::env::current_dir()?;
+        Ok(cwd.join(path))
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    init_logger(opt.verbose, opt.quiet);
+    if let Err(e) = run(opt) {
+        exit_with_error(e);
+    }
+}
+
+// RUST_LOG, when set, takes precedence over the command line
+fn init_logger(verbose: u8, quiet: bool) {
+    // A single -v is for the operations themselves, see `Printer::note`
+    let level = match (quiet, verbose) {
+        (true, _) => log::LevelFilter::Error,
+        (false, 0..=1) => log::LevelFilter::Warn,
+        (false, 2) => log::LevelFilter::Info,
+        (false, 3) => log::LevelFilter::Debug,
+        (false, _) => log::LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
+}
+
+fn run(opt: Opt) -> Result<(), Error> {
+    // clap only sees a conflict when both flags come on the same side of the
+    // subcommand
+    let conflicts = [
+        (opt.repo.is_some() && opt.repo_dir.is_some(), "--repo", "--repo-dir"),
+        (opt.quiet && opt.verbose > 0, "--quiet", "--verbose"),
+        (opt.absolute && opt.relative, "--absolute", "--relative"),
+    ];
+    if let Some(&(_, a, b)) = conflicts.iter().find(|(given, _, _)| *given) {
+        return Err(Error::Conflicting(a, b));
+    }
+
+    let mut printer = Printer::new(opt.color(), opt.output_mode(), opt.verbosity());
+    // There is no config to read before it has been written
+    if let Command::Init { repo_dir, force } = opt.cmd {
+        return init(config_path(opt.config)?, repo_dir, dirs::home_dir().as_deref(), force, opt.dry_run, &mut printer);
+    }
+
+    // Diagnosing a broken config must not require a working one
+    if let Command::Doctor = opt.cmd {
+        return doctor(opt.config, &mut printer);
+    }
+    if let Command::Config { cmd } = opt.cmd {
+        return config_command(config_path(opt.config)?, cmd, opt.dry_run, &mut printer);
+    }
+    if let Command::Completions { shell, output } = opt.cmd {
+        return completions(shell, output, &mut printer);
+    }
+
+    let mut config = match (read_config(config_path(opt.config.clone())?), &opt.repo_dir) {
+        // The repository given on the command line is all that is needed
+        (Err(Error::IoAt(_, ref e)), Some(_)) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+        (result, _) => result?,
+    };
+    if let Some(ref name) = opt.repo {
+        select_repo(&mut config, name)?;
+    }
+    if let Some(ref repo_dir) = opt.repo_dir {
+        config.repo_dir = to_absolute(expand_tilde(repo_dir).ok_or(Error::HomeDir)?)?;
+    }
+    if config.repo_dir.as_os_str().is_empty() {
+        return Err(Error::NoRepo);
+    }
+    validate_repo_dir(&config.repo_dir)?;
+    if opt.absolute {
+        config.symlink_style = SymlinkStyle::Absolute;
+    }
+    else if opt.relative {
+        config.symlink_style = SymlinkStyle::Relative;
+    }
+
+    match opt.cmd {
+        Command::Init { .. } | Command::Doctor | Command::Config { .. } | Command::Completions { .. } => unreachable!(),
+        Command::Add { files, recursive, conflict, rename_on_collision, force, copy, link, link_dir, interactive, yes, .. } => {
+            let conflict = if rename_on_collision {
+                Some(Conflict::Rename)
+            }
+            else if force {
+                Some(Conflict::Overwrite)
+            }
+            else {
+                conflict
+            };
+            let mode = match (copy, link) {
+                (false, _) => config.link_type.add_mode(),
+                (true, false) => AddMode::Copy,
+                (true, true) => AddMode::CopyAndLink,
+            };
+            let options = AddOptions { recursive, conflict, mode, link_dir, interactive, yes, dry_run: opt.dry_run };
+            add(files, options, config, &mut printer)
+        },
+        Command::Copy { files, recursive, conflict } => {
+            let options = AddOptions { recursive, conflict, mode: AddMode::Copy, dry_run: opt.dry_run, ..AddOptions::default() };
+            add(files, options, config, &mut printer)
+        },
+        Command::Import { dir, max_depth, exts, include_empty, conflict } => {
+            let options = AddOptions { conflict, dry_run: opt.dry_run, ..AddOptions::default() };
+            import(dir, max_depth, exts, include_empty, options, config, &mut printer)
+        },
+        Command::Remove { files, search_root } => {
+            remove(files, search_root, opt.dry_run, config, &mut printer)
+        },
+        Command::Mv { link, new_name } => {
+            mv(link, new_name, opt.dry_run, config, &mut printer)
+        },
+        Command::Link { name, dests } => {
+            link(name, dests, opt.dry_run, config, &mut printer)
+        },
+        Command::Unmanage { links } => {
+            unmanage(links, opt.dry_run, config, &mut printer)
+        },
+        Command::Relink { old_repo, search_root } => {
+            relink(old_repo, search_root, opt.dry_run, config, &mut printer)
+        },
+        Command::ExportLinks { out, search_root } => {
+            export_links(out, search_root, opt.dry_run, config, &mut printer)
+        },
+        Command::ImportLinks { manifest } => {
+            import_links(manifest, opt.dry_run, config, &mut printer)
+        },
+        Command::MvLink { from, to } => {
+            mvlink(from, to, opt.dry_run, config, &mut printer)
+        },
+        Command::Rename { old_name, new_name, search_root } => {
+            rename(old_name, new_name, search_root, opt.dry_run, config, &mut printer)
+        },
+        Command::Move { file, subdir, search_root } => {
+            move_to_subdir(file, subdir, search_root, opt.dry_run, config, &mut printer)
+        },
+        Command::Restore { name, dest, force, parents } => {
+            restore(name, dest, force, parents, opt.dry_run, config, &mut printer)
+        },
+        Command::Export { dest_dir, names, all, force } => {
+            export(dest_dir, names, all, force, opt.dry_run, config, &mut printer)
+        },
+        Command::List { long, full_path, search_root, tag, pattern } => {
+            list(long, full_path, pattern.map(NameFilter::Glob), tag, search_root, config, &mut printer)
+        },
+        Command::Search { pattern, regex, ignore_case, long, full_path, search_root } => {
+            let filter = if regex {
+                // Compiled once here rather than for every file
+                NameFilter::Regex(regex::Regex::new(&if ignore_case { format!("(?i){}", pattern) } else { pattern })?)
+            }
+            else {
+                NameFilter::Substring(pattern, ignore_case)
+            };
+            list(long, full_path, Some(filter), None, search_root, config, &mut printer)
+        },
+        Command::Find { patterns, and, name_only } => {
+            find(patterns, and, name_only, config, &mut printer)
+        },
+        Command::Check { search_roots, fix, yes } => {
+            check(search_roots, fix, yes, opt.dry_run, config, &mut printer).and_then(|summary| match summary.problems {
+                0 => Ok(()),
+                n => Err(Error::Problems(n)),
+            })
+        },
+        Command::Gc { search_roots, move_to, yes, force } => {
+            gc(search_roots, move_to, yes || force, opt.dry_run, config, &mut printer)
+        },
+        Command::Verify { update } => {
+            verify(update, opt.dry_run, config, &mut printer)
+        },
+        Command::Prune { apply } => {
+            prune(apply, opt.dry_run, config, &mut printer)
+        },
+        Command::Dedupe { resolve, search_root } => {
+            dedupe(resolve, search_root, opt.dry_run, config, &mut printer)
+        },
+        Command::Status { dirs } => {
+            status(dirs, config, &mut printer)
+        },
+        Command::Info { path, search_root } => {
+            info(path, search_root, config, &mut printer)
+        },
+        Command::Which { file } => {
+            which(file, config, &mut printer)
+        },
+        Command::Open { name, print, app } => {
+            open(name, print, app, opt.dry_run, config, &mut printer)
+        },
+        Command::Stats => {
+            stats(config, &mut printer)
+        },
+        Command::Grep { query, case_sensitive } => {
+            grep(query, case_sensitive, config, &mut printer)
+        },
+        Command::Log { limit, file } => {
+            log(limit, file, config, &mut printer)
+        },
+        Command::Undo { steps, force } => {
+            undo(steps, force, opt.dry_run, config, &mut printer)
+        },
+        Command::Tag { cmd } => {
+            tag(cmd, opt.dry_run, config, &mut printer)
+        },
+    }
+}
+
+fn exit_with_error(e: Error) -> ! {
+    match e {
+        // The reader has gone away, as `head` does, so there is no one to tell
+        Error::Io(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {},
+        Error::Config(_) | Error::ConfigDir => {
+            eprintln!("Invalid configuration: {}", e);
+        },
+        // Details have already been printed by the command
+        Error::NotAdded(_) | Error::NotExported(_) | Error::NotRemoved(_) | Error::NotLinked(_) | Error::NotUnmanaged(_) | Error::LinksNotUpdated(_) | Error::Problems(_) | Error::Diverged(_) | Error::NotUndone(_) => {
+            eprintln!("{}", e);
+        },
+        _ => {
+            eprintln!("Error: {}", e);
+        },
+    }
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_path() {
+        // Nothing else in the tests reads $PAPERMAN_CONFIG
+        std::env::set_var("PAPERMAN_CONFIG", "/from/env.toml");
+        assert_eq!(config_path(Some(PathBuf::from("/from/flag.toml"))).unwrap(), PathBuf::from("/from/flag.toml"));
+        assert_eq!(config_path(None).unwrap(), PathBuf::from("/from/env.toml"));
+        std::env::set_var("PAPERMAN_CONFIG", "");
+        assert!(config_path(None).unwrap().ends_with(concat!(env!("CARGO_PKG_NAME"), ".toml")));
+        std::env::remove_var("PAPERMAN_CONFIG");
+    }
+
+    #[test]
+    fn test_repo_dir_option() {
+        let dir = temp_dir("repo_dir_option");
+        fs::write(dir.join("paper.pdf"), "content").unwrap();
+        let args = vec![
+            PathBuf::from("pm"),
+            PathBuf::from("--config"), dir.join("missing.toml"),
+            PathBuf::from("--repo-dir"), dir.join("repo"),
+            PathBuf::from("add"), dir.join("paper.pdf"),
+        ];
+        run(Opt::from_iter(args)).unwrap();
+        assert!(dir.join("repo/paper.pdf").is_file());
+    }
+
+    #[test]
+    fn test_repo_option() {
+        // The alias may be given after any subcommand, but not with --repo-dir
+        for args in &[&["pm", "add", "--repo", "books", "a.pdf"][..], &["pm", "list", "--repo", "books"], &["pm", "--repo", "books", "status"], &["pm", "gc", "--repo", "books"]] {
+            assert_eq!(Opt::from_iter_safe(*args).unwrap().repo.as_deref(), Some("books"));
+        }
+        assert!(Opt::from_iter_safe(&["pm", "--repo-dir", "/srv", "--repo", "books", "list"]).is_err());
+        let opt = Opt::from_iter(&["pm", "--repo-dir", "/srv", "add", "--repo", "books", "a.pdf"]);
+        assert!(matches!(run(opt), Err(Error::Conflicting(..))));
+    }
+
+    #[test]
+    fn test_dry_run_option() {
+        for args in &[&["pm", "-n", "add", "a.pdf"][..], &["pm", "add", "--dry-run", "a.pdf"], &["pm", "gc", "--dry-run"], &["pm", "gc", "-n"]] {
+            assert!(Opt::from_iter_safe(*args).unwrap().dry_run);
+        }
+        assert!(!Opt::from_iter(&["pm", "gc"]).dry_run);
+    }
+
+    #[test]
+    fn test_verbosity() {
+        assert_eq!(Opt::from_iter(&["pm", "list"]).verbosity(), Verbosity::Normal);
+        assert_eq!(Opt::from_iter(&["pm", "-v", "list"]).verbosity(), Verbosity::Verbose);
+        assert_eq!(Opt::from_iter(&["pm", "-vv", "list"]).verbosity(), Verbosity::Verbose);
+        assert_eq!(Opt::from_iter(&["pm", "--quiet", "list"]).verbosity(), Verbosity::Quiet);
+        assert!(Opt::from_iter_safe(&["pm", "-q", "-v", "list"]).is_err());
+        assert!(matches!(run(Opt::from_iter(&["pm", "-q", "list", "-v"])), Err(Error::Conflicting(..))));
+
+        // The global flags may also follow the subcommand
+        assert_eq!(Opt::from_iter(&["pm", "add", "-v", "a.pdf"]).verbosity(), Verbosity::Verbose);
+        assert_eq!(Opt::from_iter(&["pm", "unmanage", "-q", "a.pdf"]).verbosity(), Verbosity::Quiet);
+        let opt = Opt::from_iter(&["pm", "list", "--no-color", "--config", "/etc/pm.toml", "--repo-dir", "/srv", "--absolute"]);
+        assert!(opt.no_color && opt.absolute);
+        assert_eq!((opt.config, opt.repo_dir), (Some(PathBuf::from("/etc/pm.toml")), Some(PathBuf::from("/srv"))));
+        assert!(Opt::from_iter(&["pm", "add", "--relative", "a.pdf"]).relative);
+
+        assert!(!Printer::new(false, OutputMode::Human, Verbosity::Quiet).progress);
+
+        // Notes are only printed with `--verbose`, and warnings not with `--quiet`
+        let mut normal = printer();
+        normal.note("moved").unwrap();
+        normal.warn("skipped").unwrap();
+        assert!(normal.out.get_ref().is_empty());
+        assert_eq!(normal.err.get_ref(), b"Warning: skipped\n");
+        let mut verbose = Printer { verbosity: Verbosity::Verbose, ..printer() };
+        verbose.note("moved").unwrap();
+        assert_eq!(verbose.out.get_ref(), b"moved\n");
+        let mut quiet = Printer { verbosity: Verbosity::Quiet, ..printer() };
+        quiet.warn("skipped").unwrap();
+        assert!(quiet.err.get_ref().is_empty());
+    }
+
+    #[test]
+    fn test_output_mode() {
+        assert_eq!(Opt::from_iter(&["pm", "status"]).output_mode(), OutputMode::Human);
+        assert_eq!(Opt::from_iter(&["pm", "--json", "status"]).output_mode(), OutputMode::Json);
+        // The flag is global, so it may come after any subcommand
+        for cmd in &["list", "stats", "check", "verify", "gc", "doctor"] {
+            assert_eq!(Opt::from_iter(&["pm", cmd, "--json"]).output_mode(), OutputMode::Json);
+        }
+
+        let entry = StatusEntry { status: Health::Broken.label(), repo_path: Path::new("/repo/a.pdf"), link: None };
+        assert_eq!(serde_json::to_string(&entry).unwrap(), r#"{"status":"broken","repo_path":"/repo/a.pdf","link":null}"#);
+
+        let dir = temp_dir("output_mode");
+        fs::create_dir_all(dir.join("repo")).unwrap();
+        fs::write(dir.join("paper.pdf"), "content").unwrap();
+        let config = || repo_config(&dir.join("repo"));
+        let json = || Printer { output: OutputMode::Json, ..printer() };
+        add(vec![dir.join("paper.pdf")], add_options(), config(), &mut json()).unwrap();
+        // Each command prints a single JSON document
+        let parse = |out: Printer<termcolor::NoColor<Vec<u8>>>| -> serde_json::Value { serde_json::from_slice(&out.out.into_inner()).unwrap() };
+        for search_root in [Some(dir.clone()), None] {
+            let mut out = json();
+            list(false, false, None, None, search_root, config(), &mut out).unwrap();
+            let entries = parse(out);
+            assert_eq!(entries.as_array().unwrap().len(), 1);
+            assert_eq!(entries[0]["repo_path"], "paper.pdf");
+            assert_eq!(entries[0]["linked"], true);
+            assert_eq!(entries[0]["size"], 7);
+            assert_eq!(entries[0]["path"], dir.join("paper.pdf").to_str().unwrap());
+        }
+        let mut out = json();
+        status(vec![dir.clone()], config(), &mut out).unwrap();
+        assert!(String::from_utf8(out.out.into_inner()).unwrap().contains(r#""healthy""#));
+        let mut out = json();
+        log(None, None, config(), &mut out).unwrap();
+        let entries = parse(out);
+        assert_eq!(entries.as_array().unwrap().len(), 1);
+        assert_eq!(entries[0]["op"], "add");
+        assert_eq!(entries[0]["source"], dir.join("paper.pdf").to_str().unwrap());
+        let mut out = json();
+        which(dir.join("paper.pdf"), config(), &mut out).unwrap();
+        assert_eq!(parse(out), dir.join("repo/paper.pdf").to_str().unwrap());
+        let mut out = json();
+        info(dir.join("paper.pdf"), Some(dir.clone()), config(), &mut out).unwrap();
+        let report = parse(out);
+        assert_eq!(report["path"], dir.join("repo/paper.pdf").to_str().unwrap());
+        assert_eq!(report["size"], 7);
+        assert_eq!(report["links"], serde_json::json!([{ "path": dir.join("paper.pdf"), "broken": false }]));
+        assert_eq!(report["original_path"], dir.join("paper.pdf").to_str().unwrap());
+        let mut out = json();
+        check(vec![dir.clone()], false, false, false, config(), &mut out).unwrap();
+        assert_eq!(parse(out), serde_json::json!({ "problems": [], "repaired": 0, "skipped": 0, "unrecoverable": 0 }));
+        let mut out = json();
+        find(vec!["paper".to_string()], false, false, config(), &mut out).unwrap();
+        let found: Vec<PathBuf> = serde_json::from_slice(&out.out.into_inner()).unwrap();
+        assert_eq!(found, vec![dir.join("repo/paper.pdf")]);
+    }
+
+    #[test]
+    fn test_wants_color() {
+        let term = || Some("xterm-256color".into());
+        assert!(wants_color(false, None, term(), true));
+        assert!(wants_color(false, Some("".into()), term(), true));
+        assert!(!wants_color(true, None, term(), true));
+        assert!(!wants_color(false, Some("1".into()), term(), true));
+        assert!(!wants_color(false, None, Some("dumb".into()), true));
+        assert!(!wants_color(false, None, None, true));
+        assert!(!wants_color(false, None, term(), false));
+    }
+
+    #[test]
+    fn test_read_config() {
+        let dir = temp_dir("read-config");
+        fs::write(dir.join("paperman.toml"), "repo_dir = \"/srv/papers\"\n").unwrap();
+        assert_eq!(read_config(dir.join("paperman.toml")).unwrap().repo_dir, PathBuf::from("/srv/papers"));
+        assert!(matches!(read_config(dir.join("missing.toml")), Err(Error::IoAt(..))));
+
+        fs::write(dir.join("broken.toml"), "repo_dir = ").unwrap();
+        assert!(matches!(read_config(dir.join("broken.toml")), Err(Error::Config(_))));
+
+        assert_eq!(config_path(Some(dir.join("other.toml"))).unwrap(), dir.join("other.toml"));
+    }
+
+    #[test]
+    fn test_validate_repo_dir() {
+        let dir = temp_dir("validate-repo-dir");
+        fs::create_dir_all(dir.join("repo")).unwrap();
+        fs::write(dir.join("file"), "content").unwrap();
+        validate_repo_dir(&dir.join("repo")).unwrap();
+        validate_repo_dir(&dir.join("new/repo")).unwrap();
+        assert!(!dir.join("new").exists());
+        assert!(matches!(validate_repo_dir(&dir.join("file")), Err(Error::NotDir(_))));
+        assert!(matches!(validate_repo_dir(&dir.join("file/repo")), Err(Error::NotDir(_))));
+    }
+
+    #[test]
+    fn test_select_repo() {
+        let dir = temp_dir("select-repo");
+        fs::write(dir.join("paperman.toml"), "repo_dir = \"/srv/papers\"\n\n[repos]\nreceipts = \"/srv/receipts\"\n").unwrap();
+        let mut config = read_config(dir.join("paperman.toml")).unwrap();
+        assert_eq!(config.repo_dir, PathBuf::from("/srv/papers"));
+        select_repo(&mut config, "receipts").unwrap();
+        assert_eq!(config.repo_dir, PathBuf::from("/srv/receipts"));
+        assert!(matches!(select_repo(&mut config, "code"), Err(Error::UnknownRepo(..))));
+
+        // Configs with a single repository keep working, and it is named
+        // "default"
+        fs::write(dir.join("single.toml"), "repo_dir = \"/srv/papers\"\n").unwrap();
+        let mut config = read_config(dir.join("single.toml")).unwrap();
+        assert_eq!(config.repos.keys().collect::<Vec<_>>(), vec!["default"]);
+        select_repo(&mut config, "default").unwrap();
+        assert_eq!(config.repo_dir, PathBuf::from("/srv/papers"));
+
+        // The default can be one of `repos`, and `repo_dir` can be left out
+        fs::write(dir.join("multi.toml"), "default_repo = \"work\"\n\n[repos]\nwork = \"/srv/work\"\nbooks = \"/srv/books\"\n").unwrap();
+        let mut config = read_config(dir.join("multi.toml")).unwrap();
+        assert_eq!(config.repo_dir, PathBuf::from("/srv/work"));
+        select_repo(&mut config, "books").unwrap();
+        assert_eq!(config.repo_dir, PathBuf::from("/srv/books"));
+        assert!(matches!(select_repo(&mut config, "default"), Err(Error::UnknownRepo(..))));
+
+        fs::write(dir.join("unknown.toml"), "repo_dir = \"/srv/papers\"\ndefault_repo = \"work\"\n").unwrap();
+        assert!(matches!(read_config(dir.join("unknown.toml")), Err(Error::UnknownRepo(..))));
+    }
+
+    #[test]
+    fn test_init() {
+        let dir = temp_dir("init");
+        let config_path = dir.join("config/paperman.toml");
+        init(config_path.clone(), Some(dir.join("papers")), Some(&dir), false, false, &mut printer()).unwrap();
+        assert_eq!(read_config(&config_path).unwrap().repo_dir, dir.join("papers"));
+        assert!(dir.join("papers").is_dir());
+
+        assert!(matches!(init(config_path.clone(), Some(dir.join("other")), Some(&dir), false, false, &mut printer()), Err(Error::Exists(_))));
+        init(config_path.clone(), Some(dir.join("other")), Some(&dir), true, false, &mut printer()).unwrap();
+        assert_eq!(read_config(&config_path).unwrap().repo_dir, dir.join("other"));
+
+        // Tilde paths are kept as they are, while ~user is refused
+        init(config_path.clone(), Some(PathBuf::from("~/papers")), Some(&dir), true, true, &mut printer()).unwrap();
+        assert!(init(config_path.clone(), Some(PathBuf::from("~someone/papers")), Some(&dir), true, false, &mut printer()).is_err());
+        assert_eq!(read_config(&config_path).unwrap().repo_dir, dir.join("other"));
+    }
+
+    #[test]
+    fn test_prompt_repo_dir() {
+        let dir = temp_dir("prompt_repo_dir");
+        fs::write(dir.join("file"), "").unwrap();
+        let ask = |input: &str| {
+            let mut output = Vec::new();
+            let result = prompt_repo_dir(&mut input.as_bytes(), &mut output, Some(&dir));
+            (result, String::from_utf8(output).unwrap())
+        };
+
+        let (result, output) = ask(&format!("{}\n", dir.join("papers").display()));
+        assert_eq!(result.unwrap(), dir.join("papers"));
+        assert_eq!(output, "Repository directory [~/papers]: ");
+        assert_eq!(ask("\n").0.unwrap(), PathBuf::from("~/papers"));
+
+        // Unusable answers are asked again
+        let (result, output) = ask(&format!("{}\n~no-such-user-paperman\n{}\n", dir.join("file/papers").display(), dir.display()));
+        assert_eq!(result.unwrap(), dir);
+        assert_eq!(output.matches("Repository directory").count(), 3);
+        assert!(ask("").0.is_err());
+    }
+
+    #[test]
+    fn test_completions() {
+        let dir = temp_dir("completions");
+        for (shell, name) in &[(Shell::Bash, "bash"), (Shell::Zsh, "zsh"), (Shell::Fish, "fish")] {
+            let path = dir.join(name);
+            completions(*shell, Some(path.clone()), &mut printer()).unwrap();
+            let script = fs::read_to_string(&path).unwrap();
+            assert!(script.contains("dedupe"));
+            assert!(script.contains("rename-on-collision"));
+        }
+    }
+
+    #[test]
+    fn test_config_command() {
+        let dir = temp_dir("config-command");
+        let path = dir.join("paperman.toml");
+        let set = |key: &str, value: &str| config_command(path.clone(), ConfigCommand::Set { key: key.to_string(), value: value.to_string() }, false, &mut printer());
+
+        // repo_dir has to come first for the file to be a valid config
+        assert!(matches!(set("symlink_style", "absolute"), Err(Error::NoRepo)));
+        set("repo_dir", "~/papers").unwrap();
+        set("symlink_style", "absolute").unwrap();
+        assert!(matches!(set("symlink_style", "sideways"), Err(Error::InvalidValue(..))));
+        assert!(matches!(set("repo", "/srv"), Err(Error::UnknownKey(..))));
+
+        let table = read_config_table(&path).unwrap();
+        assert_eq!(table["repo_dir"].as_str(), Some("~/papers"));
+        assert_eq!(table["symlink_style"].as_str(), Some("absolute"));
+
+        // Keys paperman does not know about are left alone
+        fs::write(&path, "repo_dir = \"/srv/papers\"\ncomment = \"mine\"\n").unwrap();
+        set("repo_dir", "/srv/other").unwrap();
+        let table = read_config_table(&path).unwrap();
+        assert_eq!(table["repo_dir"].as_str(), Some("/srv/other"));
+        assert_eq!(table["comment"].as_str(), Some("mine"));
+
+        // The old spelling is read and replaced as `symlink_style`
+        fs::write(&path, "repo_dir = \"/srv/papers\"\nsymlink_type = \"absolute\"\n").unwrap();
+        set("symlink_style", "relative").unwrap();
+        set("symlink_type", "absolute").unwrap();
+        let table = read_config_table(&path).unwrap();
+        assert!(!table.contains_key("symlink_type"));
+        assert_eq!(read_config(&path).unwrap().symlink_style, SymlinkStyle::Absolute);
+    }
+
+    #[test]
+    fn test_doctor() {
+        // Run `doctor` and return its result and the lines it printed
+        let doctor = |config_path: &Path| {
+            let mut printer = printer();
+            let result = doctor(Some(config_path.to_path_buf()), &mut printer);
+            let output = String::from_utf8(printer.out.into_inner()).unwrap();
+            (result, output.lines().map(String::from).collect::<Vec<_>>())
+        };
+        let has_row = |lines: &[String], prefix: &str| lines.iter().any(|line| line.starts_with(prefix));
+
+        let dir = temp_dir("doctor");
+        let config_path = dir.join("paperman.toml");
+        let (result, lines) = doctor(&config_path);
+        assert!(result.is_err());
+        assert!(has_row(&lines, "[FAIL] config file"));
+
+        fs::write(&config_path, format!("repo_dir = {:?}\n", dir.join("repo"))).unwrap();
+        let (result, lines) = doctor(&config_path);
+        result.unwrap();
+        assert!(has_row(&lines, "[OK]   repository directory"));
+        assert!(!dir.join("repo").exists());
+
+        fs::create_dir_all(dir.join("repo")).unwrap();
+        let (result, lines) = doctor(&config_path);
+        result.unwrap();
+        assert!(!lines.iter().any(|line| line.starts_with("[WARN]") || line.starts_with("[FAIL]")));
+        assert_eq!(fs::read_dir(dir.join("repo")).unwrap().count(), 0);
+
+        // Orphaned sidecars and shared inodes are only warned about, while
+        // a broken symlink fails
+        fs::write(dir.join("repo/a.pdf"), "a").unwrap();
+        fs::hard_link(dir.join("repo/a.pdf"), dir.join("repo/b.pdf")).unwrap();
+        fs::create_dir_all(dir.join("repo/.paperman/meta")).unwrap();
+        fs::write(dir.join("repo/.paperman/meta/gone.pdf.toml"), "format_version = 1\nsha256 = \"\"\n").unwrap();
+        let (result, lines) = doctor(&config_path);
+        result.unwrap();
+        assert!(has_row(&lines, "[WARN] 1 sidecar(s) without their file"));
+        assert!(has_row(&lines, "[WARN] 1 group(s) of repository files share an inode"));
+        assert!(!has_row(&lines, "[FAIL]"));
+
+        unix::fs::symlink("missing.pdf", dir.join("repo/broken.pdf")).unwrap();
+        let (result, lines) = doctor(&config_path);
+        assert!(matches!(result, Err(Error::Problems(1))));
+        assert!(has_row(&lines, "[FAIL] 1 broken symlink(s) in the repository"));
+        assert!(has_row(&lines, "[WARN] 1 sidecar(s) without their file"));
+
+        fs::write(&config_path, "repo_dir = ").unwrap();
+        assert!(doctor(&config_path).0.is_err());
+    }
+
+    #[test]
+    fn test_expand_tilde() {
+        let home = Some(Path::new("/home/alice"));
+        let expand_tilde = |path: &str| expand_tilde_with(path, home);
+        assert_eq!(expand_tilde("~"), Some("/home/alice".into()));
+        assert_eq!(expand_tilde("~/"), Some("/home/alice/".into()));
+        assert_eq!(expand_tilde("~/foo"), Some("/home/alice/foo".into()));
+        assert_eq!(expand_tilde("/foo/bar"), Some("/foo/bar".into()));
+        assert_eq!(expand_tilde("~no-such-user-paperman/foo/bar"), Some("~no-such-user-paperman/foo/bar".into()));
+        // `PathBuf` equality ignores trailing slashes, so compare strings
+        assert_eq!(expand_tilde("~/").unwrap().to_str(), Some("/home/alice/"));
+        assert_eq!(expand_tilde("~/foo/").unwrap().to_str(), Some("/home/alice/foo/"));
+
+        assert_eq!(expand_tilde_with("~/foo", Some(Path::new("/home/alice/"))).unwrap().to_str(), Some("/home/alice/foo"));
+
+        let expand_tilde = |path: &str| expand_tilde_with(path, Some(Path::new("/")));
+        assert_eq!(expand_tilde("~"), Some("/".into()));
+        assert_eq!(expand_tilde("~/"), Some("/".into()));
+        assert_eq!(expand_tilde("~/foo"), Some("/foo".into()));
+        assert_eq!(expand_tilde("/foo/bar"), Some("/foo/bar".into()));
+        assert_eq!(expand_tilde("~no-such-user-paperman/foo/bar"), Some("~no-such-user-paperman/foo/bar".into()));
+        assert_eq!(expand_tilde("~/").unwrap().to_str(), Some("/"));
+        assert_eq!(expand_tilde("~/foo/").unwrap().to_str(), Some("/foo/"));
+
+        // Other users are looked up in the user database
+        let root_home = uzers::get_user_by_name("root").map(|user| uzers::os::unix::UserExt::home_dir(&user).to_path_buf()).unwrap();
+        assert_eq!(expand_tilde("~root"), Some(root_home.clone()));
+        assert_eq!(expand_tilde("~root/foo"), Some(root_home.join("foo")));
+        assert_eq!(expand_tilde("~no-such-user-paperman/foo"), Some("~no-such-user-paperman/foo".into()));
+        // Without a home directory only `~` itself cannot be expanded
+        assert_eq!(expand_tilde_with("~/foo", None), None);
+        assert_eq!(expand_tilde_with("~root", None), Some(root_home));
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        std::env::set_var("PAPERMAN_TEST_DATA", "/home/alice/.local/share");
+        std::env::remove_var("PAPERMAN_TEST_UNSET");
+        assert_eq!(expand_env_vars("$PAPERMAN_TEST_DATA/papers").ok(), Some("/home/alice/.local/share/papers".into()));
+        assert_eq!(expand_env_vars("${PAPERMAN_TEST_DATA}/papers").ok(), Some("/home/alice/.local/share/papers".into()));
+        assert_eq!(expand_env_vars("${PAPERMAN_TEST_DATA}-old").ok(), Some("/home/alice/.local/share-old".into()));
+        assert_eq!(expand_env_vars("/srv/papers").ok(), Some("/srv/papers".into()));
+        assert_eq!(expand_env_vars("/srv/$/papers").ok(), Some("/srv/$/papers".into()));
+        assert!(matches!(expand_env_vars("$PAPERMAN_TEST_UNSET/papers"), Err(Error::UnsetVar(_))));
+        assert!(matches!(expand_env_vars("${PAPERMAN_TEST_UNSET}"), Err(Error::UnsetVar(_))));
+        assert!(expand_env_vars("${PAPERMAN_TEST_DATA/papers").is_err());
+
+        let dir = temp_dir("expand-env-vars");
+        fs::write(dir.join("paperman.toml"), "repo_dir = \"$PAPERMAN_TEST_DATA/papers\"\n").unwrap();
+        assert_eq!(read_config(dir.join("paperman.toml")).unwrap().repo_dir, PathBuf::from("/home/alice/.local/share/papers"));
+    }
+
+    #[test]
+    fn test_to_absolute() {
+        std::env::set_current_dir("/usr");
+        assert_eq!(to_absolute("foo/bar").ok(), Some("/usr/foo/bar".into()));
+        assert_eq!(to_absolute("/").ok(), Some("/".into()));
+        assert_eq!(to_absolute("/foo/bar").ok(), Some("/foo/bar".into()));
+
+        std::env::set_current_dir("/");
+        assert_eq!(to_absolute("foo/bar").ok(), Some("/foo/bar".into()));
+        assert_eq!(to_absolute("/").ok(), Some("/".into()));
+        assert_eq!(to_absolute("/foo/bar").ok(), Some("/foo/bar".into()));
+    }
+
+    #[test]
+    fn test_relative_path_from() {
+        assert_eq!(relative_path_from("/usr", "/usr/share").ok(), Some("share".into()));
+        assert_eq!(relative_path_from("/usr/", "/usr/share").ok(), Some("share".into()));
+        assert_eq!(relative_path_from("/usr/bin", "/usr/share").ok(), Some("../share".into()));
+        assert_eq!(relative_path_from("/a/b/c", "/a/d/e").ok(), Some("../../d/e".into()));
+        assert_eq!(relative_path_from("/a/b/c/d/e", "/a/x").ok(), Some("../../../../x".into()));
+        assert_eq!(relative_path_from("/a", "/a/b/c/d").ok(), Some("b/c/d".into()));
+        assert_eq!(relative_path_from("/a/b", "/c/d/e/f").ok(), Some("../../c/d/e/f".into()));
+        assert_eq!(relative_path_from("/a/b/../c", "/a/c/x").ok(), Some("x".into()));
+        assert_eq!(relative_path_from("/a/./b", "/a/b/x").ok(), Some("x".into()));
+        assert_eq!(relative_path_from("/usr/share", "/usr/share").ok(), Some(".".into()));
+        assert_eq!(relative_path_from("/usr/share/", "/usr/share").ok(), Some(".".into()));
+        assert_eq!(relative_path_from("/", "/").ok(), Some(".".into()));
+
+        // Symlinks are not resolved
+        let dir = temp_dir("relative_path_from");
+        fs::create_dir_all(dir.join("real")).unwrap();
+        unix::fs::symlink("real", dir.join("alias")).unwrap();
+        assert_eq!(relative_path_from(dir.join("alias"), dir.join("real")).ok(), Some("../real".into()));
+        assert_eq!(relative_path_from(dir.join("alias"), dir.join("alias")).ok(), Some(".".into()));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("/usr/bin/../share"), PathBuf::from("/usr/share"));
+        assert_eq!(normalize("/usr/./bin/../../etc"), PathBuf::from("/etc"));
+        assert_eq!(normalize("/usr/share"), PathBuf::from("/usr/share"));
+    }
+
+    #[test]
+    fn test_name_filter() {
+        assert!(NameFilter::Glob("*.pdf".into()).matches("paper.pdf"));
+        assert!(NameFilter::Substring("Deep".into(), false).matches("Deep Learning.pdf"));
+        assert!(!NameFilter::Substring("deep".into(), false).matches("Deep Learning.pdf"));
+        assert!(NameFilter::Substring("deep".into(), true).matches("Deep Learning.pdf"));
+        let re = NameFilter::Regex(regex::Regex::new(r"^\d+-.*\.pdf$").unwrap());
+        assert!(re.matches("2019-paper.pdf"));
+        assert!(!re.matches("paper-2019.pdf"));
+        assert!(NameFilter::Regex(regex::Regex::new("(?i)^paper").unwrap()).matches("PAPER.pdf"));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.pdf", "paper.pdf"));
+        assert!(glob_match("*.pdf", ".pdf"));
+        assert!(!glob_match("*.pdf", "paper.pdf.txt"));
+        assert!(glob_match("*a*b", "xxaxxab"));
+        assert!(glob_match("paper-?.pdf", "paper-1.pdf"));
+        assert!(!glob_match("paper-?.pdf", "paper-10.pdf"));
+        assert!(glob_match("[0-9]*", "2019-survey.pdf"));
+        assert!(!glob_match("[!0-9]*", "2019-survey.pdf"));
+        assert!(glob_match("[]]", "]"));
+        assert!(glob_match("[abc", "[abc"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn test_name_matches() {
+        let patterns = vec!["Deep".to_string(), "2019".to_string()];
+        assert!(name_matches("deep-learning.pdf", &patterns, false));
+        assert!(!name_matches("deep-learning.pdf", &patterns, true));
+        assert!(name_matches("DEEP-2019.pdf", &patterns, true));
+        assert!(!name_matches("survey.pdf", &patterns, false));
+    }
+
+    #[test]
+    fn test_expand_glob() {
+        let dir = temp_dir("expand-glob");
+        fs::create_dir_all(dir.join("2019")).unwrap();
+        fs::create_dir_all(dir.join("2020")).unwrap();
+        fs::write(dir.join("2019/a.pdf"), "").unwrap();
+        fs::write(dir.join("2019/b.txt"), "").unwrap();
+        fs::write(dir.join("2020/c.pdf"), "").unwrap();
+        fs::write(dir.join("2020/.hidden.pdf"), "").unwrap();
+
+        assert_eq!(expand_glob(&dir.join("*/*.pdf")), vec![dir.join("2019/a.pdf"), dir.join("2020/c.pdf")]);
+        assert_eq!(expand_glob(&dir.join("2019/*")), vec![dir.join("2019/a.pdf"), dir.join("2019/b.txt")]);
+        assert_eq!(expand_glob(&dir.join("2020/.*.pdf")), vec![dir.join("2020/.hidden.pdf")]);
+        assert!(expand_glob(&dir.join("*.djvu")).is_empty());
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01 00:00:00");
+        assert_eq!(format_time(UNIX_EPOCH + std::time::Duration::from_secs(951782400)), "2000-02-29 00:00:00");
+        assert_eq!(format_time(UNIX_EPOCH + std::time::Duration::from_secs(1700000000)), "2023-11-14 22:13:20");
+    }
+
+    fn repo_config(repo_dir: &Path) -> Config {
+        Config { repo_dir: repo_dir.to_path_buf(), ..Config::default() }
+    }
+
+    // Options for adding files in tests, which never ask about duplicates
+    fn add_options() -> AddOptions {
+        AddOptions { yes: true, ..AddOptions::default() }
+    }
+
+    // A printer that keeps what commands print in memory
+    fn printer() -> Printer<termcolor::NoColor<Vec<u8>>> {
+        Printer {
+            out: termcolor::NoColor::new(Vec::new()),
+            err: termcolor::NoColor::new(Vec::new()),
+            output: OutputMode::Human,
+            verbosity: Verbosity::Normal,
+            progress: false,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("paperman-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Run `f` without the privilege to override file permissions, as the
+    // owner of `dir`.  Tests run as root would pass regardless otherwise.
+    fn unprivileged<T: Send, F: FnOnce() -> T + Send>(dir: &Path, f: F) -> T {
+        if unsafe { libc::geteuid() } != 0 {
+            return f();
+        }
+        const NOBODY: u32 = 65534;
+        unix::fs::chown(dir, Some(NOBODY), Some(NOBODY)).unwrap();
+        // The filesystem IDs belong to the thread, and changing them away from
+        // root drops the capabilities to override permissions with them
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                unsafe {
+                    libc::setfsgid(NOBODY);
+                    libc::setfsuid(NOBODY);
+                }
+                f()
+            }).join().unwrap()
+        })
+    }
+
+    // Run `status` and return what it printed, as rows of cells between the
+    // header and the summary line
+    fn status_rows(dirs: Vec<PathBuf>, config: Config) -> (Result<(), Error>, Vec<Vec<String>>, String) {
+        let mut printer = printer();
+        let result = status(dirs, config, &mut printer);
+        let output = String::from_utf8(printer.out.into_inner()).unwrap();
+        let rows = output.lines().skip(1)
+            .take_while(|line| !line.is_empty())
+            .map(|line| line.split_whitespace().map(str::to_string).collect())
+            .collect();
+        (result, rows, output.lines().last().unwrap_or("").to_string())
+    }
+
+    #[test]
+    fn test_add_and_remove() {
+        let dir = temp_dir("add-and-remove");
+        let repo_dir = dir.join("repo");
+        let fp = dir.join("paper.pdf");
+        fs::write(&fp, "content").unwrap();
+
+        add(vec![fp.clone()], add_options(), repo_config(&repo_dir), &mut printer()).unwrap();
+        assert_eq!(file_type(&fp).unwrap(), FileType::Symlink);
+        assert_eq!(fs::read_to_string(repo_dir.join("paper.pdf")).unwrap(), "content");
+
+        // The restored file takes the name of the symlink
+        let renamed = dir.join("renamed.pdf");
+        fs::rename(&fp, &renamed).unwrap();
+        remove(vec![renamed.clone()], None, false, repo_config(&repo_dir), &mut printer()).unwrap();
+        assert_eq!(file_type(&renamed).unwrap(), FileType::File);
+        assert_eq!(fs::read_to_string(&renamed).unwrap(), "content");
+        assert!(!repo_dir.join("paper.pdf").exists());
+    }
+
+    #[test]
+    fn test_move_file_across_devices() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("move-across-devices");
+        let from = dir.join("from.pdf");
+        let to = dir.join("to.pdf");
+        fs::write(&from, "content").unwrap();
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o640)).unwrap();
+        let mtime = UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000);
+        File::options().write(true).open(&from).unwrap().set_modified(mtime).unwrap();
+
+        let exdev = |_: &Path, _: &Path| Err(io::Error::from_raw_os_error(libc::EXDEV));
+        move_file_with(&from, &to, exdev).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(&to).unwrap(), "content");
+        assert_eq!(fs::metadata(&to).unwrap().permissions().mode() & 0o777, 0o640);
+        assert_eq!(fs::metadata(&to).unwrap().modified().unwrap(), mtime);
+        assert!(dir.join(".to.pdf.paperman-tmp").symlink_metadata().is_err());
+
+        // A failed copy leaves the original alone
+        fs::rename(&to, &from).unwrap();
+        assert!(move_file_with(&from, &dir.join("missing/to.pdf"), exdev).is_err());
+        assert_eq!(fs::read_to_string(&from).unwrap(), "content");
+        assert!(!dir.join("missing").exists());
+
+        // Executable files stay executable
+        let script = dir.join("script.sh");
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o700)).unwrap();
+        move_file_with(&script, &dir.join("moved.sh"), exdev).unwrap();
+        assert_eq!(fs::metadata(dir.join("moved.sh")).unwrap().permissions().mode() & 0o777, 0o700);
+
+        // Read-only files can be copied by someone who cannot override
+        // permissions, as the owner of the file
+        unprivileged(&dir, || {
+            let from = dir.join("read-only.pdf");
+            fs::write(&from, "content").unwrap();
+            fs::set_permissions(&from, fs::Permissions::from_mode(0o444)).unwrap();
+            move_file_with(&from, &dir.join("moved.pdf"), exdev).unwrap();
+        });
+        assert_eq!(fs::metadata(dir.join("moved.pdf")).unwrap().permissions().mode() & 0o777, 0o444);
+        assert!(!dir.join("read-only.pdf").exists());
+
+        // Other errors are not handled by the fallback
+        let eacces = |_: &Path, _: &Path| Err(io::Error::from_raw_os_error(libc::EACCES));
+        assert!(move_file_with(&from, &to, eacces).is_err());
+        assert!(from.exists());
+        assert!(!to.exists());
+    }
+
+    #[test]
+    fn test_add_dry_run() {
+        let dir = temp_dir("add-dry-run");
+        let repo_dir = dir.join("repo");
+        let fp = dir.join("paper.pdf");
+        fs::write(&fp, "content").unwrap();
+
+        add(vec![fp.clone()], AddOptions { dry_run: true, ..add_options() }, repo_config(&repo_dir), &mut printer()).unwrap();
+        assert_eq!(file_type(&fp).unwrap(), FileType::File);
+        assert!(!repo_dir.exists());
+
+        // Rejected files are still reported as failures
+        assert!(add(vec![dir.clone()], AddOptions { dry_run: true, ..add_options() }, repo_config(&repo_dir), &mut printer()).is_err());
+    }
+
+    #[test]
+    fn test_add_conflict() {
+        let dir = temp_dir("add-conflict");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::create_dir_all(dir.join("c")).unwrap();
+        fs::write(dir.join("a/paper.pdf"), "a").unwrap();
+        fs::write(dir.join("b/paper.pdf"), "b").unwrap();
+        fs::write(dir.join("c/paper.pdf"), "c").unwrap();
+        let config = || repo_config(&repo_dir);
+
+        add(vec![dir.join("a/paper.pdf")], add_options(), config(), &mut printer()).unwrap();
+        assert!(add(vec![dir.join("b/paper.pdf")], add_options(), config(), &mut printer()).is_err());
+        add(vec![dir.join("b/paper.pdf")], AddOptions { conflict: Some(Conflict::Skip), ..add_options() }, config(), &mut printer()).unwrap();
+        assert_eq!(file_type(dir.join("b/paper.pdf")).unwrap(), FileType::File);
+
+        add(vec![dir.join("b/paper.pdf")], AddOptions { conflict: Some(Conflict::Rename), ..add_options() }, config(), &mut printer()).unwrap();
+        add(vec![dir.join("c/paper.pdf")], AddOptions { conflict: Some(Conflict::Rename), ..add_options() }, config(), &mut printer()).unwrap();
+        assert_eq!(fs::read_to_string(repo_dir.join("paper.pdf")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(repo_dir.join("paper-1.pdf")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(repo_dir.join("paper-2.pdf")).unwrap(), "c");
+        assert_eq!(fs::read_to_string(dir.join("c/paper.pdf")).unwrap(), "c");
+    }
+
+    #[test]
+    fn test_on_conflict_option() {
+        let conflict = |args: &[&str]| match Opt::from_iter_safe(args) {
+            Ok(Opt { cmd: Command::Add { conflict, .. }, .. }) => conflict,
+            _ => panic!("failed to parse {:?}", args),
+        };
+        assert_eq!(conflict(&["pm", "add", "a.pdf"]), None);
+        assert_eq!(conflict(&["pm", "add", "--on-conflict", "suffix", "a.pdf"]), Some(Conflict::Rename));
+        assert_eq!(conflict(&["pm", "add", "--on-conflict", "skip", "a.pdf"]), Some(Conflict::Skip));
+        // The old spelling keeps working
+        assert_eq!(conflict(&["pm", "add", "--conflict", "rename", "a.pdf"]), Some(Conflict::Rename));
+        assert!(Opt::from_iter_safe(&["pm", "add", "--on-conflict", "merge", "a.pdf"]).is_err());
+
+        let dir = temp_dir("on-conflict");
+        fs::create_dir_all(dir.join("repo")).unwrap();
+        fs::write(dir.join("repo/paper.pdf"), "old").unwrap();
+        fs::write(dir.join("paper.pdf"), "new").unwrap();
+        let mut outcome = AddOutcome::default();
+        add_files(vec![dir.join("paper.pdf")], &add_options(), &repo_config(&dir.join("repo")), &mut outcome, &mut printer()).unwrap();
+        assert_eq!(outcome.failed, vec![(dir.join("paper.pdf"), "name already exists in repository".to_string())]);
+        assert_eq!(fs::read_to_string(dir.join("repo/paper.pdf")).unwrap(), "old");
+    }
+
+    #[test]
+    fn test_add_same_basename() {
+        let dir = temp_dir("add-same-basename");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::write(dir.join("a/notes.txt"), "a").unwrap();
+        fs::write(dir.join("b/notes.txt"), "b").unwrap();
+        let config = || repo_config(&repo_dir);
+
+        // Without renaming the second file is reported and left alone
+        assert!(add(vec![dir.join("a/notes.txt"), dir.join("b/notes.txt")], add_options(), config(), &mut printer()).is_err());
+        assert_eq!(fs::read_to_string(repo_dir.join("notes.txt")).unwrap(), "a");
+        assert_eq!(file_type(dir.join("b/notes.txt")).unwrap(), FileType::File);
+
+        add(vec![dir.join("b/notes.txt")], AddOptions { conflict: Some(Conflict::Rename), ..add_options() }, config(), &mut printer()).unwrap();
+        assert_eq!(fs::read_to_string(repo_dir.join("notes.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(repo_dir.join("notes-1.txt")).unwrap(), "b");
+        assert_eq!(fs::read_link(dir.join("b/notes.txt")).unwrap(), PathBuf::from("../repo/notes-1.txt"));
+        assert_eq!(fs::read_to_string(dir.join("a/notes.txt")).unwrap(), "a");
+    }
+
+    #[test]
+    fn test_journal() {
+        let dir = temp_dir("journal");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(dir.join("paper.pdf"), "content").unwrap();
+        let config = || repo_config(&repo_dir);
+        assert!(read_journal(&repo_dir).unwrap().is_empty());
+
+        add(vec![dir.join("paper.pdf")], add_options(), config(), &mut printer()).unwrap();
+        rename(PathBuf::from("paper.pdf"), "renamed.pdf".to_string(), Some(dir.clone()), false, config(), &mut printer()).unwrap();
+        // Nothing happens in a dry run
+        remove(vec![dir.join("paper.pdf")], None, true, config(), &mut printer()).unwrap();
+        assert_eq!(file_type(dir.join("paper.pdf")).unwrap(), FileType::Symlink);
+        remove(vec![dir.join("paper.pdf")], None, false, config(), &mut printer()).unwrap();
+        let entries: Vec<_> = read_journal(&repo_dir).unwrap().into_iter().map(|e| (e.op, e.source, e.repo_path)).collect();
+        assert_eq!(entries, vec![
+            ("add".to_string(), dir.join("paper.pdf"), repo_dir.join("paper.pdf")),
+            ("rename".to_string(), repo_dir.join("paper.pdf"), repo_dir.join("renamed.pdf")),
+            ("remove".to_string(), dir.join("paper.pdf"), repo_dir.join("renamed.pdf")),
+        ]);
+        // Only the last entries about the file are shown, oldest first
+        let mut out = printer();
+        log(Some(2), Some("renamed.pdf".to_string()), config(), &mut out).unwrap();
+        let output = String::from_utf8(out.out.into_inner()).unwrap();
+        let lines: Vec<_> = output.lines().map(|line| line.split_once("  ").unwrap().1.to_string()).collect();
+        assert_eq!(lines, vec!["rename  paper.pdf -> renamed.pdf".to_string(), format!("remove  renamed.pdf -> {}", dir.join("paper.pdf").display())]);
+        let mut out = printer();
+        log(Some(1), None, config(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out.out.into_inner()).unwrap().lines().count(), 1);
+
+        // Odd characters in paths survive the round trip, and partial lines
+        // are ignored
+        journal(&repo_dir, "add", Path::new("/a\tb\\n\nc"), &repo_dir.join("x.pdf"), &mut printer()).unwrap();
+        let mut file = File::options().append(true).open(repo_dir.join(META_DIR).join(JOURNAL)).unwrap();
+        file.write_all(b"2020-01-01T00:00:00Z\tadd").unwrap();
+        let entries = read_journal(&repo_dir).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[3].source, PathBuf::from("/a\tb\\n\nc"));
+    }
+
+    #[test]
+    fn test_undo() {
+        let dir = temp_dir("undo");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        for name in &["a.pdf", "b.pdf", "c.pdf"] {
+            fs::write(dir.join(name), *name).unwrap();
+        }
+        let config = || repo_config(&repo_dir);
+        assert!(matches!(undo(1, false, false, config(), &mut printer()), Err(Error::NothingToUndo)));
+        add(vec![dir.join("a.pdf")], add_options(), config(), &mut printer()).unwrap();
+        add(vec![dir.join("b.pdf")], add_options(), config(), &mut printer()).unwrap();
+        add(vec![dir.join("c.pdf")], AddOptions { mode: AddMode::Copy, ..add_options() }, config(), &mut printer()).unwrap();
+
+        // A copy only loses its repository entry
+        undo(1, false, false, config(), &mut printer()).unwrap();
+        assert!(!repo_dir.join("c.pdf").exists());
+        assert_eq!(fs::read_to_string(dir.join("c.pdf")).unwrap(), "c.pdf");
+
+        // A diverged symlink is left alone unless forced, without keeping
+        // the other steps from being undone
+        fs::remove_file(dir.join("b.pdf")).unwrap();
+        fs::write(dir.join("b.pdf"), "mine").unwrap();
+        assert!(matches!(undo(2, false, false, config(), &mut printer()), Err(Error::NotUndone(1))));
+        assert!(repo_dir.join("b.pdf").is_file());
+        assert_eq!(fs::read_to_string(dir.join("a.pdf")).unwrap(), "a.pdf");
+        assert!(!repo_dir.join("a.pdf").exists());
+        // and even then the file that has taken its place is kept
+        undo(1, true, false, config(), &mut printer()).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("b.pdf")).unwrap(), "mine");
+        assert_eq!(fs::read_to_string(dir.join("b-1.pdf")).unwrap(), "b.pdf");
+        assert!(!repo_dir.join("b.pdf").exists());
+        assert!(matches!(undo(1, false, false, config(), &mut printer()), Err(Error::NothingToUndo)));
+
+        journal(&repo_dir, "rename", &repo_dir.join("x.pdf"), &repo_dir.join("y.pdf"), &mut printer()).unwrap();
+        assert!(matches!(undo(1, false, false, config(), &mut printer()), Err(Error::NotUndoable(_))));
+    }
+
+    #[test]
+    fn test_add_link_dir() {
+        let dir = temp_dir("add_link_dir");
+        let repo_dir = dir.join("repo");
+        let link_dir = dir.join("links");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::create_dir_all(&link_dir).unwrap();
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs/paper.pdf"), "content").unwrap();
+        let config = || repo_config(&repo_dir);
+
+        add(vec![dir.join("docs/paper.pdf")], AddOptions { link_dir: Some(link_dir.clone()), ..add_options() }, config(), &mut printer()).unwrap();
+        assert!(dir.join("docs/paper.pdf").symlink_metadata().is_err());
+        assert_eq!(fs::read_link(link_dir.join("paper.pdf")).unwrap(), PathBuf::from("../repo/paper.pdf"));
+        assert_eq!(fs::read_to_string(link_dir.join("paper.pdf")).unwrap(), "content");
+
+        // An existing file in the link directory stops the file from moving
+        fs::write(dir.join("docs/paper.pdf"), "other").unwrap();
+        assert!(matches!(add(vec![dir.join("docs/paper.pdf")], AddOptions { conflict: Some(Conflict::Rename), link_dir: Some(link_dir.clone()), ..add_options() }, config(), &mut printer()), Err(Error::NotAdded(1))));
+        assert_eq!(fs::read_to_string(dir.join("docs/paper.pdf")).unwrap(), "other");
+        assert!(matches!(add(vec![dir.join("docs/paper.pdf")], AddOptions { mode: AddMode::Copy, link_dir: Some(link_dir.clone()), ..add_options() }, config(), &mut printer()), Err(Error::Path(_))));
+    }
+
+    #[test]
+    fn test_add_duplicate_content() {
+        let dir = temp_dir("add-duplicate-content");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        for name in &["paper.pdf", "copy.pdf", "again.pdf"] {
+            fs::write(dir.join(name), "content").unwrap();
+        }
+        let config = || repo_config(&repo_dir);
+        assert!(recorded_digests(&repo_dir).unwrap().is_empty());
+
+        add(vec![dir.join("paper.pdf")], add_options(), config(), &mut printer()).unwrap();
+        let digests = recorded_digests(&repo_dir).unwrap();
+        assert_eq!(digests.get(&sha256_file(&repo_dir.join("paper.pdf")).unwrap()), Some(&repo_dir.join("paper.pdf")));
+
+        // The digest is looked up in the index rather than in the sidecars,
+        // which is no file of the repository itself
+        let digest = sha256_file(&repo_dir.join("paper.pdf")).unwrap();
+        let index = read_index(&repo_dir).unwrap().unwrap();
+        assert_eq!(index.digests[&digest], BTreeSet::from([PathBuf::from("paper.pdf")]));
+        assert_eq!(repo_files(&repo_dir).unwrap(), vec![repo_dir.join("paper.pdf")]);
+        fs::remove_dir_all(repo_dir.join(META_DIR)).unwrap();
+        assert_eq!(recorded_digests(&repo_dir).unwrap()[&digest], repo_dir.join("paper.pdf"));
+        // Without an index, the sidecars are read instead
+        record_metadata(&repo_dir.join("paper.pdf"), dir.join("paper.pdf"), false, &repo_dir).unwrap();
+        fs::remove_file(repo_dir.join(DIGEST_INDEX)).unwrap();
+        assert_eq!(recorded_digests(&repo_dir).unwrap()[&digest], repo_dir.join("paper.pdf"));
+
+        // A dry run neither reads the files nor asks about them
+        let mut out = printer();
+        let mut outcome = AddOutcome::default();
+        let options = AddOptions { interactive: true, dry_run: true, ..AddOptions::default() };
+        add_files(vec![dir.join("copy.pdf")], &options, &config(), &mut outcome, &mut out).unwrap();
+        assert_eq!(outcome.added, vec![(dir.join("copy.pdf"), repo_dir.join("copy.pdf"))]);
+        assert!(out.err.get_ref().is_empty());
+
+        // Duplicates are still added when told to go ahead
+        add(vec![dir.join("copy.pdf"), dir.join("again.pdf")], add_options(), config(), &mut printer()).unwrap();
+        assert!(repo_dir.join("copy.pdf").is_file());
+        assert!(repo_dir.join("again.pdf").is_file());
+        assert_eq!(recorded_digests(&repo_dir).unwrap().len(), 1);
+        forget_metadata(&[repo_dir.join("paper.pdf")], &repo_dir).unwrap();
+        let index = read_index(&repo_dir).unwrap().unwrap();
+        assert_eq!(index.digests[&digest], BTreeSet::from([PathBuf::from("again.pdf"), PathBuf::from("copy.pdf")]));
+    }
+
+    #[test]
+    fn test_add_outcome() {
+        let dir = temp_dir("add-outcome");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("new.pdf"), "content").unwrap();
+        fs::write(dir.join("taken.pdf"), "content").unwrap();
+        fs::write(repo_dir.join("taken.pdf"), "other").unwrap();
+        let config = repo_config(&repo_dir);
+
+        let mut outcome = AddOutcome::default();
+        add_files(vec![dir.join("new.pdf"), dir.join("taken.pdf"), dir.join("docs")], &AddOptions { conflict: Some(Conflict::Skip), ..add_options() }, &config, &mut outcome, &mut printer()).unwrap();
+        assert_eq!(outcome.added, vec![(dir.join("new.pdf"), repo_dir.join("new.pdf"))]);
+        assert_eq!(outcome.skipped, vec![dir.join("taken.pdf")]);
+        assert_eq!(outcome.failed.iter().map(|(fp, _)| fp.clone()).collect::<Vec<_>>(), vec![dir.join("docs")]);
+
+        // A file that disappears aborts the run, but what was done is kept
+        let mut outcome = AddOutcome::default();
+        assert!(add_files(vec![dir.join("missing.pdf")], &add_options(), &config, &mut outcome, &mut printer()).is_err());
+        assert!(add(vec![dir.join("missing.pdf")], add_options(), config, &mut printer()).is_err());
+    }
+
+    #[test]
+    fn test_confirm_targets() {
+        let mut targets = vec![
+            (PathBuf::from("/docs/a.pdf"), PathBuf::from("a.pdf")),
+            (PathBuf::from("/docs/b.pdf"), PathBuf::from("b.pdf")),
+        ];
+        let prompts = std::cell::RefCell::new(Vec::new());
+        let declined = confirm_targets_with(&mut targets, Path::new("/repo"), |prompt| {
+            prompts.borrow_mut().push(prompt.to_string());
+            Ok(prompt.contains("a.pdf"))
+        }).unwrap();
+        assert_eq!(prompts.into_inner(), vec!["Add /docs/a.pdf -> /repo/a.pdf?", "Add /docs/b.pdf -> /repo/b.pdf?"]);
+        assert_eq!(targets, vec![(PathBuf::from("/docs/a.pdf"), PathBuf::from("a.pdf"))]);
+        assert_eq!(declined, vec![PathBuf::from("/docs/b.pdf")]);
+    }
+
+    #[test]
+    fn test_add_copy() {
+        let dir = temp_dir("add-copy");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("kept.pdf"), "kept").unwrap();
+        fs::write(dir.join("linked.pdf"), "linked").unwrap();
+        let config = || repo_config(&repo_dir);
+
+        let mtime = UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000);
+        File::options().write(true).open(dir.join("kept.pdf")).unwrap().set_modified(mtime).unwrap();
+
+        add(vec![dir.join("kept.pdf")], AddOptions { mode: AddMode::Copy, ..add_options() }, config(), &mut printer()).unwrap();
+        assert_eq!(file_type(dir.join("kept.pdf")).unwrap(), FileType::File);
+        assert_eq!(fs::metadata(repo_dir.join("kept.pdf")).unwrap().modified().unwrap(), mtime);
+        assert_eq!(fs::read(dir.join("kept.pdf")).unwrap(), fs::read(repo_dir.join("kept.pdf")).unwrap());
+        assert!(is_copy_mode(&repo_dir.join("kept.pdf"), &repo_dir));
+
+        // Copies have no symlink but are not orphans either
+        let files = repo_files(&repo_dir).unwrap();
+        assert_eq!(classify(&[], &[], &files, &repo_dir)[0].0, Health::CopyMode);
+        assert_eq!(status_rows(vec![dir.join("elsewhere")], config()).1, vec![vec!["copy-mode".to_string(), repo_dir.join("kept.pdf").display().to_string(), "-".to_string()]]);
+        gc(vec![dir.clone()], None, true, false, config(), &mut printer()).unwrap();
+        assert!(repo_dir.join("kept.pdf").exists());
+
+        // Collisions concern the copy in the repository only
+        fs::write(dir.join("kept.pdf"), "changed").unwrap();
+        assert!(add(vec![dir.join("kept.pdf")], AddOptions { mode: AddMode::Copy, ..add_options() }, config(), &mut printer()).is_err());
+        add(vec![dir.join("kept.pdf")], AddOptions { conflict: Some(Conflict::Overwrite), mode: AddMode::Copy, ..add_options() }, config(), &mut printer()).unwrap();
+        assert_eq!(fs::read_to_string(repo_dir.join("kept.pdf")).unwrap(), "changed");
+        assert_eq!(fs::read_to_string(dir.join("kept.pdf")).unwrap(), "changed");
+
+        add(vec![dir.join("linked.pdf")], AddOptions { mode: AddMode::CopyAndLink, ..add_options() }, config(), &mut printer()).unwrap();
+        assert_eq!(fs::read_link(dir.join("linked.pdf")).unwrap(), PathBuf::from("repo/linked.pdf"));
+        assert_eq!(fs::read_to_string(repo_dir.join("linked.pdf")).unwrap(), "linked");
+        assert!(!is_copy_mode(&repo_dir.join("linked.pdf"), &repo_dir));
+
+        // A failed copy leaves neither a partial file nor the temporary one
+        fs::create_dir_all(repo_dir.join("dir.pdf")).unwrap();
+        fs::write(dir.join("dir.pdf"), "content").unwrap();
+        let mut outcome = AddOutcome::default();
+        add_files(vec![dir.join("dir.pdf")], &AddOptions { conflict: Some(Conflict::Overwrite), mode: AddMode::Copy, ..add_options() }, &config(), &mut outcome, &mut printer()).unwrap();
+        assert!(outcome.failed[0].1.starts_with("failed to copy: "));
+        assert!(repo_dir.join("dir.pdf").is_dir());
+        assert!(repo_dir.join(".dir.pdf.paperman-tmp").symlink_metadata().is_err());
+        assert_eq!(fs::read_to_string(dir.join("dir.pdf")).unwrap(), "content");
+    }
+
+    #[test]
+    fn test_add_hard_link() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = temp_dir("add-hard-link");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs/paper.pdf"), "content").unwrap();
+        fs::write(dir.join("docs/orphan.pdf"), "content").unwrap();
+        let config = || Config { link_type: LinkType::Hard, ..repo_config(&repo_dir) };
+
+        add(vec![dir.join("docs/paper.pdf")], AddOptions { mode: config().link_type.add_mode(), ..add_options() }, config(), &mut printer()).unwrap();
+        assert_eq!(file_type(dir.join("docs/paper.pdf")).unwrap(), FileType::File);
+        assert_eq!(fs::metadata(dir.join("docs/paper.pdf")).unwrap().ino(), fs::metadata(repo_dir.join("paper.pdf")).unwrap().ino());
+        assert_eq!(find_hard_links(&dir, &repo_dir).unwrap(), vec![(dir.join("docs/paper.pdf"), repo_dir.join("paper.pdf"))]);
+        let (result, rows, _) = status_rows(vec![dir.join("docs")], config());
+        result.unwrap();
+        assert_eq!(rows, vec![vec!["hard-link".to_string(), repo_dir.join("paper.pdf").display().to_string(), dir.join("docs/paper.pdf").display().to_string()]]);
+
+        // gc leaves hard linked files alone even where it does not look
+        gc(vec![dir.join("elsewhere")], None, true, false, config(), &mut printer()).unwrap();
+        assert!(repo_dir.join("paper.pdf").exists());
+
+        assert!(matches!(remove(vec![dir.join("docs/paper.pdf"), dir.join("docs/orphan.pdf")], None, false, config(), &mut printer()), Err(Error::NotRemoved(1))));
+        assert!(!repo_dir.join("paper.pdf").exists());
+        assert!(!sidecar_path(&repo_dir.join("paper.pdf"), &repo_dir).exists());
+        assert_eq!(fs::read_to_string(dir.join("docs/paper.pdf")).unwrap(), "content");
+        assert!(dir.join("docs/orphan.pdf").exists());
+    }
+
+    #[test]
+    fn test_add_rejects_directory_and_symlink() {
+        let dir = temp_dir("add-rejects");
+        let repo_dir = dir.join("repo");
+        let docs = dir.join("docs");
+        fs::create_dir_all(&docs).unwrap();
+        fs::write(docs.join("inside.pdf"), "content").unwrap();
+        fs::write(dir.join("real.pdf"), "content").unwrap();
+        unix::fs::symlink("real.pdf", dir.join("link.pdf")).unwrap();
+
+        assert!(matches!(add(vec![docs.clone(), dir.join("link.pdf")], add_options(), repo_config(&repo_dir), &mut printer()), Err(Error::NotAdded(2))));
+        assert_eq!(file_type(&docs).unwrap(), FileType::Dir);
+        assert_eq!(file_type(docs.join("inside.pdf")).unwrap(), FileType::File);
+        assert_eq!(fs::read_link(dir.join("link.pdf")).unwrap(), PathBuf::from("real.pdf"));
+        assert_eq!(file_type(dir.join("real.pdf")).unwrap(), FileType::File);
+        assert!(!repo_dir.exists());
+    }
+
+    #[test]
+    fn test_numbered_path() {
+        assert_eq!(numbered_path(Path::new("/repo/paper.pdf"), 1), PathBuf::from("/repo/paper-1.pdf"));
+        assert_eq!(numbered_path(Path::new("/repo/paper.tar.gz"), 2), PathBuf::from("/repo/paper.tar-2.gz"));
+        assert_eq!(numbered_path(Path::new("/repo/README"), 3), PathBuf::from("/repo/README-3"));
+    }
+
+    #[test]
+    fn test_add_already_managed() {
+        let dir = temp_dir("add-already-managed");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(dir.join("paper.pdf"), "content").unwrap();
+        let config = || repo_config(&repo_dir);
+        add(vec![dir.join("paper.pdf")], add_options(), config(), &mut printer()).unwrap();
+        // Also through a symlink to the repository itself
+        unix::fs::symlink("repo", dir.join("alias")).unwrap();
+        unix::fs::symlink("alias/paper.pdf", dir.join("other.pdf")).unwrap();
+
+        let mut outcome = AddOutcome::default();
+        add_files(vec![dir.join("paper.pdf"), dir.join("other.pdf")], &add_options(), &config(), &mut outcome, &mut printer()).unwrap();
+        let reasons: Vec<_> = outcome.failed.iter().map(|(_, reason)| reason.as_str()).collect();
+        assert_eq!(reasons, vec!["file is already managed", "file is already managed"]);
+        assert!(is_managed(&dir.join("paper.pdf"), &repo_dir).unwrap());
+        fs::write(dir.join("real.pdf"), "content").unwrap();
+        unix::fs::symlink("real.pdf", dir.join("unmanaged.pdf")).unwrap();
+        assert!(!is_managed(&dir.join("unmanaged.pdf"), &repo_dir).unwrap());
+    }
+
+    #[test]
+    fn test_add_force() {
+        let dir = temp_dir("add-force");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::create_dir_all(dir.join("other")).unwrap();
+        fs::write(repo_dir.join("paper.pdf"), "old").unwrap();
+        fs::write(dir.join("paper.pdf"), "new").unwrap();
+        let config = || repo_config(&repo_dir);
+        let add_forced = |fp: &Path| {
+            let args = vec![
+                PathBuf::from("pm"),
+                PathBuf::from("--config"), dir.join("missing.toml"),
+                PathBuf::from("--repo-dir"), repo_dir.clone(),
+                PathBuf::from("add"), PathBuf::from("--force"), fp.to_path_buf(),
+            ];
+            run(Opt::from_iter(args))
+        };
+
+        assert!(matches!(add(vec![dir.join("paper.pdf")], add_options(), config(), &mut printer()), Err(Error::NotAdded(1))));
+        assert_eq!(fs::read_to_string(repo_dir.join("paper.pdf")).unwrap(), "old");
+        add_forced(&dir.join("paper.pdf")).unwrap();
+        assert_eq!(fs::read_to_string(repo_dir.join("paper.pdf")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(dir.join("paper.pdf")).unwrap(), "new");
+
+        // A file whose link is still in place is not replaced
+        fs::write(dir.join("docs/linked.pdf"), "linked").unwrap();
+        fs::write(dir.join("other/linked.pdf"), "other").unwrap();
+        add(vec![dir.join("docs/linked.pdf")], add_options(), config(), &mut printer()).unwrap();
+        let mut outcome = AddOutcome::default();
+        add_files(vec![dir.join("other/linked.pdf")], &AddOptions { conflict: Some(Conflict::Overwrite), ..add_options() }, &config(), &mut outcome, &mut printer()).unwrap();
+        assert_eq!(outcome.failed, vec![(dir.join("other/linked.pdf"), format!("the file it would replace is still linked from {}", dir.join("docs/linked.pdf").display()))]);
+        assert_eq!(fs::read_to_string(dir.join("docs/linked.pdf")).unwrap(), "linked");
+        assert_eq!(file_type(dir.join("other/linked.pdf")).unwrap(), FileType::File);
+
+        assert!(Opt::from_iter_safe(&["pm", "add", "--force", "--on-conflict", "skip", "a.pdf"]).is_err());
+        assert!(Opt::from_iter_safe(&["pm", "add", "--force", "--rename-on-collision", "a.pdf"]).is_err());
+    }
+
+    #[test]
+    fn test_add_inside_repo() {
+        let dir = temp_dir("add-inside-repo");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(repo_dir.join("sub")).unwrap();
+        fs::write(repo_dir.join("paper.pdf"), "content").unwrap();
+        fs::write(repo_dir.join("sub/other.pdf"), "other").unwrap();
+        unix::fs::symlink("repo", dir.join("alias")).unwrap();
+        let config = || repo_config(&repo_dir);
+
+        let mut outcome = AddOutcome::default();
+        let files = vec![repo_dir.join("paper.pdf"), repo_dir.join("sub/other.pdf"), dir.join("alias/paper.pdf")];
+        add_files(files, &add_options(), &config(), &mut outcome, &mut printer()).unwrap();
+        let reasons: Vec<_> = outcome.failed.iter().map(|(_, reason)| reason.as_str()).collect();
+        assert_eq!(reasons, vec!["already inside repo"; 3]);
+        assert!(outcome.added.is_empty());
+        assert_eq!(file_type(repo_dir.join("paper.pdf")).unwrap(), FileType::File);
+        assert_eq!(fs::read_to_string(repo_dir.join("paper.pdf")).unwrap(), "content");
+        assert_eq!(fs::read_to_string(repo_dir.join("sub/other.pdf")).unwrap(), "other");
+        assert!(repo_dir.join("other.pdf").symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn test_add_recursive() {
+        let dir = temp_dir("add-recursive");
+        let repo_dir = dir.join("repo");
+        let papers = dir.join("papers");
+        fs::create_dir_all(papers.join("2019")).unwrap();
+        fs::create_dir_all(papers.join("2020")).unwrap();
+        fs::write(papers.join("a.pdf"), "a").unwrap();
+        fs::write(papers.join("2019/b.pdf"), "b").unwrap();
+        fs::write(papers.join("2020/b.pdf"), "b2").unwrap();
+        unix::fs::symlink("a.pdf", papers.join("link.pdf")).unwrap();
+        // A cycle that following directory symlinks would never get out of
+        unix::fs::symlink("..", papers.join("2019/up")).unwrap();
+
+        // Directories are refused unless requested
+        assert!(add(vec![papers.clone()], add_options(), repo_config(&repo_dir), &mut printer()).is_err());
+        assert!(!repo_dir.exists());
+
+        // Nested symlinks are reported but do not stop the others
+        assert!(matches!(add(vec![papers.clone()], AddOptions { recursive: true, ..add_options() }, repo_config(&repo_dir), &mut printer()), Err(Error::NotAdded(3))));
+        assert_eq!(fs::read_link(papers.join("2019/up")).unwrap(), PathBuf::from(".."));
+        assert_eq!(fs::read_to_string(repo_dir.join("a.pdf")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(repo_dir.join("b.pdf")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(papers.join("2019/b.pdf")).unwrap(), "b");
+        assert_eq!(file_type(papers.join("2019/b.pdf")).unwrap(), FileType::Symlink);
+
+        // Flattening goes through collision handling
+        assert_eq!(file_type(papers.join("2020/b.pdf")).unwrap(), FileType::File);
+        add(vec![papers.join("2020")], AddOptions { recursive: true, conflict: Some(Conflict::Rename), ..add_options() }, repo_config(&repo_dir), &mut printer()).unwrap();
+        assert_eq!(fs::read_to_string(repo_dir.join("b-1.pdf")).unwrap(), "b2");
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = temp_dir("import");
+        let repo_dir = dir.join("repo");
+        let scans = dir.join("scans");
+        fs::create_dir_all(scans.join("2020/extra")).unwrap();
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(scans.join("a.pdf"), "a").unwrap();
+        fs::write(scans.join("b.DJVU"), "b").unwrap();
+        fs::write(scans.join("notes.txt"), "notes").unwrap();
+        fs::write(scans.join("empty.pdf"), "").unwrap();
+        fs::write(scans.join("2020/c.pdf"), "c").unwrap();
+        fs::write(scans.join("2020/extra/d.pdf"), "d").unwrap();
+        fs::write(scans.join("2020/taken.pdf"), "new").unwrap();
+        fs::write(repo_dir.join("taken.pdf"), "old").unwrap();
+        unix::fs::symlink("a.pdf", scans.join("link.pdf")).unwrap();
+
+        // A failing file does not stop the others
+        assert!(import(scans.clone(), Some(2), vec!["pdf".to_string(), ".djvu".to_string()], false, AddOptions::default(), repo_config(&repo_dir), &mut printer()).is_err());
+        for name in &["a.pdf", "b.DJVU", "c.pdf"] {
+            assert_eq!(file_type(repo_dir.join(name)).unwrap(), FileType::File);
+        }
+        assert_eq!(file_type(scans.join("a.pdf")).unwrap(), FileType::Symlink);
+        assert_eq!(fs::read_to_string(repo_dir.join("taken.pdf")).unwrap(), "old");
+        assert_eq!(file_type(scans.join("2020/taken.pdf")).unwrap(), FileType::File);
+        assert_eq!(file_type(scans.join("notes.txt")).unwrap(), FileType::File);
+        assert_eq!(file_type(scans.join("empty.pdf")).unwrap(), FileType::File);
+        assert_eq!(file_type(scans.join("2020/extra/d.pdf")).unwrap(), FileType::File);
+    }
+
+    #[test]
+    fn test_walk_max_depth() {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("walk-max-depth");
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join("1"), "").unwrap();
+        fs::write(dir.join("a/2"), "").unwrap();
+        fs::write(dir.join("a/b/3"), "").unwrap();
+        let names = |max_depth| walk_max_depth(&dir, max_depth).unwrap().into_iter().map(|(path, _)| path).collect::<Vec<_>>();
+        assert_eq!(names(Some(1)), vec![dir.join("1")]);
+        assert_eq!(names(Some(2)), vec![dir.join("1"), dir.join("a/2")]);
+        assert_eq!(names(None), vec![dir.join("1"), dir.join("a/2"), dir.join("a/b/3")]);
+
+        // FIFOs are left out and an unreadable directory is skipped over,
+        // unless running as root, which can read it anyway
+        let fifo = std::ffi::CString::new(dir.join("a/fifo").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        assert_eq!(file_type(dir.join("a/fifo")).unwrap(), FileType::Other);
+        fs::set_permissions(dir.join("a/b"), fs::Permissions::from_mode(0o000)).unwrap();
+        let readable = fs::read_dir(dir.join("a/b")).is_ok();
+        let names = names(None);
+        fs::set_permissions(dir.join("a/b"), fs::Permissions::from_mode(0o755)).unwrap();
+        if readable {
+            assert_eq!(names, vec![dir.join("1"), dir.join("a/2"), dir.join("a/b/3")]);
+        }
+        else {
+            assert_eq!(names, vec![dir.join("1"), dir.join("a/2")]);
+        }
+    }
+
+    #[test]
+    fn test_add_symlink_style() {
+        let dir = temp_dir("add-symlink-style");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs/relative.pdf"), "r").unwrap();
+        fs::write(dir.join("docs/absolute.pdf"), "a").unwrap();
+
+        add(vec![dir.join("docs/relative.pdf")], add_options(), Config { symlink_style: SymlinkStyle::Relative, ..repo_config(&repo_dir) }, &mut printer()).unwrap();
+        assert_eq!(fs::read_link(dir.join("docs/relative.pdf")).unwrap(), PathBuf::from("../repo/relative.pdf"));
+
+        add(vec![dir.join("docs/absolute.pdf")], add_options(), Config { symlink_style: SymlinkStyle::Absolute, ..repo_config(&repo_dir) }, &mut printer()).unwrap();
+        let target = fs::read_link(dir.join("docs/absolute.pdf")).unwrap();
+        assert_eq!(target, repo_dir.canonicalize().unwrap().join("absolute.pdf"));
+        assert_eq!(fs::read_to_string(dir.join("docs/absolute.pdf")).unwrap(), "a");
+
+        // Both kinds of link are recognized as pointing into the repository
+        assert_eq!(find_links(dir.join("docs"), &repo_dir).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_read_config_symlink_style() {
+        let dir = temp_dir("read-config-symlink-style");
+        fs::write(dir.join("paperman.toml"), "repo_dir = \"/srv/papers\"\nsymlink_style = \"absolute\"\n").unwrap();
+        assert_eq!(read_config(dir.join("paperman.toml")).unwrap().symlink_style, SymlinkStyle::Absolute);
+        fs::write(dir.join("paperman.toml"), "repo_dir = \"/srv/papers\"\n").unwrap();
+        assert_eq!(read_config(dir.join("paperman.toml")).unwrap().symlink_style, SymlinkStyle::Relative);
+        fs::write(dir.join("paperman.toml"), "repo_dir = \"/srv/papers\"\nsymlink_type = \"absolute\"\n").unwrap();
+        assert_eq!(read_config(dir.join("paperman.toml")).unwrap().symlink_style, SymlinkStyle::Absolute);
+    }
+
+    #[test]
+    fn test_move_and_link_rolls_back() {
+        let dir = temp_dir("move-and-link-rollback");
+        let fp = dir.join("paper.pdf");
+        let to = dir.join("repo.pdf");
+        fs::write(&fp, "content").unwrap();
+
+        let eexist = |_: &Path, _: &Path| Err(io::Error::from_raw_os_error(libc::EEXIST));
+        assert!(move_and_link_with(&fp, &to, &fp, SymlinkStyle::Relative, eexist).is_err());
+        assert_eq!(file_type(&fp).unwrap(), FileType::File);
+        assert_eq!(fs::read_to_string(&fp).unwrap(), "content");
+        assert!(!to.exists());
+
+        // Something else has taken the original place in the meantime
+        let taken = |_: &Path, dst: &Path| {
+            fs::write(dst, "other")?;
+            Err(io::Error::from_raw_os_error(libc::EEXIST))
+        };
+        let result = move_and_link_with(&fp, &to, &fp, SymlinkStyle::Relative, taken);
+        assert!(matches!(result, Err(Error::LeftBehind { ref path, .. }) if *path == to));
+        assert_eq!(fs::read_to_string(&fp).unwrap(), "other");
+        assert_eq!(fs::read_to_string(&to).unwrap(), "content");
+    }
+
+    #[test]
+    fn test_add_failures_leave_files_alone() {
+        let dir = temp_dir("add-failures");
+        let fp = dir.join("paper.pdf");
+        fs::create_dir_all(dir.join("links")).unwrap();
+        fs::write(&fp, "content").unwrap();
+        fs::write(dir.join("links/paper.pdf"), "other").unwrap();
+        fs::write(dir.join("blocker"), "").unwrap();
+
+        // The repository cannot be written to
+        let config = repo_config(&dir.join("blocker/repo"));
+        assert!(matches!(add(vec![fp.clone()], add_options(), config, &mut printer()), Err(Error::NotAdded(1))));
+        assert_eq!(file_type(&fp).unwrap(), FileType::File);
+        assert_eq!(fs::read_to_string(&fp).unwrap(), "content");
+
+        // The symlink cannot be created where it is wanted
+        let repo_dir = dir.join("repo");
+        let config = repo_config(&repo_dir);
+        let result = add(vec![fp.clone()], AddOptions { link_dir: Some(dir.join("links")), ..add_options() }, config, &mut printer());
+        assert!(matches!(result, Err(Error::NotAdded(1))));
+        assert_eq!(fs::read_to_string(&fp).unwrap(), "content");
+        assert_eq!(fs::read_to_string(dir.join("links/paper.pdf")).unwrap(), "other");
+        assert!(repo_dir.join("paper.pdf").symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn test_mv() {
+        let dir = temp_dir("mv");
+        let repo_dir = dir.join("repo");
+        let fp = dir.join("paper.pdf");
+        fs::write(&fp, "content").unwrap();
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(repo_dir.join("taken.pdf"), "other").unwrap();
+        let config = || repo_config(&repo_dir);
+
+        add(vec![fp.clone()], add_options(), config(), &mut printer()).unwrap();
+        assert!(mv(fp.clone(), "a/b.pdf".into(), false, config(), &mut printer()).is_err());
+        assert!(mv(fp.clone(), "taken.pdf".into(), false, config(), &mut printer()).is_err());
+        assert_eq!(fs::read_to_string(repo_dir.join("taken.pdf")).unwrap(), "other");
+
+        mv(fp.clone(), "renamed.pdf".into(), false, config(), &mut printer()).unwrap();
+        assert!(!repo_dir.join("paper.pdf").exists());
+        assert_eq!(fs::read_link(&fp).unwrap(), PathBuf::from("repo/renamed.pdf"));
+        assert_eq!(fs::read_to_string(&fp).unwrap(), "content");
+    }
+
+    #[test]
+    fn test_link() {
+        let dir = temp_dir("link");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        fs::write(repo_dir.join("paper.pdf"), "content").unwrap();
+        fs::write(dir.join("b/taken.pdf"), "other").unwrap();
+        let config = || repo_config(&repo_dir);
+
+        assert!(matches!(link(PathBuf::from("missing.pdf"), vec![dir.join("a")], false, config(), &mut printer()), Err(Error::NotInRepo(_))));
+        assert!(fs::read_dir(dir.join("a")).unwrap().next().is_none());
+
+        let result = link(PathBuf::from("paper.pdf"), vec![dir.join("a"), dir.join("b/taken.pdf"), dir.join("b/renamed.pdf")], false, config(), &mut printer());
+        assert!(matches!(result, Err(Error::NotLinked(1))));
+        assert_eq!(fs::read_link(dir.join("a/paper.pdf")).unwrap(), PathBuf::from("../repo/paper.pdf"));
+        assert_eq!(fs::read_link(dir.join("b/renamed.pdf")).unwrap(), PathBuf::from("../repo/paper.pdf"));
+        assert_eq!(fs::read_to_string(dir.join("b/taken.pdf")).unwrap(), "other");
+    }
+
+    #[test]
+    fn test_unmanage() {
+        let dir = temp_dir("unmanage");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(repo_dir.join("paper.pdf"), "content").unwrap();
+        fs::write(dir.join("other.pdf"), "other").unwrap();
+        unix::fs::symlink("repo/paper.pdf", dir.join("paper.pdf")).unwrap();
+        unix::fs::symlink("other.pdf", dir.join("elsewhere.pdf")).unwrap();
+        let config = || repo_config(&repo_dir);
+
+        let result = unmanage(vec![dir.join("paper.pdf"), dir.join("other.pdf"), dir.join("elsewhere.pdf")], false, config(), &mut printer());
+        assert!(matches!(result, Err(Error::NotUnmanaged(2))));
+        assert!(dir.join("paper.pdf").symlink_metadata().is_err());
+        assert!(repo_dir.join("paper.pdf").is_file());
+        assert!(dir.join("other.pdf").is_file());
+        assert!(dir.join("elsewhere.pdf").symlink_metadata().is_ok());
+    }
+
+    #[test]
+    fn test_relink() {
+        let dir = temp_dir("relink");
+        let old_repo = dir.join("old/repo");
+        let repo_dir = dir.join("new/repo");
+        let docs = dir.join("docs");
+        fs::create_dir_all(&old_repo).unwrap();
+        fs::create_dir_all(&docs).unwrap();
+        for name in &["a.pdf", "b.pdf"] {
+            fs::write(old_repo.join(name), *name).unwrap();
+        }
+        unix::fs::symlink("../old/repo/a.pdf", docs.join("a.pdf")).unwrap();
+        unix::fs::symlink(old_repo.join("b.pdf"), docs.join("b.pdf")).unwrap();
+        unix::fs::symlink("../old/repo/missing.pdf", docs.join("missing.pdf")).unwrap();
+        fs::create_dir_all(dir.join("new")).unwrap();
+        fs::rename(&old_repo, &repo_dir).unwrap();
+        let config = || repo_config(&repo_dir);
+
+        let result = relink(old_repo.clone(), Some(docs.clone()), false, config(), &mut printer());
+        assert!(matches!(result, Err(Error::LinksNotUpdated(1))));
+        assert_eq!(fs::read_link(docs.join("a.pdf")).unwrap(), PathBuf::from("../new/repo/a.pdf"));
+        assert_eq!(fs::read_link(docs.join("b.pdf")).unwrap(), PathBuf::from("../new/repo/b.pdf"));
+        assert_eq!(fs::read_link(docs.join("missing.pdf")).unwrap(), PathBuf::from("../old/repo/missing.pdf"));
+    }
+
+    #[test]
+    fn test_export_links() {
+        let dir = temp_dir("export_links");
+        let repo_dir = dir.join("repo");
+        let docs = dir.join("docs");
+        fs::create_dir_all(repo_dir.join("sub")).unwrap();
+        fs::create_dir_all(&docs).unwrap();
+        fs::write(repo_dir.join("a.pdf"), "a").unwrap();
+        fs::write(repo_dir.join("sub/b.pdf"), "b").unwrap();
+        unix::fs::symlink("../repo/a.pdf", docs.join("a.pdf")).unwrap();
+        unix::fs::symlink(repo_dir.join("sub/b.pdf"), docs.join("b.pdf")).unwrap();
+        let config = || repo_config(&repo_dir);
+
+        // Relative and absolute links come out the same
+        let manifest = collect_manifest(&docs, &repo_dir).unwrap();
+        assert_eq!(manifest, Manifest { links: vec![
+            ManifestEntry { repo_path: "a.pdf".into(), link: docs.join("a.pdf") },
+            ManifestEntry { repo_path: "sub/b.pdf".into(), link: docs.join("b.pdf") },
+        ] });
+
+        export_links(dir.join("links.toml"), Some(docs.clone()), false, config(), &mut printer()).unwrap();
+        let written: Manifest = toml::from_str(&fs::read_to_string(dir.join("links.toml")).unwrap()).unwrap();
+        assert_eq!(written, manifest);
+        export_links(dir.join("links.json"), Some(docs.clone()), false, config(), &mut printer()).unwrap();
+        assert!(fs::read_to_string(dir.join("links.json")).unwrap().contains("\"repo_path\": \"sub/b.pdf\""));
+    }
+
+    #[test]
+    fn test_import_links() {
+        let dir = temp_dir("import_links");
+        let repo_dir = dir.join("repo");
+        let docs = dir.join("docs");
+        fs::create_dir_all(repo_dir.join("sub")).unwrap();
+        fs::create_dir_all(&docs).unwrap();
+        fs::write(repo_dir.join("a.pdf"), "a").unwrap();
+        fs::write(repo_dir.join("sub/b.pdf"), "b").unwrap();
+        unix::fs::symlink("../repo/a.pdf", docs.join("a.pdf")).unwrap();
+        unix::fs::symlink("../../repo/sub/b.pdf", {
+            fs::create_dir_all(docs.join("deep")).unwrap();
+            docs.join("deep/b.pdf")
+        }).unwrap();
+        let config = || repo_config(&repo_dir);
+        export_links(dir.join("links.toml"), Some(docs.clone()), false, config(), &mut printer()).unwrap();
+        export_links(dir.join("links.json"), Some(docs.clone()), false, config(), &mut printer()).unwrap();
+
+        // Links that already point correctly are left alone
+        import_links(dir.join("links.toml"), false, config(), &mut printer()).unwrap();
+
+        fs::remove_dir_all(&docs).unwrap();
+        import_links(dir.join("links.toml"), true, config(), &mut printer()).unwrap();
+        assert!(docs.symlink_metadata().is_err());
+
+        // Missing parent directories are created
+        import_links(dir.join("links.json"), false, config(), &mut printer()).unwrap();
+        assert_eq!(fs::read_link(docs.join("a.pdf")).unwrap(), PathBuf::from("../repo/a.pdf"));
+        assert_eq!(fs::read_link(docs.join("deep/b.pdf")).unwrap(), PathBuf::from("../../repo/sub/b.pdf"));
+
+        fs::remove_dir_all(&docs).unwrap();
+        fs::remove_file(repo_dir.join("sub/b.pdf")).unwrap();
+        assert!(matches!(import_links(dir.join("links.toml"), false, config(), &mut printer()), Err(Error::NotLinked(1))));
+        assert_eq!(fs::read_to_string(docs.join("a.pdf")).unwrap(), "a");
+        assert!(docs.join("deep/b.pdf").symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn test_mvlink() {
+        let dir = temp_dir("mvlink");
+        let repo_dir = dir.join("repo");
+        let fp = dir.join("paper.pdf");
+        fs::create_dir_all(dir.join("docs/sub")).unwrap();
+        fs::write(&fp, "content").unwrap();
+        fs::write(dir.join("plain.pdf"), "content").unwrap();
+        let config = || repo_config(&repo_dir);
+
+        add(vec![fp.clone()], add_options(), config(), &mut printer()).unwrap();
+        mvlink(fp.clone(), dir.join("docs/sub"), false, config(), &mut printer()).unwrap();
+        assert!(fp.symlink_metadata().is_err());
+        assert_eq!(fs::read_link(dir.join("docs/sub/paper.pdf")).unwrap(), PathBuf::from("../../repo/paper.pdf"));
+        assert_eq!(fs::read_to_string(repo_dir.join("paper.pdf")).unwrap(), "content");
+
+        mvlink(dir.join("docs/sub/paper.pdf"), dir.join("docs/renamed.pdf"), false, config(), &mut printer()).unwrap();
+        assert_eq!(fs::read_link(dir.join("docs/renamed.pdf")).unwrap(), PathBuf::from("../repo/paper.pdf"));
+
+        assert!(matches!(mvlink(dir.join("plain.pdf"), dir.join("docs"), false, config(), &mut printer()), Err(Error::NotManaged(_))));
+        assert!(matches!(mvlink(dir.join("docs/renamed.pdf"), dir.join("plain.pdf"), false, config(), &mut printer()), Err(Error::Exists(_))));
+        assert!(matches!(mvlink(dir.join("docs/renamed.pdf"), dir.join("missing/paper.pdf"), false, config(), &mut printer()), Err(Error::NoParentDir(_))));
+        assert!(dir.join("docs/renamed.pdf").exists());
+    }
+
+    #[test]
+    fn test_remove_rejects_foreign_symlink() {
+        let dir = temp_dir("remove-foreign");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        let target = dir.join("target.pdf");
+        fs::write(&target, "content").unwrap();
+        let link = dir.join("link.pdf");
+        unix::fs::symlink(&target, &link).unwrap();
+
+        assert!(matches!(remove(vec![link.clone(), target.clone()], None, false, repo_config(&repo_dir), &mut printer()), Err(Error::NotRemoved(2))));
+        assert_eq!(file_type(&link).unwrap(), FileType::Symlink);
+        assert_eq!(file_type(&target).unwrap(), FileType::File);
+    }
+
+    #[test]
+    fn test_rename() {
+        let dir = temp_dir("rename");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(repo_dir.join("old.pdf"), "content").unwrap();
+        record_metadata(&repo_dir.join("old.pdf"), dir.join("one.pdf"), false, &repo_dir).unwrap();
+        fs::write(repo_dir.join("taken.pdf"), "taken").unwrap();
+        unix::fs::symlink("repo/old.pdf", dir.join("one.pdf")).unwrap();
+        unix::fs::symlink("../../repo/old.pdf", dir.join("a/b/two.pdf")).unwrap();
+        let config = || repo_config(&repo_dir);
+
+        assert!(matches!(rename(PathBuf::from("old.pdf"), "taken.pdf".to_string(), Some(dir.clone()), false, config(), &mut printer()), Err(Error::AlreadyInRepo(_))));
+        assert!(matches!(rename(PathBuf::from("old.pdf"), "a/new.pdf".to_string(), Some(dir.clone()), false, config(), &mut printer()), Err(Error::InvalidName(_))));
+
+        rename(PathBuf::from("old.pdf"), "new.pdf".to_string(), Some(dir.clone()), false, config(), &mut printer()).unwrap();
+        assert!(!repo_dir.join("old.pdf").exists());
+        assert!(sidecar_path(&repo_dir.join("new.pdf"), &repo_dir).exists());
+        assert_eq!(fs::read_link(dir.join("one.pdf")).unwrap(), PathBuf::from("repo/new.pdf"));
+        assert_eq!(fs::read_link(dir.join("a/b/two.pdf")).unwrap(), PathBuf::from("../../repo/new.pdf"));
+        assert_eq!(fs::read_to_string(dir.join("a/b/two.pdf")).unwrap(), "content");
+    }
+
+    #[test]
+    fn test_move_to_subdir() {
+        let dir = temp_dir("move-to-subdir");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(repo_dir.join("paper.pdf"), "content").unwrap();
+        record_metadata(&repo_dir.join("paper.pdf"), dir.join("paper.pdf"), false, &repo_dir).unwrap();
+        unix::fs::symlink("repo/paper.pdf", dir.join("paper.pdf")).unwrap();
+        let config = || repo_config(&repo_dir);
+
+        assert!(move_to_subdir(PathBuf::from("paper.pdf"), PathBuf::from("../outside"), Some(dir.clone()), false, config(), &mut printer()).is_err());
+        assert!(move_to_subdir(PathBuf::from("paper.pdf"), dir.join("elsewhere"), Some(dir.clone()), false, config(), &mut printer()).is_err());
+        assert!(!dir.join("outside").exists());
+
+        move_to_subdir(dir.join("paper.pdf"), PathBuf::from("2024/ml"), Some(dir.clone()), false, config(), &mut printer()).unwrap();
+        assert!(repo_dir.join("2024/ml/paper.pdf").is_file());
+        assert!(sidecar_path(&repo_dir.join("2024/ml/paper.pdf"), &repo_dir).is_file());
+        assert_eq!(fs::read_link(dir.join("paper.pdf")).unwrap(), PathBuf::from("repo/2024/ml/paper.pdf"));
+        assert_eq!(fs::read_to_string(dir.join("paper.pdf")).unwrap(), "content");
+
+        // And back to the top
+        move_to_subdir(PathBuf::from("2024/ml/paper.pdf"), PathBuf::from("."), Some(dir.clone()), false, config(), &mut printer()).unwrap();
+        assert_eq!(fs::read_link(dir.join("paper.pdf")).unwrap(), PathBuf::from("repo/paper.pdf"));
+    }
+
+    #[test]
+    fn test_restore() {
+        let dir = temp_dir("restore");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(repo_dir.join("paper.pdf"), "content").unwrap();
+        let config = || repo_config(&repo_dir);
+
+        let dest = dir.join("a/b/paper.pdf");
+        assert!(restore("paper.pdf".into(), dest.clone(), false, false, false, config(), &mut printer()).is_err());
+        restore("paper.pdf".into(), dest.clone(), false, true, false, config(), &mut printer()).unwrap();
+        assert_eq!(fs::read_link(&dest).unwrap(), PathBuf::from("../../repo/paper.pdf"));
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "content");
+
+        // Existing files are kept unless forced
+        assert!(restore("paper.pdf".into(), dest.clone(), false, false, false, config(), &mut printer()).is_err());
+        restore("paper.pdf".into(), dest.clone(), true, false, false, config(), &mut printer()).unwrap();
+
+        assert!(restore("missing.pdf".into(), dir.join("missing.pdf"), false, false, false, config(), &mut printer()).is_err());
+    }
+
+    #[test]
+    fn test_link_state() {
+        let dir = temp_dir("link-state");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(repo_dir.join("subdir")).unwrap();
+        fs::write(repo_dir.join("paper.pdf"), "content").unwrap();
+        unix::fs::symlink("repo/paper.pdf", dir.join("ok")).unwrap();
+        unix::fs::symlink("repo/missing.pdf", dir.join("broken")).unwrap();
+        unix::fs::symlink("repo/subdir", dir.join("foreign")).unwrap();
+        unix::fs::symlink("/usr", dir.join("unrelated")).unwrap();
+
+        let links = find_links(&dir, &repo_dir).unwrap();
+        let states: Vec<_> = links.iter()
+            .map(|(link, target)| (link.file_name().unwrap().to_str().unwrap(), link_state(target)))
+            .collect();
+        assert_eq!(states, vec![
+            ("broken", LinkState::Broken),
+            ("foreign", LinkState::Foreign),
+            ("ok", LinkState::Ok),
+        ]);
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = temp_dir("check");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(repo_dir.join("subdir")).unwrap();
+        fs::write(repo_dir.join("linked.pdf"), "content").unwrap();
+        fs::write(repo_dir.join("orphan.pdf"), "content").unwrap();
+        unix::fs::symlink("repo/linked.pdf", dir.join("linked.pdf")).unwrap();
+        assert_eq!(check(vec![dir.clone()], false, false, false, repo_config(&repo_dir), &mut printer()).unwrap().problems, 1);
+
+        fs::remove_file(repo_dir.join("orphan.pdf")).unwrap();
+        assert_eq!(check(vec![dir.clone()], false, false, false, repo_config(&repo_dir), &mut printer()).unwrap().problems, 0);
+
+        fs::write(repo_dir.join("empty.pdf"), "").unwrap();
+        assert_eq!(check(vec![], false, false, false, repo_config(&repo_dir), &mut printer()).unwrap().problems, 1);
+    }
+
+    #[test]
+    fn test_check_fix() {
+        let dir = temp_dir("check-fix");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(repo_dir.join("2020")).unwrap();
+        fs::write(repo_dir.join("2020/moved.pdf"), "content").unwrap();
+        unix::fs::symlink("repo/moved.pdf", dir.join("moved.pdf")).unwrap();
+        unix::fs::symlink("repo/gone.pdf", dir.join("gone.pdf")).unwrap();
+        let config = || repo_config(&repo_dir);
+
+        // Nothing is touched in a dry run
+        let summary = check(vec![dir.clone()], true, true, true, config(), &mut printer()).unwrap();
+        assert_eq!((summary.repaired, summary.skipped, summary.unrecoverable), (0, 2, 0));
+        assert_eq!(fs::read_link(dir.join("moved.pdf")).unwrap(), PathBuf::from("repo/moved.pdf"));
+
+        let summary = check(vec![dir.clone()], true, true, false, config(), &mut printer()).unwrap();
+        assert_eq!((summary.repaired, summary.skipped, summary.unrecoverable), (1, 1, 0));
+        assert_eq!(summary.problems, 1);
+        assert_eq!(fs::read_link(dir.join("moved.pdf")).unwrap(), PathBuf::from("repo/2020/moved.pdf"));
+        assert_eq!(fs::read_link(dir.join("gone.pdf")).unwrap(), PathBuf::from("repo/gone.pdf"));
+    }
+
+    #[test]
+    fn test_list() {
+        let dir = temp_dir("list");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(dir.join("kept.pdf"), "kept").unwrap();
+        fs::write(dir.join("gone.pdf"), "gone").unwrap();
+        let config = || repo_config(&repo_dir);
+        add(vec![dir.join("kept.pdf"), dir.join("gone.pdf")], add_options(), config(), &mut printer()).unwrap();
+        fs::remove_file(repo_dir.join("gone.pdf")).unwrap();
+
+        // A link to a file gone from the repository is shown as broken with
+        // or without walking a search root
+        let files = repo_files(&repo_dir).unwrap();
+        for search_root in [None, Some(dir.clone())] {
+            let mut links = listed_links(&files, search_root, &repo_dir).unwrap();
+            links.sort();
+            assert_eq!(links, vec![(dir.join("gone.pdf"), repo_dir.join("gone.pdf")), (dir.join("kept.pdf"), repo_dir.join("kept.pdf"))]);
+
+            let entries = list_entries(&files, &links, &repo_dir, |_| true).unwrap();
+            let summary: Vec<_> = entries.iter().map(|entry| (entry.path.clone(), entry.size, entry.broken)).collect();
+            assert_eq!(summary, vec![(Some(dir.join("kept.pdf")), Some(4), false), (Some(dir.join("gone.pdf")), None, true)]);
+            assert_eq!(entries[1].original_path, Some(dir.join("gone.pdf")));
+            let inventory: Vec<_> = entries.iter().map(|entry| (entry.repo_path.clone(), entry.linked)).collect();
+            assert_eq!(inventory, vec![(PathBuf::from("kept.pdf"), true), (PathBuf::from("gone.pdf"), false)]);
+            assert!(list_entries(&files, &links, &repo_dir, |fp| fp.ends_with("kept.pdf")).unwrap().iter().all(|entry| !entry.broken));
+        }
+        list(true, false, None, None, None, config(), &mut printer()).unwrap();
+
+        // A file in the repository with no symlink to it is listed as unlinked
+        fs::remove_file(dir.join("kept.pdf")).unwrap();
+        let entries = list_entries(&files, &[], &repo_dir, |_| true).unwrap();
+        let json = serde_json::to_value(&entries).unwrap();
+        assert_eq!((&json[0]["repo_path"], &json[0]["linked"], &json[0]["size"]), (&serde_json::json!("kept.pdf"), &serde_json::json!(false), &serde_json::json!(4)));
+        assert_eq!(json[0]["path"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_status() {
+        let dir = temp_dir("status");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(repo_dir.join("paper.pdf"), "content").unwrap();
+        unix::fs::symlink("repo/paper.pdf", dir.join("paper.pdf")).unwrap();
+        let config = || repo_config(&repo_dir);
+        let row = |cells: &[&dyn std::fmt::Display]| -> Vec<String> { cells.iter().map(|cell| cell.to_string()).collect() };
+        let healthy = row(&[&"healthy", &repo_dir.join("paper.pdf").display(), &dir.join("paper.pdf").display()]);
+
+        let (result, rows, summary) = status_rows(vec![dir.clone()], config());
+        result.unwrap();
+        assert_eq!(rows, vec![healthy.clone()]);
+        assert_eq!(summary, "1 ok, 0 problem(s)");
+
+        fs::write(repo_dir.join("orphan.pdf"), "content").unwrap();
+        let (result, rows, summary) = status_rows(vec![dir.clone()], config());
+        assert!(matches!(result, Err(Error::Problems(1))));
+        assert_eq!(rows, vec![row(&[&"orphaned", &repo_dir.join("orphan.pdf").display(), &"-"]), healthy.clone()]);
+        assert_eq!(summary, "1 ok, 1 problem(s)");
+        fs::remove_file(repo_dir.join("orphan.pdf")).unwrap();
+
+        unix::fs::symlink("repo/missing.pdf", dir.join("missing.pdf")).unwrap();
+        let (result, rows, _) = status_rows(vec![dir.clone()], config());
+        assert!(matches!(result, Err(Error::Problems(1))));
+        assert_eq!(rows, vec![row(&[&"broken", &repo_dir.join("missing.pdf").display(), &dir.join("missing.pdf").display()]), healthy]);
+
+        // Columns are padded to their widest cell, except for the last one
+        let mut printer = printer();
+        printer.table(&["A", "LONG"], &[vec!["wide", "x"]], &[Some(Color::Red)]).unwrap();
+        assert_eq!(String::from_utf8(printer.out.into_inner()).unwrap(), "A     LONG\nwide  x\n");
+    }
+
+    #[test]
+    fn test_classify() {
+        let dir = temp_dir("classify");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(repo_dir.join("sub")).unwrap();
+        fs::write(repo_dir.join("a.pdf"), "content").unwrap();
+        fs::write(repo_dir.join("b.pdf"), "content").unwrap();
+        let links = vec![
+            (dir.join("a.pdf"), repo_dir.join("a.pdf")),
+            (dir.join("c.pdf"), repo_dir.join("c.pdf")),
+            (dir.join("sub"), repo_dir.join("sub")),
+        ];
+        let files = vec![repo_dir.join("a.pdf"), repo_dir.join("b.pdf")];
+        let entries: Vec<_> = classify(&links, &[], &files, &repo_dir).into_iter().map(|(health, file, _)| (health, file.to_path_buf())).collect();
+        assert_eq!(entries, vec![
+            (Health::Healthy, repo_dir.join("a.pdf")),
+            (Health::Orphaned, repo_dir.join("b.pdf")),
+            (Health::Broken, repo_dir.join("c.pdf")),
+            (Health::Foreign, repo_dir.join("sub")),
+        ]);
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = temp_dir("verify");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        for name in &["intact.pdf", "rotten.pdf", "gone.pdf"] {
+            fs::write(repo_dir.join(name), *name).unwrap();
+            record_metadata(&repo_dir.join(name), dir.join(name), false, &repo_dir).unwrap();
+        }
+        fs::write(repo_dir.join("new.pdf"), "new").unwrap();
+        fs::write(repo_dir.join("rotten.pdf"), "flipped").unwrap();
+        fs::remove_file(repo_dir.join("gone.pdf")).unwrap();
+        let config = || repo_config(&repo_dir);
+
+        assert_eq!(orphaned_sidecars(&repo_dir).unwrap(), vec![sidecar_path(&repo_dir.join("gone.pdf"), &repo_dir)]);
+        assert!(matches!(verify(false, false, config(), &mut printer()), Err(Error::Problems(2))));
+        assert!(read_metadata(&repo_dir.join("new.pdf"), &repo_dir).unwrap().is_none());
+
+        // Updating accepts the current contents, but cannot bring back a file
+        assert!(matches!(verify(true, false, config(), &mut printer()), Err(Error::Problems(1))));
+        assert_eq!(read_metadata(&repo_dir.join("new.pdf"), &repo_dir).unwrap().unwrap().sha256, sha256_file(&repo_dir.join("new.pdf")).unwrap());
+        fs::remove_file(sidecar_path(&repo_dir.join("gone.pdf"), &repo_dir)).unwrap();
+        verify(false, false, config(), &mut printer()).unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let dir = temp_dir("prune");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        for name in &["kept.pdf", "gone.pdf"] {
+            fs::write(dir.join(name), *name).unwrap();
+        }
+        let config = || repo_config(&repo_dir);
+        add(vec![dir.join("kept.pdf"), dir.join("gone.pdf")], add_options(), config(), &mut printer()).unwrap();
+        fs::remove_file(repo_dir.join("gone.pdf")).unwrap();
+
+        prune(false, false, config(), &mut printer()).unwrap();
+        assert!(sidecar_path(&repo_dir.join("gone.pdf"), &repo_dir).exists());
+        prune(true, true, config(), &mut printer()).unwrap();
+        assert!(sidecar_path(&repo_dir.join("gone.pdf"), &repo_dir).exists());
+        prune(true, false, config(), &mut printer()).unwrap();
+        assert!(!sidecar_path(&repo_dir.join("gone.pdf"), &repo_dir).exists());
+        assert!(sidecar_path(&repo_dir.join("kept.pdf"), &repo_dir).exists());
+    }
+
+    #[test]
+    fn test_dedupe() {
+        let dir = temp_dir("dedupe");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        let config = || repo_config(&repo_dir);
+        for name in &["old.pdf", "new.pdf", "other.pdf"] {
+            fs::write(repo_dir.join(name), if *name == "other.pdf" { "other" } else { "same" }).unwrap();
+        }
+        let old = SystemTime::now() - std::time::Duration::from_secs(3600);
+        File::options().write(true).open(repo_dir.join("old.pdf")).unwrap().set_modified(old).unwrap();
+        unix::fs::symlink("repo/new.pdf", dir.join("new.pdf")).unwrap();
+        tag(TagCommand::Add { file: PathBuf::from("new.pdf"), tags: vec!["ml".to_string()] }, false, config(), &mut printer()).unwrap();
+
+        // Listing and dry runs leave everything in place
+        dedupe(None, Some(dir.clone()), false, config(), &mut printer()).unwrap();
+        dedupe(Some(Resolve::KeepOldest), Some(dir.clone()), true, config(), &mut printer()).unwrap();
+        assert!(repo_dir.join("new.pdf").exists());
+
+        dedupe(Some(Resolve::KeepOldest), Some(dir.clone()), false, config(), &mut printer()).unwrap();
+        assert_eq!(repo_files(&repo_dir).unwrap(), vec![repo_dir.join("old.pdf"), repo_dir.join("other.pdf")]);
+        assert_eq!(fs::read_link(dir.join("new.pdf")).unwrap(), PathBuf::from("repo/old.pdf"));
+        assert!(has_tag(&repo_dir.join("old.pdf"), "ml", &repo_dir));
+        assert!(!sidecar_path(&repo_dir.join("new.pdf"), &repo_dir).exists());
+    }
+
+    #[test]
+    fn test_similar_names() {
+        assert_eq!(levenshtein("", ""), 0);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("paper.pdf", "paper(1).pdf"), 3);
+        assert_eq!(levenshtein("paper.pdf", "papers.pdf"), 1);
+        let files = vec![PathBuf::from("/r/Attention.pdf"), PathBuf::from("/r/attention1.pdf"), PathBuf::from("/r/other.pdf")];
+        assert_eq!(similar_names(&files), vec![(&files[0], &files[1])]);
+        // Pairs come in the order of the files whatever their lengths
+        let files = vec![PathBuf::from("/r/papers.pdf"), PathBuf::from("/r/paper.pdf"), PathBuf::from("/r/a-much-longer-name.pdf"), PathBuf::from("/r/pape.pdf")];
+        assert_eq!(similar_names(&files), vec![(&files[0], &files[1]), (&files[0], &files[3]), (&files[1], &files[3])]);
+    }
+
+    #[test]
+    fn test_gc() {
+        let dir = temp_dir("gc");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::create_dir_all(dir.join("a/b/c")).unwrap();
+        fs::write(repo_dir.join("linked.pdf"), "content").unwrap();
+        fs::write(repo_dir.join("nested.pdf"), "content").unwrap();
+        fs::write(repo_dir.join("orphan.pdf"), "content").unwrap();
+        fs::write(repo_dir.join("stray.pdf"), "content").unwrap();
+        unix::fs::symlink("repo/linked.pdf", dir.join("linked.pdf")).unwrap();
+        unix::fs::symlink("../../../repo/nested.pdf", dir.join("a/b/c/nested.pdf")).unwrap();
+        let config = || repo_config(&repo_dir);
+
+        // Each deletion is asked about first, while dry runs and listing as
+        // JSON leave everything in place
+        let asked = std::cell::RefCell::new(Vec::new());
+        gc_with(vec![dir.clone()], None, false, false, config(), &mut printer(), |prompt| {
+            asked.borrow_mut().push(prompt.to_string());
+            Ok(false)
+        }).unwrap();
+        assert_eq!(asked.into_inner(), vec![
+            format!("Delete {}?", repo_dir.join("orphan.pdf").display()),
+            format!("Delete {}?", repo_dir.join("stray.pdf").display()),
+        ]);
+        gc(vec![dir.clone()], None, true, true, config(), &mut printer()).unwrap();
+        gc(vec![dir.clone()], None, false, false, config(), &mut Printer { output: OutputMode::Json, ..printer() }).unwrap();
+        assert!(repo_dir.join("orphan.pdf").exists());
+        gc_with(vec![dir.clone()], None, false, false, config(), &mut printer(), |prompt| Ok(prompt.contains("orphan.pdf"))).unwrap();
+        assert!(!repo_dir.join("orphan.pdf").exists());
+        assert!(repo_dir.join("stray.pdf").exists());
+        fs::write(repo_dir.join("orphan.pdf"), "content").unwrap();
+
+        let quarantine = dir.join("quarantine");
+        fs::remove_file(repo_dir.join("orphan.pdf")).unwrap();
+        gc(vec![dir.clone()], Some(quarantine.clone()), false, false, config(), &mut printer()).unwrap();
+        assert!(!repo_dir.join("stray.pdf").exists());
+        assert!(quarantine.join("stray.pdf").exists());
+        assert!(repo_dir.join("nested.pdf").exists());
+
+        fs::write(repo_dir.join("orphan.pdf"), "content").unwrap();
+        gc(vec![dir.clone()], None, true, false, config(), &mut printer()).unwrap();
+        assert!(!repo_dir.join("orphan.pdf").exists());
+        assert!(repo_dir.join("linked.pdf").exists());
+        assert!(repo_dir.join("nested.pdf").exists());
+
+        // The link recorded at the time of adding counts even outside the
+        // search roots
+        fs::create_dir_all(dir.join("elsewhere")).unwrap();
+        fs::write(dir.join("elsewhere/kept.pdf"), "kept").unwrap();
+        add(vec![dir.join("elsewhere/kept.pdf")], add_options(), config(), &mut printer()).unwrap();
+        gc(vec![dir.join("a")], None, true, false, config(), &mut printer()).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("elsewhere/kept.pdf")).unwrap(), "kept");
+        assert!(!repo_dir.join("linked.pdf").exists());
+        assert!(repo_dir.join("nested.pdf").exists());
+
+        assert!(Opt::from_iter_safe(&["pm", "gc", "--force"]).is_ok());
+        assert!(Opt::from_iter_safe(&["pm", "gc", "--force", "--move-to", "/tmp/q"]).is_err());
+    }
+
+    #[test]
+    fn test_which() {
+        let dir = temp_dir("which");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(repo_dir.join("sub")).unwrap();
+        fs::write(repo_dir.join("sub/paper.pdf"), "content").unwrap();
+        fs::write(repo_dir.join("hard.pdf"), "content").unwrap();
+        fs::write(dir.join("unmanaged.pdf"), "content").unwrap();
+        fs::hard_link(repo_dir.join("hard.pdf"), dir.join("hard.pdf")).unwrap();
+        unix::fs::symlink("repo/sub/paper.pdf", dir.join("link.pdf")).unwrap();
+        unix::fs::symlink("unmanaged.pdf", dir.join("foreign.pdf")).unwrap();
+
+        assert_eq!(which_path(&dir.join("link.pdf"), &repo_dir).unwrap(), repo_dir.join("sub/paper.pdf"));
+        assert_eq!(which_path(&repo_dir.join("sub/../sub/paper.pdf"), &repo_dir).unwrap(), repo_dir.join("sub/paper.pdf"));
+        assert_eq!(which_path(&dir.join("hard.pdf"), &repo_dir).unwrap(), repo_dir.join("hard.pdf"));
+        assert!(matches!(which_path(&dir.join("unmanaged.pdf"), &repo_dir), Err(Error::NotManaged(_))));
+        assert!(matches!(which_path(&dir.join("foreign.pdf"), &repo_dir), Err(Error::NotManaged(_))));
+        assert!(matches!(which_path(&dir.join("missing.pdf"), &repo_dir), Err(Error::IoAt(..))));
+        assert!(matches!(which_path(&repo_dir, &repo_dir), Err(Error::IsDir(_))));
+    }
+
+    #[test]
+    fn test_find_document() {
+        let dir = temp_dir("find-document");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(repo_dir.join("2020")).unwrap();
+        fs::write(repo_dir.join("2020/Attention.pdf"), "content").unwrap();
+        fs::write(repo_dir.join("bert.pdf"), "content").unwrap();
+        fs::write(repo_dir.join("bert-large.pdf"), "content").unwrap();
+        fs::write(repo_dir.join("roberta.pdf"), "content").unwrap();
+        unix::fs::symlink("repo/bert.pdf", dir.join("link.pdf")).unwrap();
+
+        assert_eq!(find_document(Path::new("bert.pdf"), &repo_dir, &mut printer()).unwrap(), repo_dir.join("bert.pdf"));
+        assert_eq!(find_document(&dir.join("link.pdf"), &repo_dir, &mut printer()).unwrap(), repo_dir.join("bert.pdf"));
+        assert_eq!(find_document(Path::new("attention"), &repo_dir, &mut printer()).unwrap(), repo_dir.join("2020/Attention.pdf"));
+        assert_eq!(find_document(Path::new("2020/att"), &repo_dir, &mut printer()).unwrap(), repo_dir.join("2020/Attention.pdf"));
+        assert_eq!(find_document(Path::new("Rob"), &repo_dir, &mut printer()).unwrap(), repo_dir.join("roberta.pdf"));
+        assert!(matches!(find_document(Path::new("bert"), &repo_dir, &mut printer()), Err(Error::Ambiguous(_, 2))));
+        // Only prefixes match
+        assert!(matches!(find_document(Path::new("ert"), &repo_dir, &mut printer()), Err(Error::NotInRepo(_))));
+        assert!(matches!(find_document(Path::new("missing"), &repo_dir, &mut printer()), Err(Error::NotInRepo(_))));
+
+        let config = || Config { viewer: Some("paperman-no-such-viewer".to_string()), ..repo_config(&repo_dir) };
+        open(PathBuf::from("attention"), true, None, false, config(), &mut printer()).unwrap();
+        assert!(matches!(open(PathBuf::from("attention"), false, None, false, config(), &mut printer()), Err(Error::IoAt(_, _))));
+        // The opener is not waited for, so its exit status does not matter
+        open(PathBuf::from("attention"), false, Some("false".to_string()), false, config(), &mut printer()).unwrap();
+    }
+
+    #[test]
+    fn test_collect_stats() {
+        let dir = temp_dir("collect-stats");
+        let repo_dir = dir.join("repo");
+        assert_eq!(collect_stats(&repo_dir).unwrap().files, 0);
+
+        fs::create_dir_all(repo_dir.join("sub")).unwrap();
+        fs::write(repo_dir.join("a.pdf"), "12345").unwrap();
+        fs::write(repo_dir.join("sub/b.PDF"), "123").unwrap();
+        fs::write(repo_dir.join("notes"), "1").unwrap();
+        record_metadata(&repo_dir.join("a.pdf"), dir.join("a.pdf"), false, &repo_dir).unwrap();
+        let old = UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000);
+        File::options().write(true).open(repo_dir.join("notes")).unwrap().set_modified(old).unwrap();
+
+        let stats = collect_stats(&repo_dir).unwrap();
+        assert_eq!((stats.files, stats.total_size), (3, 9));
+        assert_eq!((stats.by_extension["pdf"].files, stats.by_extension["pdf"].size), (2, 8));
+        assert_eq!(stats.by_extension[""].files, 1);
+        assert_eq!(stats.largest.iter().map(|f| f.repo_path.clone()).collect::<Vec<_>>(), vec![PathBuf::from("a.pdf"), PathBuf::from("sub/b.PDF"), PathBuf::from("notes")]);
+        assert_eq!(stats.oldest.as_deref(), Some("2017-07-14T02:40:00Z"));
+        assert!(serde_json::to_string(&stats).unwrap().contains("\"total_size\":9"));
+    }
+
+    #[test]
+    fn test_extract_text() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("extract-text");
+        let repo_dir = dir.join("repo");
+        let cache_dir = repo_dir.join(META_DIR).join(TEXT_CACHE_DIR);
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(repo_dir.join("paper.pdf"), "Attention is all you need\n").unwrap();
+        fs::write(repo_dir.join("secret.pdf"), "encrypted").unwrap();
+        // Stands in for pdftotext, failing on what looks encrypted
+        let extractor = dir.join("extract");
+        fs::write(&extractor, "#!/bin/sh\ngrep -q encrypted \"$1\" && exit 1\ncat \"$1\"\n").unwrap();
+        fs::set_permissions(&extractor, fs::Permissions::from_mode(0o755)).unwrap();
+        let extractor = extractor.to_str().unwrap();
+
+        assert_eq!(extract_text(&repo_dir.join("paper.pdf"), extractor, &cache_dir).unwrap(), "Attention is all you need\n");
+        assert!(matches!(extract_text(&repo_dir.join("secret.pdf"), extractor, &cache_dir), Err(Error::Exited(_, 1))));
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 1);
+
+        // Cached text is used without running the extractor again
+        assert_eq!(extract_text(&repo_dir.join("paper.pdf"), "false", &cache_dir).unwrap(), "Attention is all you need\n");
+
+        let config = Config { text_extractor: Some(extractor.to_string()), ..repo_config(&repo_dir) };
+        grep("attention".to_string(), false, config, &mut printer()).unwrap();
+        assert_eq!(repo_files(&repo_dir).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_resolve_repo_file() {
+        let dir = temp_dir("resolve-repo-file");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(repo_dir.join("a.pdf"), "content").unwrap();
+        fs::write(dir.join("plain.pdf"), "content").unwrap();
+        unix::fs::symlink("repo/a.pdf", dir.join("a.pdf")).unwrap();
+        unix::fs::symlink("repo/gone.pdf", dir.join("gone.pdf")).unwrap();
+        unix::fs::symlink("plain.pdf", dir.join("foreign.pdf")).unwrap();
+
+        assert_eq!(resolve_repo_file(&dir.join("a.pdf"), &repo_dir).unwrap(), repo_dir.join("a.pdf"));
+        assert_eq!(resolve_repo_file(Path::new("a.pdf"), &repo_dir).unwrap(), repo_dir.join("a.pdf"));
+        assert!(matches!(resolve_repo_file(&dir.join("gone.pdf"), &repo_dir), Err(Error::BrokenLink(_))));
+        assert!(matches!(resolve_repo_file(&dir.join("foreign.pdf"), &repo_dir), Err(Error::NotManaged(_))));
+        assert!(matches!(resolve_repo_file(&dir.join("plain.pdf"), &repo_dir), Err(Error::NotInRepo(_))));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let dir = temp_dir("metadata");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(dir.join("paper.pdf"), "content").unwrap();
+        let config = || repo_config(&repo_dir);
+
+        add(vec![dir.join("paper.pdf")], add_options(), config(), &mut printer()).unwrap();
+        let meta = read_metadata(&repo_dir.join("paper.pdf"), &repo_dir).unwrap().unwrap();
+        assert_eq!(meta.format_version, METADATA_FORMAT_VERSION);
+        assert_eq!(meta.original_path, Some(dir.join("paper.pdf")));
+        assert_eq!(meta.sha256, "ed7002b439e9ac845f22357d822bac1444730fbdb6016d3ec9432297b9ec9f73");
+        assert!(meta.added.as_ref().unwrap().ends_with('Z'));
+
+        // Sidecars are not repository files of their own
+        assert!(sidecar_path(&repo_dir.join("paper.pdf"), &repo_dir).starts_with(repo_dir.join(META_DIR)));
+        assert_eq!(repo_files(&repo_dir).unwrap(), vec![repo_dir.join("paper.pdf")]);
+
+        mv(dir.join("paper.pdf"), "renamed.pdf".to_string(), false, config(), &mut printer()).unwrap();
+        assert!(!sidecar_path(&repo_dir.join("paper.pdf"), &repo_dir).exists());
+        assert_eq!(read_metadata(&repo_dir.join("renamed.pdf"), &repo_dir).unwrap(), Some(meta));
+
+        remove(vec![dir.join("paper.pdf")], None, false, config(), &mut printer()).unwrap();
+        assert!(!sidecar_path(&repo_dir.join("renamed.pdf"), &repo_dir).exists());
+
+        // A file named like a sidecar is a file like any other
+        fs::write(dir.join("data.toml"), "mine").unwrap();
+        fs::write(dir.join("data"), "data").unwrap();
+        add(vec![dir.join("data.toml"), dir.join("data")], add_options(), config(), &mut printer()).unwrap();
+        assert_eq!(fs::read_to_string(repo_dir.join("data.toml")).unwrap(), "mine");
+        assert_eq!(repo_files(&repo_dir).unwrap(), vec![repo_dir.join("data"), repo_dir.join("data.toml")]);
+    }
+
+    #[test]
+    fn test_format_rfc3339() {
+        assert_eq!(format_rfc3339(UNIX_EPOCH + std::time::Duration::from_secs(1569674096)), "2019-09-28T12:34:56Z");
+    }
+
+    #[test]
+    fn test_export() {
+        let dir = temp_dir("export");
+        let repo_dir = dir.join("repo");
+        let dest_dir = dir.join("out/papers");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(repo_dir.join("a.pdf"), "aaa").unwrap();
+        fs::write(repo_dir.join("b.pdf"), "bb").unwrap();
+        let mtime = UNIX_EPOCH + std::time::Duration::from_secs(1569674096);
+        File::options().write(true).open(repo_dir.join("a.pdf")).unwrap().set_modified(mtime).unwrap();
+        let config = || repo_config(&repo_dir);
+
+        export(dest_dir.clone(), vec![PathBuf::from("a.pdf")], false, false, false, config(), &mut printer()).unwrap();
+        assert_eq!(file_type(dest_dir.join("a.pdf")).unwrap(), FileType::File);
+        assert_eq!(fs::read_to_string(dest_dir.join("a.pdf")).unwrap(), "aaa");
+        assert_eq!(fs::metadata(dest_dir.join("a.pdf")).unwrap().modified().unwrap(), mtime);
+
+        // Existing files are kept unless forced, without stopping the others
+        fs::write(dest_dir.join("a.pdf"), "local").unwrap();
+        assert!(export(dest_dir.clone(), vec![], true, false, false, config(), &mut printer()).is_err());
+        assert_eq!(fs::read_to_string(dest_dir.join("a.pdf")).unwrap(), "local");
+        assert_eq!(fs::read_to_string(dest_dir.join("b.pdf")).unwrap(), "bb");
+        export(dest_dir.clone(), vec![], true, true, false, config(), &mut printer()).unwrap();
+        assert_eq!(fs::read_to_string(dest_dir.join("a.pdf")).unwrap(), "aaa");
+
+        assert!(export(dest_dir.clone(), vec![PathBuf::from("missing.pdf")], false, false, false, config(), &mut printer()).is_err());
+
+        // Read-only files keep their permissions and times
+        use std::os::unix::fs::PermissionsExt;
+        fs::write(repo_dir.join("c.pdf"), "c").unwrap();
+        File::options().write(true).open(repo_dir.join("c.pdf")).unwrap().set_modified(mtime).unwrap();
+        fs::set_permissions(repo_dir.join("c.pdf"), fs::Permissions::from_mode(0o444)).unwrap();
+        let read_only_dir = dir.join("read-only");
+        unprivileged(&dir, || export(read_only_dir.clone(), vec![PathBuf::from("c.pdf")], false, false, false, config(), &mut printer())).unwrap();
+        assert_eq!(fs::read_to_string(read_only_dir.join("c.pdf")).unwrap(), "c");
+        assert_eq!(fs::metadata(read_only_dir.join("c.pdf")).unwrap().permissions().mode() & 0o777, 0o444);
+        assert_eq!(fs::metadata(read_only_dir.join("c.pdf")).unwrap().modified().unwrap(), mtime);
+    }
+
+    #[test]
+    fn test_tag() {
+        let dir = temp_dir("tag");
+        let repo_dir = dir.join("repo");
+        fs::create_dir_all(&repo_dir).unwrap();
+        fs::write(repo_dir.join("a.pdf"), "content").unwrap();
+        fs::write(repo_dir.join("b.pdf"), "content").unwrap();
+        unix::fs::symlink("repo/a.pdf", dir.join("a.pdf")).unwrap();
+        let config = || repo_config(&repo_dir);
+        let tags = |v: &[&str]| v.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let tags_of = |name: &str| read_metadata(&repo_dir.join(name), &repo_dir).unwrap().unwrap().tags.into_iter().collect::<Vec<_>>();
+
+        tag(TagCommand::Add { file: dir.join("a.pdf"), tags: tags(&["ml", "to-read", "ml"]) }, false, config(), &mut printer()).unwrap();
+        tag(TagCommand::Add { file: PathBuf::from("b.pdf"), tags: tags(&["ml"]) }, false, config(), &mut printer()).unwrap();
+        assert_eq!(tags_of("a.pdf"), vec!["ml", "to-read"]);
+        assert!(fs::read_to_string(sidecar_path(&repo_dir.join("a.pdf"), &repo_dir)).unwrap().contains("tags = ["));
+        assert_eq!(tag_counts(&repo_dir).unwrap().into_iter().collect::<Vec<_>>(), vec![("ml".to_string(), 2), ("to-read".to_string(), 1)]);
+        assert!(has_tag(&repo_dir.join("b.pdf"), "ml", &repo_dir));
+        assert!(!has_tag(&repo_dir.join("b.pdf"), "to-read", &repo_dir));
+
+        tag(TagCommand::Remove { file: PathBuf::from("b.pdf"), tags: tags(&["ml"]) }, false, config(), &mut printer()).unwrap();
+        assert!(tags_of("b.pdf").is_empty());
+
+        // Sidecars are neither repository files nor taggable
+        assert_eq!(repo_files(&repo_dir).unwrap(), vec![repo_dir.join("a.pdf"), repo_dir.join("b.pdf")]);
+        assert!(tag(TagCommand::Add { file: PathBuf::from(".paperman/meta/a.pdf.toml"), tags: tags(&["x"]) }, false, config(), &mut printer()).is_err());
+        assert!(tag(TagCommand::Add { file: PathBuf::from("../repo/a.pdf"), tags: tags(&["x"]) }, false, config(), &mut printer()).is_ok());
+        assert!(tag(TagCommand::Add { file: PathBuf::from("a.pdf"), tags: tags(&[""]) }, false, config(), &mut printer()).is_err());
+
+        // Tags follow the file when it is renamed
+        mv(dir.join("a.pdf"), "c.pdf".to_string(), false, config(), &mut printer()).unwrap();
+        assert!(tags_of("c.pdf").contains(&"to-read".to_string()));
+    }
+
+    #[test]
+    fn test_remove_by_repo_name() {
+        let dir = temp_dir("remove-by-name");
+        let repo_dir = dir.join("repo");
+        let docs = dir.join("docs");
+        fs::create_dir_all(&docs).unwrap();
+        let fp = docs.join("paper.pdf");
+        fs::write(&fp, "content").unwrap();
+
+        add(vec![fp.clone()], add_options(), repo_config(&repo_dir), &mut printer()).unwrap();
+        remove(vec!["paper.pdf".into()], Some(dir.clone()), false, repo_config(&repo_dir), &mut printer()).unwrap();
+        assert_eq!(file_type(&fp).unwrap(), FileType::File);
+        assert!(!repo_dir.join("paper.pdf").exists());
     }
 
     #[test]